@@ -0,0 +1,528 @@
+use crate::config::yaml_string_list;
+use crate::filetype::{FileType, FileTypeConfig, SNIFF_LENGTH};
+use flate2::read::GzDecoder;
+use regex::Regex;
+use std::io::prelude::*;
+use std::io::{self, Cursor, SeekFrom};
+use yaml_rust::Yaml;
+
+// Small archives compress far better than the ratio allows, like a tarball
+// of a few short files padded with zeros, so the ratio only counts past this
+const RATIO_FLOOR: u64 = 1_048_576;
+
+// Separates the levels of nested archives in member names
+const NESTING_SEPARATOR: &str = "!";
+
+// Limits for looking inside tar, tar.gz and zip files. Members are read as a
+// stream and never written to disk.
+#[derive(Debug, Clone)]
+pub struct ArchivePolicy {
+    pub enabled: bool,
+    // Archives nested deeper than this are blocked
+    pub max_depth: u64,
+    pub max_members: u64,
+    pub max_member_size: u64,
+    // Uncompressed bytes across every member, nested ones included
+    pub max_total_size: u64,
+    // Uncompressed to compressed size, anything above is taken to be a bomb
+    pub max_ratio: u64,
+    // Members with a matching path are blocked
+    pub denied_names: Vec<Regex>,
+}
+
+impl Default for ArchivePolicy {
+    fn default() -> ArchivePolicy {
+        ArchivePolicy {
+            enabled: false,
+            max_depth: 3,
+            max_members: 100_000,
+            max_member_size: 17_179_869_184,
+            max_total_size: 68_719_476_736,
+            max_ratio: 100,
+            denied_names: Vec::new(),
+        }
+    }
+}
+
+impl ArchivePolicy {
+    // Reads the `archives` section of the job config, sizes are in bytes:
+    //
+    //   archives:
+    //     enabled: true
+    //     max_depth: 2
+    //     max_member_size: 1073741824
+    //     max_ratio: 50
+    //     denied_names: ['\.exe$', '(^|/)\.ssh/']
+    pub fn from_yaml(yaml: &Yaml) -> Result<ArchivePolicy, String> {
+        let defaults = ArchivePolicy::default();
+        let limit = |field: &str, default: u64| match &yaml[field] {
+            Yaml::Integer(value) if *value > 0 => Ok(*value as u64),
+            Yaml::BadValue => Ok(default),
+            _ => Err(format!("archives.{} must be a positive number", field)),
+        };
+        let enabled = match &yaml["enabled"] {
+            Yaml::Boolean(b) => *b,
+            Yaml::BadValue => defaults.enabled,
+            _ => return Err("archives.enabled must be true or false".to_owned()),
+        };
+        let denied_names = yaml_string_list(&yaml["denied_names"], "archives.denied_names")?
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .map_err(|e| format!("Invalid archives.denied_names pattern {:?}: {}", pattern, e))
+            })
+            .collect::<Result<Vec<Regex>, String>>()?;
+        Ok(ArchivePolicy {
+            enabled,
+            max_depth: limit("max_depth", defaults.max_depth)?,
+            max_members: limit("max_members", defaults.max_members)?,
+            max_member_size: limit("max_member_size", defaults.max_member_size)?,
+            max_total_size: limit("max_total_size", defaults.max_total_size)?,
+            max_ratio: limit("max_ratio", defaults.max_ratio)?,
+            denied_names,
+        })
+    }
+
+    // Most uncompressed bytes allowed out of `compressed` bytes
+    fn expansion_limit(&self, compressed: u64) -> u64 {
+        compressed
+            .saturating_mul(self.max_ratio)
+            .max(RATIO_FLOOR)
+            .min(self.max_total_size)
+    }
+}
+
+pub fn is_archive(file_type: FileType) -> bool {
+    match file_type {
+        FileType::Tar | FileType::TarGz | FileType::Zip => true,
+        _ => false,
+    }
+}
+
+// Walks every member of the archive, nested archives included, and returns
+// why it has to be blocked
+pub fn inspect_archive<R: Read + Seek>(
+    mut file: R,
+    file_type: FileType,
+    policy: &ArchivePolicy,
+    file_types: &FileTypeConfig,
+) -> Result<(), String> {
+    let size = file.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+    let mut walk = ArchiveWalk {
+        policy,
+        file_types,
+        members: 0,
+        total_size: 0,
+    };
+    match file_type {
+        FileType::Zip => walk.zip(file, "", 1),
+        _ => walk.tar(&mut file, file_type == FileType::TarGz, size, "", 1),
+    }
+}
+
+// Member paths can't be absolute or climb out of the extraction directory
+pub fn check_member_path(name: &str) -> Result<(), String> {
+    let name = name.replace('\\', "/");
+    let drive = name.len() > 1 && name.as_bytes()[1] == b':';
+    if name.starts_with('/') || drive {
+        return Err(format!("member {:?} has an absolute path", name));
+    }
+    if name.contains('\0') || name.split('/').any(|component| component == "..") {
+        return Err(format!("member {:?} escapes the archive", name));
+    }
+    Ok(())
+}
+
+// Symbolic links are resolved from the link's directory, hard links from the
+// root of the archive
+fn check_link_target(name: &str, target: &str, symbolic: bool) -> Result<(), String> {
+    let target = target.replace('\\', "/");
+    if target.starts_with('/') {
+        return Err(format!("link {:?} points to the absolute path {:?}", name, target));
+    }
+    let mut depth: i64 = if symbolic {
+        name.split('/').filter(|c| !c.is_empty() && *c != ".").count() as i64 - 1
+    } else {
+        0
+    };
+    for component in target.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => depth -= 1,
+            _ => depth += 1,
+        }
+        if depth < 0 {
+            return Err(format!("link {:?} points outside the archive to {:?}", name, target));
+        }
+    }
+    Ok(())
+}
+
+struct ArchiveWalk<'a> {
+    policy: &'a ArchivePolicy,
+    file_types: &'a FileTypeConfig,
+    members: u64,
+    total_size: u64,
+}
+
+impl<'a> ArchiveWalk<'a> {
+    fn tar(
+        &mut self,
+        reader: &mut dyn Read,
+        gzip: bool,
+        compressed_size: u64,
+        parent: &str,
+        depth: u64,
+    ) -> Result<(), String> {
+        let mut decoder;
+        let reader: &mut dyn Read = if gzip {
+            decoder = GzDecoder::new(reader);
+            &mut decoder
+        } else {
+            reader
+        };
+        // Decompressed bytes are counted as they are read so a bomb is caught
+        // before it has been inflated
+        let limit = if gzip {
+            self.policy.expansion_limit(compressed_size)
+        } else {
+            self.policy.max_total_size
+        };
+        let mut bounded = BoundedReader {
+            inner: reader,
+            read: 0,
+            limit,
+        };
+        let mut archive = tar::Archive::new(&mut bounded as &mut dyn Read);
+        let entries = archive.entries().map_err(|e| tar_error(parent, e))?;
+        for entry in entries {
+            let mut entry = entry.map_err(|e| tar_error(parent, e))?;
+            let member = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            let name = format!("{}{}", parent, member);
+            check_member_path(&member).map_err(|e| with_parent(parent, e))?;
+            let entry_type = entry.header().entry_type();
+            if let Some(target) = entry.link_name_bytes() {
+                let target = String::from_utf8_lossy(&target).into_owned();
+                check_link_target(&member, &target, entry_type.is_symlink())
+                    .map_err(|e| with_parent(parent, e))?;
+            }
+            let size = entry.header().size().map_err(|e| tar_error(parent, e))?;
+            self.count_member(&name, size)?;
+            if entry_type.is_file() {
+                self.member(&mut entry, &name, size, depth)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn zip<R: Read + Seek>(&mut self, reader: R, parent: &str, depth: u64) -> Result<(), String> {
+        let mut archive = zip::ZipArchive::new(reader).map_err(|e| zip_error(parent, e))?;
+        for index in 0..archive.len() {
+            let mut file = archive.by_index(index).map_err(|e| zip_error(parent, e))?;
+            let member = file.name().to_owned();
+            let name = format!("{}{}", parent, member);
+            check_member_path(&member).map_err(|e| with_parent(parent, e))?;
+            let size = file.size();
+            self.count_member(&name, size)?;
+            if size > self.policy.expansion_limit(file.compressed_size()) {
+                return Err(format!(
+                    "member {:?} expands from {} to {} bytes",
+                    name,
+                    file.compressed_size(),
+                    size
+                ));
+            }
+            if !file.is_dir() {
+                // The sizes in the headers can lie, never read past them
+                let mut bounded = BoundedReader {
+                    inner: &mut file,
+                    read: 0,
+                    limit: size,
+                };
+                self.member(&mut bounded, &name, size, depth)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn count_member(&mut self, name: &str, size: u64) -> Result<(), String> {
+        self.members += 1;
+        self.total_size = self.total_size.saturating_add(size);
+        if self.members > self.policy.max_members {
+            return Err(format!("more than {} members", self.policy.max_members));
+        }
+        if size > self.policy.max_member_size {
+            return Err(format!("member {:?} is {} bytes", name, size));
+        }
+        if self.total_size > self.policy.max_total_size {
+            return Err(format!("members add up to more than {} bytes", self.policy.max_total_size));
+        }
+        Ok(())
+    }
+
+    // Applies the name and type policy to a regular file and walks it when it
+    // is an archive itself
+    fn member(&mut self, reader: &mut dyn Read, name: &str, size: u64, depth: u64) -> Result<(), String> {
+        if let Some(pattern) = self.policy.denied_names.iter().find(|p| p.is_match(name)) {
+            return Err(format!("member {:?} matches the denied name {:?}", name, pattern.as_str()));
+        }
+        let mut head = Vec::with_capacity(SNIFF_LENGTH);
+        reader
+            .take(SNIFF_LENGTH as u64)
+            .read_to_end(&mut head)
+            .map_err(|e| format!("Could not read member {:?}: {}", name, e))?;
+        let file_type = FileType::sniff(&head).or_else(|| FileType::from_key(name));
+        let file_type = match file_type {
+            Some(file_type) => file_type,
+            None => return Ok(()),
+        };
+        if self.file_types.enabled {
+            self.file_types
+                .check(name, None, &head)
+                .map_err(|e| format!("member {:?}: {}", name, e))?;
+        }
+        if !is_archive(file_type) {
+            return Ok(());
+        }
+        if depth >= self.policy.max_depth {
+            return Err(format!("member {:?} is nested more than {} deep", name, self.policy.max_depth));
+        }
+        let parent = format!("{}{}", name, NESTING_SEPARATOR);
+        let mut rest = Cursor::new(head).chain(reader);
+        match file_type {
+            // Zip needs to seek to its central directory, so nested ones are
+            // read into memory, bounded by the member size limit
+            FileType::Zip => {
+                let mut content = Vec::new();
+                rest.read_to_end(&mut content)
+                    .map_err(|e| format!("Could not read member {:?}: {}", name, e))?;
+                self.zip(Cursor::new(content), &parent, depth + 1)
+            }
+            _ => self.tar(&mut rest, file_type == FileType::TarGz, size, &parent, depth + 1),
+        }
+    }
+}
+
+struct BoundedReader<R> {
+    inner: R,
+    read: u64,
+    limit: u64,
+}
+
+impl<R: Read> Read for BoundedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        if self.read > self.limit {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("expands to more than {} bytes", self.limit),
+            ));
+        }
+        Ok(read)
+    }
+}
+
+fn with_parent(parent: &str, reason: String) -> String {
+    if parent.is_empty() {
+        reason
+    } else {
+        format!("{} (inside {})", reason, parent.trim_end_matches(NESTING_SEPARATOR))
+    }
+}
+
+fn tar_error(parent: &str, error: io::Error) -> String {
+    with_parent(parent, format!("unreadable tar archive: {}", error))
+}
+
+fn zip_error(parent: &str, error: zip::result::ZipError) -> String {
+    with_parent(parent, format!("unreadable zip archive: {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use yaml_rust::YamlLoader;
+
+    // Writes the names straight into the headers, the tar builder refuses
+    // the unsafe ones
+    fn tar(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, content) in members {
+            let mut header = tar::Header::new_ustar();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, *content).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn tar_with_link(name: &str, target: &str, kind: tar::EntryType) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_ustar();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.as_old_mut().linkname[..target.len()].copy_from_slice(target.as_bytes());
+        header.set_entry_type(kind);
+        header.set_size(0);
+        header.set_cksum();
+        builder.append(&header, &[][..]).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn gzip(content: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zip(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in members {
+            writer
+                .start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn inspect(name: &str, content: &[u8], policy: &ArchivePolicy) -> Result<(), String> {
+        fs::create_dir_all("file_tests").unwrap();
+        let path = format!("file_tests/{}", name);
+        fs::write(&path, content).unwrap();
+        let file_type = FileType::sniff(content).unwrap();
+        inspect_archive(File::open(&path).unwrap(), file_type, policy, &FileTypeConfig::default())
+    }
+
+    fn enabled() -> ArchivePolicy {
+        ArchivePolicy {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn member_path_test() {
+        assert_eq!(check_member_path("go/bin/go"), Ok(()));
+        assert_eq!(check_member_path("go/..bin/go"), Ok(()));
+        assert_eq!(check_member_path("../etc/passwd").is_err(), true);
+        assert_eq!(check_member_path("go/../../etc/passwd").is_err(), true);
+        assert_eq!(check_member_path("/etc/passwd").is_err(), true);
+        assert_eq!(check_member_path("..\\windows\\system32").is_err(), true);
+        assert_eq!(check_member_path("C:/windows").is_err(), true);
+    }
+
+    #[test]
+    fn link_target_test() {
+        assert_eq!(check_link_target("go/bin/gofmt", "../pkg/gofmt", true), Ok(()));
+        assert_eq!(check_link_target("go/bin/gofmt", "../../../etc", true).is_err(), true);
+        assert_eq!(check_link_target("go/bin/gofmt", "/etc/passwd", true).is_err(), true);
+        assert_eq!(check_link_target("go/bin/gofmt", "go/bin/go", false), Ok(()));
+        assert_eq!(check_link_target("go/bin/gofmt", "../go", false).is_err(), true);
+    }
+
+    #[test]
+    fn clean_archives_test() {
+        let members: &[(&str, &[u8])] = &[("go/VERSION", b"go1.12.5"), ("go/bin/go", b"\x7fELF")];
+        assert_eq!(inspect("clean.tar", &tar(members), &enabled()), Ok(()));
+        assert_eq!(inspect("clean.tar.gz", &gzip(&tar(members)), &enabled()), Ok(()));
+        assert_eq!(inspect("clean.zip", &zip(members), &enabled()), Ok(()));
+    }
+
+    #[test]
+    fn traversal_test() {
+        let evil: &[(&str, &[u8])] = &[("go/VERSION", b"go1.12.5"), ("../../.bashrc", b"curl")];
+        assert_eq!(inspect("evil.tar.gz", &gzip(&tar(evil)), &enabled()).is_err(), true);
+        assert_eq!(inspect("evil.zip", &zip(evil), &enabled()).is_err(), true);
+        let link = tar_with_link("go/link", "../../etc/passwd", tar::EntryType::Symlink);
+        assert_eq!(inspect("link.tar", &link, &enabled()).is_err(), true);
+        let hard_link = tar_with_link("go/link", "/etc/shadow", tar::EntryType::Link);
+        assert_eq!(inspect("hard_link.tar", &hard_link, &enabled()).is_err(), true);
+    }
+
+    #[test]
+    fn nested_archive_test() {
+        let inner = zip(&[("../../evil.sh", b"rm -rf /")]);
+        let outer = gzip(&tar(&[("release/inner.zip", &inner)]));
+        let reason = inspect("nested.tar.gz", &outer, &enabled()).unwrap_err();
+        assert_eq!(reason.contains("release/inner.zip"), true);
+        let clean = gzip(&tar(&[("release/inner.zip", &zip(&[("a.txt", b"a")]))]));
+        assert_eq!(inspect("nested_clean.tar.gz", &clean, &enabled()), Ok(()));
+        let shallow = ArchivePolicy {
+            max_depth: 1,
+            ..enabled()
+        };
+        assert_eq!(inspect("nested_deep.tar.gz", &clean, &shallow).is_err(), true);
+    }
+
+    #[test]
+    fn bomb_test() {
+        let zeros = vec![0; 4 * RATIO_FLOOR as usize];
+        let members: &[(&str, &[u8])] = &[("zeros", &zeros)];
+        assert_eq!(inspect("bomb.zip", &zip(members), &enabled()).is_err(), true);
+        assert_eq!(inspect("bomb.tar.gz", &gzip(&tar(members)), &enabled()).is_err(), true);
+        let lenient = ArchivePolicy {
+            max_ratio: 10_000,
+            ..enabled()
+        };
+        assert_eq!(inspect("bomb_allowed.zip", &zip(members), &lenient), Ok(()));
+    }
+
+    #[test]
+    fn member_policy_test() {
+        let members: &[(&str, &[u8])] = &[("bin/setup.exe", b"MZ"), ("README", b"readme")];
+        let denied = ArchivePolicy {
+            denied_names: vec![Regex::new(r"\.exe$").unwrap()],
+            ..enabled()
+        };
+        assert_eq!(inspect("denied.zip", &zip(members), &denied).is_err(), true);
+        let small = ArchivePolicy {
+            max_member_size: 4,
+            ..enabled()
+        };
+        assert_eq!(inspect("large.zip", &zip(members), &small).is_err(), true);
+        let few = ArchivePolicy {
+            max_members: 1,
+            ..enabled()
+        };
+        assert_eq!(inspect("many.zip", &zip(members), &few).is_err(), true);
+        // A member claiming to be an rpm has to be one
+        let fake_rpm: &[(&str, &[u8])] = &[("tools.rpm", b"#!/bin/sh")];
+        fs::write("file_tests/fake_rpm.zip", zip(fake_rpm)).unwrap();
+        let file_types = FileTypeConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            inspect_archive(File::open("file_tests/fake_rpm.zip").unwrap(), FileType::Zip, &enabled(), &file_types)
+                .is_err(),
+            true
+        );
+    }
+
+    #[test]
+    fn archive_policy_test() {
+        let policy = |content: &str| {
+            let doc = &YamlLoader::load_from_str(content).unwrap()[0];
+            ArchivePolicy::from_yaml(&doc["archives"])
+        };
+        let configured = policy(
+            "archives:\n  enabled: true\n  max_depth: 2\n  max_ratio: 50\n  denied_names: ['\\.exe$']\n",
+        )
+        .unwrap();
+        assert_eq!(configured.enabled, true);
+        assert_eq!(configured.max_depth, 2);
+        assert_eq!(configured.max_ratio, 50);
+        assert_eq!(configured.max_members, ArchivePolicy::default().max_members);
+        assert_eq!(configured.denied_names[0].is_match("setup.exe"), true);
+        assert_eq!(policy("archives:\n  max_ratio: -1\n").is_err(), true);
+        assert_eq!(policy("archives:\n  denied_names: ['(']\n").is_err(), true);
+    }
+}
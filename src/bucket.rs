@@ -1,79 +1,421 @@
 use crate::rusoto_s3::S3;
-use crate::data::{create_data_file, delete_data_file};
+use rusoto_core::RusotoError;
+use crate::data::{
+    delete_data_file, remove_staged_file, stage_data_file, staged_path, working_path, WorkingCopy,
+};
+use crate::checksum::{
+    content_md5, record_sha256, sha256_file, sha256_reader, stored_sha256,
+    verify_source_digest, SHA256_METADATA_KEY,
+    SOURCE_ETAG_METADATA_KEY, SOURCE_VERSION_METADATA_KEY,
+};
+use crate::config::JobConfig;
+use crate::envelope::{
+    remove_envelope_metadata, PLAINTEXT_SHA256_METADATA_KEY, WRAPPED_KEY_METADATA_KEY,
+};
+use crate::error::BearcatError;
+use crate::encryption::{etag_is_md5, CustomerKey, ServerSideEncryption, CUSTOMER_KEY_ALGORITHM};
+use crate::inspect::inspect_staged_file;
+use crate::etag::{file_matches_etag, multipart_etag, normalize_etag, part_digest, ETagMatch};
+use crate::metadata::ObjectHeaders;
+use crate::partition::Partition;
+use crate::objects::{
+    copy_part_ranges, create_file_parts, create_reader_parts, FilePart, ObjectDetails,
+    MAX_COPY_OBJECT_SIZE,
+};
+use sha2::{Digest, Sha256};
 use std::error::Error;
 use rusoto_s3::{
-    CompleteMultipartUploadRequest, CompletedMultipartUpload, CompletedPart,
-    CreateMultipartUploadRequest, DeletedObject, DeleteObjectRequest, DeleteObjectOutput,
-    GetObjectRequest, UploadPartRequest, GetObjectOutput, GetObjectError, HeadBucketRequest,
-    ListObjectsV2Request, ListObjectsV2Error, ListObjectsV2Output,
+    AbortMultipartUploadRequest, CompleteMultipartUploadOutput, CompleteMultipartUploadRequest, CompletedMultipartUpload, CompletedPart,
+    CreateMultipartUploadRequest, DeleteObjectRequest, DeleteObjectOutput,
+    GetObjectRequest, UploadPartRequest, HeadBucketRequest,
+    HeadObjectRequest, HeadObjectOutput, GetObjectTaggingRequest, PutObjectTaggingRequest, Tag,
+    Tagging,
+    ListObjectsV2Request, Object, PutObjectRequest,
+    GlacierJobParameters, RestoreObjectRequest, RestoreRequest,
+    DeleteMarkerEntry, GetBucketVersioningRequest, ListObjectVersionsRequest, ObjectVersion,
+    CopyObjectRequest, UploadPartCopyRequest, GetBucketLocationRequest,
 };
-use std::fs::File;
 use std::fs;
-use std::io::prelude::*;
-use std::io::BufReader;
+use std::io;
 use futures::prelude::*;
 use std::path::Path;
-use bytes::Bytes;
 use log::{info, warn};
 
-static BASE_PATH: &str = "data";
-
 // Download contents to an S3 bucket
 pub fn download<'a, 'b>(
     client: &rusoto_s3::S3Client,
     path: &'a str,
     bucket: &'b str,
-) -> Result<bool, Box<dyn Error>> {
+    job: &JobConfig,
+) -> Result<ObjectDetails, Box<dyn Error>> {
+    download_version(client, path, bucket, None, job)
+}
+
+// Download a specific version of an object. The latest version is removed from
+// the source once it is staged, historical versions are left in place.
+pub fn download_version(
+    client: &rusoto_s3::S3Client,
+    path: &str,
+    bucket: &str,
+    version_id: Option<&str>,
+    job: &JobConfig,
+) -> Result<ObjectDetails, Box<dyn Error>> {
     check_bucket_access(client, bucket)?;
-    let req = GetObjectRequest {
+    if version_id.is_none() {
+        if let Some(details) = reuse_staged_file(client, path, bucket, job)? {
+            let details = open_staged_file(details, job)?;
+            let details = inspect_staged(client, bucket, details, job)?;
+            delete_bucket_object(client, bucket, path)?;
+            return Ok(details);
+        }
+    }
+    let mut req = GetObjectRequest {
         bucket: String::from(bucket),
         key: String::from(path),
+        version_id: version_id.map(str::to_owned),
         ..Default::default()
     };
+    if let Some(key) = &job.encryption.source_customer_key {
+        key.apply_to_get_object(&mut req);
+    }
     info!(target: "BUCKET DOWNLOAD", "Checking accesss to bucket: {:?}",&bucket);
-    let res = client
+    let mut res = client
         .get_object(req)
         .sync()
-        .map_err(|e| format! {"Error getting object from source bucket {:?}", e})?;
-    let stream = res.body.unwrap();
-    let body = stream.concat2().wait().unwrap();
-    create_data_file(BASE_PATH, path, &body)?;
-    delete_bucket_object(client, bucket, path)?;
+        .map_err(|e| BearcatError::from_rusoto("Error getting object from source bucket", e))?;
+    // The body isn't read until there is room to stage it, along with the
+    // copy it is decrypted into or sealed in
+    let working_copies = if job.client_encryption.encrypt || job.client_encryption.decrypt {
+        1
+    } else {
+        0
+    };
+    job.staging
+        .reserve(path, res.content_length.unwrap_or(0) as u64, working_copies)
+        .map_err(BearcatError::LocalIo)?;
+    let stream = res.body.take().ok_or_else(|| format!("No content returned for {:?}", path))?;
+    let (sha256, length) = stage_data_file(&job.staging.path, path, stream, &job.staging)
+        .map_err(|e| format!("Error reading {:?} from {:?}: {}", path, bucket, e))?;
+    // Verify the content before anything is removed from the source bucket
+    let staged = staged_path(&job.staging.path, path);
+    let comparable_e_tag = res.e_tag.as_ref().map(String::as_str).filter(|_| {
+        etag_is_md5(
+            res.server_side_encryption.as_ref().map(String::as_str),
+            res.sse_customer_algorithm.as_ref().map(String::as_str),
+        )
+    });
+    let verified = match verify_source_digest(
+        || job.staging.open(&staged),
+        length,
+        &sha256,
+        comparable_e_tag,
+        res.metadata.as_ref(),
+    ) {
+        Ok(verified) => verified,
+        Err(e) => {
+            delete_data_file(&job.staging.path, path, &job.staging)?;
+            return Err(BearcatError::Integrity(format!("Integrity check failed for {:?}: {}", path, e)).into());
+        }
+    };
+    if !verified {
+        warn!(target: "BUCKET DOWNLOAD", "No comparable digest for {:?}, content was not verified", path);
+    }
+    let tags = get_object_tags(client, bucket, path, version_id)?;
+    let headers = ObjectHeaders::from_get_object(&res, tags);
+    let details = ObjectDetails {
+        key: path.to_owned(),
+        e_tag: res.e_tag,
+        version_id: version_id.map(str::to_owned),
+        sha256,
+        storage_class: res.storage_class,
+        headers,
+        blocked: None,
+    };
+    let details = open_staged_file(details, job)?;
+    let details = inspect_staged(client, bucket, details, job)?;
+    if version_id.is_none() {
+        delete_bucket_object(client, bucket, path)?;
+    }
     info!(target: "BUCKET DOWNLOAD", "Download completed successfully from {:?}",&bucket);
-    Ok(true)
+    Ok(details)
+}
+
+// Sealed source objects are opened in staging when the job decrypts, so the
+// checks and the destination see the plaintext
+fn open_staged_file(mut details: ObjectDetails, job: &JobConfig) -> Result<ObjectDetails, Box<dyn Error>> {
+    if !job.client_encryption.decrypt {
+        return Ok(details);
+    }
+    let envelope = match job.client_encryption.open(&details.headers.metadata) {
+        Ok(Some(envelope)) => envelope,
+        Ok(None) => return Ok(details),
+        Err(e) => {
+            delete_data_file(&job.staging.path, &details.key, &job.staging)?;
+            return Err(format!("Could not decrypt {:?}: {}", details.key, e).into());
+        }
+    };
+    let staged = staged_path(&job.staging.path, &details.key);
+    let opened = working_path(&job.staging.path, &details.key, "opened");
+    let decrypted = job
+        .staging
+        .open(&staged)
+        .and_then(|input| envelope.decrypt(input, job.staging.create(&opened)?))
+        .and_then(|output| output.finish());
+    if let Err(e) = decrypted {
+        remove_staged_file(&opened, &job.staging)?;
+        delete_data_file(&job.staging.path, &details.key, &job.staging)?;
+        return Err(format!("Could not decrypt {:?}: {}", details.key, e).into());
+    }
+    fs::rename(&opened, &staged)?;
+    let sha256 = sha256_reader(job.staging.open(&staged)?)?;
+    match details.headers.metadata.get(PLAINTEXT_SHA256_METADATA_KEY) {
+        Some(expected) if expected != &sha256 => {
+            delete_data_file(&job.staging.path, &details.key, &job.staging)?;
+            return Err(BearcatError::Integrity(format!(
+                "Integrity check failed for {:?}: the plaintext should have SHA-256 {} but has {}",
+                details.key, expected, sha256
+            ))
+            .into());
+        }
+        _ => {}
+    }
+    remove_envelope_metadata(&mut details.headers.metadata);
+    details.sha256 = sha256;
+    info!(target: "BUCKET DOWNLOAD", "Decrypted staged file {:?}", staged);
+    Ok(details)
 }
 
-// Upload using multipart method, the contents to an S3 bucket
-pub fn upload<'a, 'b, 'c>(
+// Blocked files are removed from staging before the source is touched, so a
+// blocked object stays in the source bucket. With quarantine enabled they are
+// kept instead, to be held in quarantine with the reason they were blocked.
+fn inspect_staged(
     client: &rusoto_s3::S3Client,
-    path: &'a str,
-    filename: &'b str,
-    bucket: &'c str,
+    bucket: &str,
+    mut details: ObjectDetails,
+    job: &JobConfig,
+) -> Result<ObjectDetails, Box<dyn Error>> {
+    let staged = staged_path(&job.staging.path, &details.key);
+    // A signature that can't be read counts as a missing one
+    let signature = if job.signatures.enabled {
+        let signature_key = job.signatures.signature_key(&details.key);
+        let customer_key = job.encryption.source_customer_key.as_ref();
+        get_bucket_object(client, bucket, &signature_key, customer_key).ok()
+    } else {
+        None
+    };
+    let signature = signature.as_ref().map(Vec::as_slice);
+    match inspect_staged_file(&staged, &details, signature, job) {
+        Ok(()) => Ok(details),
+        Err(blocked) if job.quarantine.enabled => {
+            warn!(target: "INSPECTION", "{}, it will be held in quarantine", blocked);
+            details.blocked = Some(blocked.reason);
+            Ok(details)
+        }
+        Err(blocked) => {
+            delete_data_file(&job.staging.path, &details.key, &job.staging)?;
+            Err(Box::new(blocked))
+        }
+    }
+}
+
+// A file staged by an earlier run that still matches the source doesn't need
+// to be downloaded again. A SHA-256 stored in the source metadata is compared
+// when present, otherwise the ETag is, as long as it is md5 based.
+fn reuse_staged_file(
+    client: &rusoto_s3::S3Client,
+    path: &str,
+    bucket: &str,
+    job: &JobConfig,
+) -> Result<Option<ObjectDetails>, Box<dyn Error>> {
+    let staged = staged_path(&job.staging.path, path);
+    // Encrypted staged files from an earlier run can't be read with this run's key
+    if !Path::new(&staged).is_file() || job.staging.encrypts() {
+        return Ok(None);
+    }
+    let head = head_bucket_object(
+        client,
+        bucket,
+        path,
+        job.encryption.source_customer_key.as_ref(),
+    )?;
+    let sha256 = sha256_file(&staged)?;
+    let matches = match head.metadata.as_ref().and_then(stored_sha256) {
+        Some(expected) => expected == &sha256,
+        None => match &head.e_tag {
+            Some(e_tag)
+                if etag_is_md5(
+                    head.server_side_encryption.as_ref().map(String::as_str),
+                    head.sse_customer_algorithm.as_ref().map(String::as_str),
+                ) =>
+            {
+                file_matches_etag(&staged, e_tag)? == ETagMatch::Match
+            }
+            _ => false,
+        },
+    };
+    if !matches {
+        return Ok(None);
+    }
+    info!(target: "BUCKET DOWNLOAD", "Staged file {:?} matches the source, skipping download", staged);
+    let headers = ObjectHeaders::from_head_object(&head, get_object_tags(client, bucket, path, None)?);
+    Ok(Some(ObjectDetails {
+        key: path.to_owned(),
+        e_tag: head.e_tag.clone(),
+        version_id: None,
+        sha256,
+        storage_class: head.storage_class.clone(),
+        headers,
+        blocked: None,
+    }))
+}
+
+// Upload using multipart method, the contents to an S3 bucket. The key can
+// differ from the staged file name, like when the object goes to quarantine.
+pub fn upload(
+    client: &rusoto_s3::S3Client,
+    path: &str,
+    filename: &str,
+    bucket: &str,
+    key: &str,
+    source: &ObjectDetails,
+    job: &JobConfig,
 ) -> Result<bool, Box<dyn Error>> {
     check_bucket_access(client, bucket)?;
-    let req = CreateMultipartUploadRequest {
+    // Make sure the staged file is still what was downloaded from the source
+    let staged = staged_path(path, filename);
+    if sha256_reader(job.staging.open(&staged)?)? != source.sha256 {
+        return Err(BearcatError::Integrity(format!(
+            "Staged file {:?} does not match the downloaded content",
+            staged
+        ))
+        .into());
+    }
+    let mut headers = source.headers.clone();
+    job.headers.apply(&mut headers);
+    // The digests bearcat records always reflect this transfer
+    let mut metadata = headers.metadata.clone();
+    // Objects are sealed into a copy of the staged file, which is what gets
+    // uploaded. Objects that are still sealed from an earlier hop pass as they are.
+    let sealed = if job.client_encryption.encrypt
+        && !metadata.contains_key(WRAPPED_KEY_METADATA_KEY)
+    {
+        let sealed = WorkingCopy::new(path, filename, "sealed", &job.staging);
+        let envelope = job.client_encryption.seal()?;
+        envelope.encrypt(job.staging.open(&staged)?, fs::File::create(&sealed.path)?)?;
+        envelope.apply_to_metadata(&mut metadata);
+        metadata.insert(PLAINTEXT_SHA256_METADATA_KEY.to_owned(), source.sha256.clone());
+        record_sha256(&mut metadata, &sha256_file(&sealed.path)?);
+        Some(sealed)
+    } else {
+        record_sha256(&mut metadata, &source.sha256);
+        None
+    };
+    if let Some(e_tag) = &source.e_tag {
+        metadata.insert(
+            SOURCE_ETAG_METADATA_KEY.to_owned(),
+            normalize_etag(e_tag).to_owned(),
+        );
+    }
+    if let Some(version_id) = &source.version_id {
+        metadata.insert(SOURCE_VERSION_METADATA_KEY.to_owned(), version_id.to_owned());
+    }
+    let mut req = CreateMultipartUploadRequest {
         bucket: bucket.to_owned(),
-        key: filename.to_owned(),
+        key: key.to_owned(),
+        content_type: headers.content_type.clone(),
+        cache_control: headers.cache_control.clone(),
+        content_encoding: headers.content_encoding.clone(),
+        content_disposition: headers.content_disposition.clone(),
+        content_language: headers.content_language.clone(),
+        expires: headers.expires.clone(),
+        metadata: Some(metadata),
+        storage_class: job
+            .storage_class
+            .destination_class(source.storage_class.as_ref().map(String::as_str)),
         ..Default::default()
     };
+    job.encryption
+        .destination
+        .apply_to_create_multipart_upload(&mut req);
 
+    // Parts are read from disk one at a time, just before each is uploaded
+    let parts: Box<dyn Iterator<Item = io::Result<FilePart>>> = match &sealed {
+        Some(sealed) => Box::new(create_file_parts(&sealed.path)?),
+        None => Box::new(create_reader_parts(job.staging.open(&staged)?)?),
+    };
     let res = client
         .create_multipart_upload(req)
         .sync()
-        .map_err(|e| format! {"Failed to create multipart"})?;
-    let upload_id = res.upload_id.unwrap();
+        .map_err(|e| {
+            BearcatError::from_rusoto(format!("Failed to create the multipart upload of {:?}", key), e)
+        })?;
+    let upload_id = res
+        .upload_id
+        .ok_or_else(|| format!("No upload id returned for {:?}", key))?;
+    let (completed, part_digests) = match upload_parts(client, bucket, key, &upload_id, parts, job) {
+        Ok(uploaded) => uploaded,
+        Err(e) => {
+            abort_multipart_upload(client, bucket, key, &upload_id);
+            return Err(e);
+        }
+    };
+    drop(sealed);
+
+    // S3 checked each part against its Content-MD5, this checks the assembled
+    // object when its ETag is md5 based
+    if etag_is_md5(
+        completed.server_side_encryption.as_ref().map(String::as_str),
+        destination_customer_algorithm(job),
+    ) {
+        let expected_e_tag = multipart_etag(&part_digests);
+        match completed.e_tag.as_ref().map(|e| normalize_etag(e)) {
+            Some(e_tag) if e_tag == expected_e_tag => {}
+            e_tag => {
+                return Err(BearcatError::Integrity(format!(
+                    "Integrity check failed for {:?}: expected ETag {} but the destination has {:?}",
+                    key, expected_e_tag, e_tag
+                ))
+                .into())
+            }
+        }
+    }
+
+    if !headers.tags.is_empty() {
+        put_object_tags(client, bucket, key, headers.tags)?;
+    }
 
-    // Create all of the parts for uploading
-    info!(target: "UPLOAD", "Creating parts for multipart upload...");
-    let parts = processes_object(path, bucket, filename, &upload_id)?;
+    info!(target: "UPLOAD", "Upload to {:?} completed successfully", &bucket);
+    delete_data_file(path, &filename, &job.staging)
+}
+
+// Uploads the parts of a multipart upload and completes it, returning the
+// completed upload with the MD5 digest of each part
+fn upload_parts(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    parts: Box<dyn Iterator<Item = io::Result<FilePart>>>,
+    job: &JobConfig,
+) -> Result<(CompleteMultipartUploadOutput, Vec<md5::Digest>), Box<dyn Error>> {
+    info!(target: "UPLOAD", "Uploading parts for multipart upload...");
     let mut completed_parts = Vec::new();
+    let mut part_digests = Vec::new();
     for part in parts {
+        let part = part?;
         let part_num = part.part_number;
+        let digest = md5::compute(&part.body);
+        let mut part_req =
+            create_upload_part(bucket, key, upload_id, part.body, part_num, &digest);
+        job.encryption.destination.apply_to_upload_part(&mut part_req);
         let response = client
-            .upload_part(part)
+            .upload_part(part_req)
             .sync()
-            .map_err(|e| format! {"Failed to upload part"})?;
+            .map_err(|e| {
+                BearcatError::from_rusoto(format!("Failed to upload part {} of {:?}", part_num, key), e)
+            })?;
         // Collect the completed  parts for finalizing later
+        part_digests.push(digest);
         completed_parts.push(CompletedPart {
             e_tag: response.e_tag.clone(),
             part_number: Some(part_num),
@@ -86,49 +428,385 @@ pub fn upload<'a, 'b, 'c>(
 
     let complete_req = CompleteMultipartUploadRequest {
         bucket: bucket.to_owned(),
-        key: filename.to_owned(),
+        key: key.to_owned(),
         upload_id: upload_id.to_owned(),
         multipart_upload: Some(completed_upload),
         ..Default::default()
     };
 
     info!(target: "UPLOAD", "Sending multipart upload completion request...");
-    client
+    let completed = client
         .complete_multipart_upload(complete_req)
         .sync()
-        .map_err(|e| format! {"Failed to complete the multipart upload"})?;
+        .map_err(|e| {
+            BearcatError::from_rusoto(format!("Failed to complete the multipart upload of {:?}", key), e)
+        })?;
+    Ok((completed, part_digests))
+}
 
-    info!(target: "UPLOAD", "Upload to {:?} completed successfully", &bucket);
-    delete_data_file(BASE_PATH, &filename)
+// Drops the parts of a multipart upload that won't be completed, so they
+// aren't left behind in the bucket
+fn abort_multipart_upload(client: &rusoto_s3::S3Client, bucket: &str, key: &str, upload_id: &str) {
+    let req = AbortMultipartUploadRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        upload_id: upload_id.to_owned(),
+        ..Default::default()
+    };
+    if let Err(e) = client.abort_multipart_upload(req).sync() {
+        warn!(target: "UPLOAD", "Failed to abort the multipart upload of {:?}: {:?}", key, e);
+    }
 }
 
-// Processes the file and return a vec of UploadPartRequest
-fn processes_object(
-    path: &str,
+// Copies an object between buckets without its content leaving S3. The client
+// needs read access to the source bucket and write access to the destination.
+// Like a download, copying the latest version removes it from the source,
+// which is done with the source client.
+pub fn copy(
+    client: &rusoto_s3::S3Client,
+    source_client: &rusoto_s3::S3Client,
+    key: &str,
+    source_bucket: &str,
+    version_id: Option<&str>,
+    destination_bucket: &str,
+    destination_key: &str,
+    job: &JobConfig,
+) -> Result<bool, Box<dyn Error>> {
+    check_bucket_access(client, source_bucket)?;
+    check_bucket_access(client, destination_bucket)?;
+    let customer_key = job.encryption.source_customer_key.as_ref();
+    let head = head_bucket_object_version(client, source_bucket, key, version_id, customer_key)?;
+    let tags = get_object_tags(client, source_bucket, key, version_id)?;
+    let mut headers = ObjectHeaders::from_head_object(&head, tags);
+    job.headers.apply(&mut headers);
+    // The content isn't read, so the SHA-256 is only known when an earlier
+    // transfer recorded it on the source object
+    let mut metadata = headers.metadata.clone();
+    let source_metadata = head.metadata.clone().unwrap_or_default();
+    if let Some(sha256) = source_metadata.get(SHA256_METADATA_KEY) {
+        metadata.insert(SHA256_METADATA_KEY.to_owned(), sha256.to_owned());
+    }
+    if let Some(e_tag) = &head.e_tag {
+        metadata.insert(
+            SOURCE_ETAG_METADATA_KEY.to_owned(),
+            normalize_etag(e_tag).to_owned(),
+        );
+    }
+    if let Some(version_id) = version_id {
+        metadata.insert(SOURCE_VERSION_METADATA_KEY.to_owned(), version_id.to_owned());
+    }
+    headers.metadata = metadata;
+    let storage_class = job
+        .storage_class
+        .destination_class(head.storage_class.as_ref().map(String::as_str));
+    let source = CopySource {
+        bucket: source_bucket,
+        key,
+        version_id,
+        customer_key,
+        head,
+    };
+    copy_with_headers(client, &source, destination_bucket, destination_key, &headers, storage_class, job)?;
+    if version_id.is_none() {
+        delete_bucket_object(source_client, source_bucket, key)?;
+    }
+    info!(target: "COPY", "Copied {:?} from {:?} to {:?}", key, source_bucket, destination_bucket);
+    Ok(true)
+}
+
+// Moves an object that passed its checks out of quarantine to its final key,
+// keeping the headers, metadata and tags it was written with
+pub fn promote_object(
+    client: &rusoto_s3::S3Client,
+    quarantine_bucket: &str,
+    quarantine_key: &str,
+    destination_bucket: &str,
+    key: &str,
+    job: &JobConfig,
+) -> Result<bool, Box<dyn Error>> {
+    // The quarantine copy is encrypted the same way as the final object
+    let customer_key = match &job.encryption.destination {
+        ServerSideEncryption::Customer(customer_key) => Some(customer_key),
+        _ => None,
+    };
+    let head = head_bucket_object(client, quarantine_bucket, quarantine_key, customer_key)?;
+    let tags = get_object_tags(client, quarantine_bucket, quarantine_key, None)?;
+    let headers = ObjectHeaders::from_head_object(&head, tags);
+    let storage_class = head.storage_class.clone();
+    let source = CopySource {
+        bucket: quarantine_bucket,
+        key: quarantine_key,
+        version_id: None,
+        customer_key,
+        head,
+    };
+    copy_with_headers(client, &source, destination_bucket, key, &headers, storage_class, job)?;
+    delete_bucket_object(client, quarantine_bucket, quarantine_key)?;
+    info!(target: "QUARANTINE", "Promoted {:?} to {:?}", quarantine_key, key);
+    Ok(true)
+}
+
+// An object being copied server side
+struct CopySource<'a> {
+    bucket: &'a str,
+    key: &'a str,
+    version_id: Option<&'a str>,
+    // Needed to read SSE-C encrypted sources
+    customer_key: Option<&'a CustomerKey>,
+    head: HeadObjectOutput,
+}
+
+// Copies the source object to the destination key, replacing its headers,
+// metadata and tags with the given ones
+fn copy_with_headers(
+    client: &rusoto_s3::S3Client,
+    source: &CopySource,
     bucket: &str,
-    filename: &str,
+    key: &str,
+    headers: &ObjectHeaders,
+    storage_class: Option<String>,
+    job: &JobConfig,
+) -> Result<(), Box<dyn Error>> {
+    let head = &source.head;
+    let copy_source_header = copy_source(source.bucket, source.key, source.version_id);
+    let size = head.content_length.unwrap_or(0);
+    if size > MAX_COPY_OBJECT_SIZE {
+        let req = CreateMultipartUploadRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            content_type: headers.content_type.clone(),
+            cache_control: headers.cache_control.clone(),
+            content_encoding: headers.content_encoding.clone(),
+            content_disposition: headers.content_disposition.clone(),
+            content_language: headers.content_language.clone(),
+            expires: headers.expires.clone(),
+            metadata: Some(headers.metadata.clone()),
+            storage_class,
+            ..Default::default()
+        };
+        copy_multipart(client, req, &copy_source_header, size, source.customer_key, job)?;
+    } else {
+        let mut req = CopyObjectRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            copy_source: copy_source_header,
+            metadata_directive: Some("REPLACE".to_owned()),
+            tagging_directive: Some("REPLACE".to_owned()),
+            content_type: headers.content_type.clone(),
+            cache_control: headers.cache_control.clone(),
+            content_encoding: headers.content_encoding.clone(),
+            content_disposition: headers.content_disposition.clone(),
+            content_language: headers.content_language.clone(),
+            expires: headers.expires.clone(),
+            metadata: Some(headers.metadata.clone()),
+            storage_class,
+            ..Default::default()
+        };
+        job.encryption.destination.apply_to_copy_object(&mut req);
+        if let Some(customer_key) = source.customer_key {
+            customer_key.apply_to_copy_source(&mut req);
+        }
+        let res = client
+            .copy_object(req)
+            .sync()
+            .map_err(|e| {
+                BearcatError::from_rusoto(format!("Error copying {:?} to {:?}", source.key, bucket), e)
+            })?;
+        // A single part source keeps its md5 ETag when neither side uses SSE-KMS or SSE-C
+        let destination_e_tag = res.copy_object_result.and_then(|r| r.e_tag);
+        let source_is_md5 = etag_is_md5(
+            head.server_side_encryption.as_ref().map(String::as_str),
+            head.sse_customer_algorithm.as_ref().map(String::as_str),
+        );
+        let destination_is_md5 = etag_is_md5(
+            res.server_side_encryption.as_ref().map(String::as_str),
+            res.sse_customer_algorithm.as_ref().map(String::as_str),
+        );
+        if let (Some(source_e_tag), Some(destination_e_tag)) = (&head.e_tag, &destination_e_tag) {
+            let source_e_tag = normalize_etag(source_e_tag);
+            if source_is_md5
+                && destination_is_md5
+                && !source_e_tag.contains('-')
+                && source_e_tag != normalize_etag(destination_e_tag)
+            {
+                return Err(BearcatError::Integrity(format!(
+                    "Integrity check failed for {:?}: expected ETag {} but the destination has {}",
+                    key, source_e_tag, destination_e_tag
+                ))
+                .into());
+            }
+        }
+    }
+    if !headers.tags.is_empty() {
+        put_object_tags(client, bucket, key, headers.tags.clone())?;
+    }
+    Ok(())
+}
+
+// Objects over 5 GiB are copied a range at a time with UploadPartCopy
+fn copy_multipart(
+    client: &rusoto_s3::S3Client,
+    mut req: CreateMultipartUploadRequest,
+    source: &str,
+    size: i64,
+    customer_key: Option<&CustomerKey>,
+    job: &JobConfig,
+) -> Result<(), Box<dyn Error>> {
+    let bucket = req.bucket.clone();
+    let key = req.key.clone();
+    job.encryption
+        .destination
+        .apply_to_create_multipart_upload(&mut req);
+    let res = client
+        .create_multipart_upload(req)
+        .sync()
+        .map_err(|e| {
+            BearcatError::from_rusoto(format!("Failed to create multipart copy of {:?}", key), e)
+        })?;
+    let upload_id = res
+        .upload_id
+        .ok_or_else(|| format!("No upload id returned for the multipart copy of {:?}", key))?;
+    let copied = copy_parts(client, &bucket, &key, &upload_id, source, size, customer_key, job);
+    let (completed, part_e_tags) = match copied {
+        Ok(copied) => copied,
+        Err(e) => {
+            abort_multipart_upload(client, &bucket, &key, &upload_id);
+            return Err(e);
+        }
+    };
+
+    // The assembled ETag has to agree with the parts S3 reported copying
+    // before the source can be removed
+    if etag_is_md5(
+        completed.server_side_encryption.as_ref().map(String::as_str),
+        destination_customer_algorithm(job),
+    ) {
+        let expected_e_tag = part_e_tags
+            .iter()
+            .map(|e_tag| e_tag.as_ref().and_then(|e_tag| part_digest(e_tag)))
+            .collect::<Option<Vec<md5::Digest>>>()
+            .map(|digests| multipart_etag(&digests));
+        match (expected_e_tag, completed.e_tag.as_ref().map(|e| normalize_etag(e))) {
+            (Some(expected_e_tag), Some(e_tag)) if e_tag == expected_e_tag => {}
+            (expected_e_tag, e_tag) => {
+                return Err(BearcatError::Integrity(format!(
+                    "Integrity check failed for {:?}: expected ETag {:?} but the destination has {:?}",
+                    key, expected_e_tag, e_tag
+                ))
+                .into())
+            }
+        }
+    }
+    Ok(())
+}
+
+// Copies the ranges of the source as the parts of a multipart upload and
+// completes it, returning the completed upload with the ETag of each part
+#[allow(clippy::too_many_arguments)]
+fn copy_parts(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
     upload_id: &str,
-) -> Result<Vec<UploadPartRequest>, std::io::Error> {
-    // Get the file ready for processing
-    let file = File::open(format!("{}/{}", path, filename))?;
-    let mut reader = BufReader::with_capacity(5242880, file);
-    // Get all of the parameters for the upload parts initialized
-    let mut index = 1;
-    let mut upload_requests = Vec::new();
-    // Start parsing the file and creating the parts, collecting them in a vector
-    while !reader.fill_buf()?.is_empty() {
-        upload_requests.push(create_upload_part(
-            bucket,
-            filename,
-            upload_id,
-            reader.buffer().to_vec(),
-            index,
-        ));
-        reader.consume(5242880);
-        index += 1;
-    }
-    // If there are no errors return the vec of UploadPartRequest
-    Ok(upload_requests)
+    source: &str,
+    size: i64,
+    customer_key: Option<&CustomerKey>,
+    job: &JobConfig,
+) -> Result<(CompleteMultipartUploadOutput, Vec<Option<String>>), Box<dyn Error>> {
+    info!(target: "COPY", "Copying parts of {:?}...", key);
+    let mut completed_parts = Vec::new();
+    for (part_number, range) in copy_part_ranges(size) {
+        let mut part_req = UploadPartCopyRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            copy_source: source.to_owned(),
+            copy_source_range: Some(range),
+            part_number,
+            upload_id: upload_id.to_owned(),
+            ..Default::default()
+        };
+        job.encryption
+            .destination
+            .apply_to_upload_part_copy(&mut part_req);
+        if let Some(customer_key) = customer_key {
+            customer_key.apply_to_upload_part_copy_source(&mut part_req);
+        }
+        let response = client
+            .upload_part_copy(part_req)
+            .sync()
+            .map_err(|e| {
+                BearcatError::from_rusoto(format!("Failed to copy part {} of {:?}", part_number, key), e)
+            })?;
+        completed_parts.push(CompletedPart {
+            e_tag: response.copy_part_result.and_then(|r| r.e_tag),
+            part_number: Some(part_number),
+        });
+    }
+    let part_e_tags = completed_parts.iter().map(|part| part.e_tag.clone()).collect();
+
+    let complete_req = CompleteMultipartUploadRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        upload_id: upload_id.to_owned(),
+        multipart_upload: Some(CompletedMultipartUpload {
+            parts: Some(completed_parts),
+        }),
+        ..Default::default()
+    };
+    let completed = client
+        .complete_multipart_upload(complete_req)
+        .sync()
+        .map_err(|e| {
+            BearcatError::from_rusoto(format!("Failed to complete the multipart copy of {:?}", key), e)
+        })?;
+    Ok((completed, part_e_tags))
+}
+
+// The SSE-C algorithm destination objects are written with, which keeps their
+// ETags from being md5 based
+fn destination_customer_algorithm(job: &JobConfig) -> Option<&'static str> {
+    match &job.encryption.destination {
+        ServerSideEncryption::Customer(_) => Some(CUSTOMER_KEY_ALGORITHM),
+        _ => None,
+    }
+}
+
+// The x-amz-copy-source header: the bucket and the URL encoded key, with the
+// version when a specific one is copied
+fn copy_source(bucket: &str, key: &str, version_id: Option<&str>) -> String {
+    let encoded: String = key
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    match version_id {
+        Some(version_id) => format!("{}/{}?versionId={}", bucket, encoded, version_id),
+        None => format!("{}/{}", bucket, encoded),
+    }
+}
+
+// Server side copies need a single client that can read the source bucket and
+// write the destination bucket, which is only possible within a partition
+pub fn can_copy_server_side(
+    client: &rusoto_s3::S3Client,
+    source_bucket: &str,
+    source_partition: Partition,
+    destination_partition: Partition,
+) -> bool {
+    if source_partition != destination_partition {
+        return false;
+    }
+    match check_bucket_access(client, source_bucket) {
+        Ok(_) => true,
+        Err(e) => {
+            info!(target: "COPY", "The destination credentials can't read {:?}: {}", source_bucket, e);
+            false
+        }
+    }
 }
 
 fn create_upload_part(
@@ -137,10 +815,12 @@ fn create_upload_part(
     upload_id: &str,
     body: Vec<u8>,
     part_number: i64,
+    digest: &md5::Digest,
 ) -> UploadPartRequest {
     UploadPartRequest {
         body: Some(body.into()),
         bucket: bucket.to_owned(),
+        content_md5: Some(content_md5(digest)),
         key: filename.to_owned(),
         upload_id: upload_id.to_owned(),
         part_number,
@@ -148,7 +828,7 @@ fn create_upload_part(
     }
 }
 
-fn delete_bucket_object(
+pub fn delete_bucket_object(
     client: &rusoto_s3::S3Client,
     bucket: &str,
     key: &str,
@@ -161,11 +841,309 @@ fn delete_bucket_object(
     let resp = client
         .delete_object(req)
         .sync()
-        .map_err(|e| format! {"Error deleting the bucket object"})?;
+        .map_err(|e| {
+            BearcatError::from_rusoto(format!("Error deleting {:?} from {:?}", key, bucket), e)
+        })?;
     info!(target: "DELETE OBJECT", "Deleted object {:?} successfully", bucket);
     Ok(resp)
 }
 
+pub fn get_object_tags(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
+    version_id: Option<&str>,
+) -> Result<Vec<Tag>, Box<dyn Error>> {
+    let req = GetObjectTaggingRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        version_id: version_id.map(str::to_owned),
+    };
+    let resp = client
+        .get_object_tagging(req)
+        .sync()
+        .map_err(|e| BearcatError::from_rusoto(format!("Error getting the tags of {:?}", key), e))?;
+    Ok(resp.tag_set)
+}
+
+pub fn put_object_tags(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
+    tags: Vec<Tag>,
+) -> Result<(), Box<dyn Error>> {
+    let req = PutObjectTaggingRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        tagging: Tagging { tag_set: tags },
+        ..Default::default()
+    };
+    client
+        .put_object_tagging(req)
+        .sync()
+        .map_err(|e| BearcatError::from_rusoto(format!("Error tagging {:?}", key), e))?;
+    info!(target: "UPLOAD", "Tagged object {:?} successfully", key);
+    Ok(())
+}
+
+pub fn head_bucket_object(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
+    customer_key: Option<&CustomerKey>,
+) -> Result<HeadObjectOutput, Box<dyn Error>> {
+    head_bucket_object_version(client, bucket, key, None, customer_key)
+}
+
+pub fn head_bucket_object_version(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
+    version_id: Option<&str>,
+    customer_key: Option<&CustomerKey>,
+) -> Result<HeadObjectOutput, Box<dyn Error>> {
+    let mut req = HeadObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        version_id: version_id.map(str::to_owned),
+        ..Default::default()
+    };
+    if let Some(customer_key) = customer_key {
+        customer_key.apply_to_head_object(&mut req);
+    }
+    let resp = client
+        .head_object(req)
+        .sync()
+        .map_err(|e| {
+            BearcatError::from_rusoto(format!("Error getting details of {:?} from {:?}", key, bucket), e)
+        })?;
+    Ok(resp)
+}
+
+// SHA-256 of an object's content, hashed as the body streams in
+pub fn hash_bucket_object(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
+) -> Result<String, Box<dyn Error>> {
+    hash_bucket_object_version(client, bucket, key, None, None)
+}
+
+pub fn hash_bucket_object_version(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
+    version_id: Option<&str>,
+    customer_key: Option<&CustomerKey>,
+) -> Result<String, Box<dyn Error>> {
+    let mut req = GetObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        version_id: version_id.map(str::to_owned),
+        ..Default::default()
+    };
+    if let Some(customer_key) = customer_key {
+        customer_key.apply_to_get_object(&mut req);
+    }
+    let res = client
+        .get_object(req)
+        .sync()
+        .map_err(|e| {
+            BearcatError::from_rusoto(format!("Error getting {:?} from {:?}", key, bucket), e)
+        })?;
+    let stream = res
+        .body
+        .ok_or_else(|| format!("No content returned for {:?}", key))?;
+    let hasher = stream
+        .fold(Sha256::new(), |mut hasher, chunk| {
+            hasher.input(&chunk);
+            Ok::<_, std::io::Error>(hasher)
+        })
+        .wait()?;
+    Ok(format!("{:x}", hasher.result()))
+}
+
+// Reads a small object, like a manifest, into memory
+pub fn get_bucket_object(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
+    customer_key: Option<&CustomerKey>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut req = GetObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+    if let Some(customer_key) = customer_key {
+        customer_key.apply_to_get_object(&mut req);
+    }
+    let res = client
+        .get_object(req)
+        .sync()
+        .map_err(|e| {
+            BearcatError::from_rusoto(format!("Error getting {:?} from {:?}", key, bucket), e)
+        })?;
+    let stream = res
+        .body
+        .ok_or_else(|| format!("No content returned for {:?}", key))?;
+    Ok(stream.concat2().wait()?.to_vec())
+}
+
+// Writes a small object bearcat generates itself, like a manifest, with the
+// destination's encryption settings
+pub fn put_bucket_object(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
+    content: Vec<u8>,
+    content_type: &str,
+    job: &JobConfig,
+) -> Result<(), Box<dyn Error>> {
+    let mut req = PutObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        content_md5: Some(content_md5(&md5::compute(&content))),
+        content_type: Some(content_type.to_owned()),
+        body: Some(content.into()),
+        ..Default::default()
+    };
+    job.encryption.destination.apply_to_put_object(&mut req);
+    client
+        .put_object(req)
+        .sync()
+        .map_err(|e| {
+            BearcatError::from_rusoto(format!("Error writing {:?} to {:?}", key, bucket), e)
+        })?;
+    info!(target: "BUCKET UPLOAD", "Wrote {:?} to {:?}", key, bucket);
+    Ok(())
+}
+
+// Lists every version and delete marker under the prefix, following the key
+// and version markers
+pub fn list_bucket_object_versions(
+    client: &rusoto_s3::S3Client,
+    bucket_name: &str,
+    prefix: Option<&str>,
+) -> Result<(Vec<ObjectVersion>, Vec<DeleteMarkerEntry>), Box<dyn Error>> {
+    let mut versions = Vec::new();
+    let mut delete_markers = Vec::new();
+    let mut key_marker = None;
+    let mut version_id_marker = None;
+    loop {
+        let req = ListObjectVersionsRequest {
+            bucket: bucket_name.to_owned(),
+            prefix: prefix.map(str::to_owned),
+            key_marker: key_marker.take(),
+            version_id_marker: version_id_marker.take(),
+            ..Default::default()
+        };
+        let res = client
+            .list_object_versions(req)
+            .sync()
+            .map_err(|e| {
+                BearcatError::from_rusoto(format!("Error listing versions in bucket {:?}", bucket_name), e)
+            })?;
+        versions.extend(res.versions.unwrap_or_default());
+        delete_markers.extend(res.delete_markers.unwrap_or_default());
+        if res.is_truncated != Some(true) {
+            break;
+        }
+        key_marker = res.next_key_marker;
+        version_id_marker = res.next_version_id_marker;
+    }
+    Ok((versions, delete_markers))
+}
+
+// Only a bucket with versioning enabled keeps delete markers and older versions
+pub fn is_bucket_versioned(
+    client: &rusoto_s3::S3Client,
+    bucket_name: &str,
+) -> Result<bool, Box<dyn Error>> {
+    let req = GetBucketVersioningRequest {
+        bucket: bucket_name.to_owned(),
+    };
+    let res = client
+        .get_bucket_versioning(req)
+        .sync()
+        .map_err(|e| {
+            BearcatError::from_rusoto(format!("Error getting the versioning of bucket {:?}", bucket_name), e)
+        })?;
+    Ok(res.status.as_ref().map(String::as_str) == Some("Enabled"))
+}
+
+// Region of a bucket, from GetBucketLocation or, when the credentials aren't
+// allowed to call it, from the x-amz-bucket-region header S3 sends with
+// HeadBucket redirects. The client can be in any region of the bucket's partition.
+pub fn bucket_region(
+    client: &rusoto_s3::S3Client,
+    bucket_name: &str,
+    client_region: &str,
+) -> Result<String, Box<dyn Error>> {
+    let req = GetBucketLocationRequest {
+        bucket: bucket_name.to_owned(),
+    };
+    match client.get_bucket_location(req).sync() {
+        Ok(res) => return Ok(location_region(res.location_constraint.as_ref().map(String::as_str))),
+        Err(e) => info!(target: "REGION", "Could not get the location of {:?}: {:?}", bucket_name, e),
+    }
+    let req = HeadBucketRequest {
+        bucket: bucket_name.to_owned(),
+    };
+    let e = match client.head_bucket(req).sync() {
+        Ok(_) => return Ok(client_region.to_owned()),
+        Err(e) => e,
+    };
+    if let RusotoError::Unknown(res) = &e {
+        if let Some(region) = res.headers.get("x-amz-bucket-region") {
+            return Ok(region.to_owned());
+        }
+    }
+    Err(BearcatError::from_rusoto(format!("Could not find the region of {:?}", bucket_name), e).into())
+}
+
+// GetBucketLocation leaves the constraint empty for us-east-1 and still
+// reports EU for old eu-west-1 buckets
+fn location_region(location_constraint: Option<&str>) -> String {
+    match location_constraint {
+        None | Some("") => "us-east-1".to_owned(),
+        Some("EU") => "eu-west-1".to_owned(),
+        Some(region) => region.to_owned(),
+    }
+}
+
+// Requests a temporary copy of an archived object, or of one of its versions,
+// be restored so it can be read
+pub fn restore_bucket_object(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
+    version_id: Option<&str>,
+    days: i64,
+    tier: &str,
+) -> Result<(), Box<dyn Error>> {
+    let req = RestoreObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        version_id: version_id.map(str::to_owned),
+        restore_request: Some(RestoreRequest {
+            days: Some(days),
+            glacier_job_parameters: Some(GlacierJobParameters {
+                tier: tier.to_owned(),
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    client
+        .restore_object(req)
+        .sync()
+        .map_err(|e| {
+            BearcatError::from_rusoto(format!("Error restoring {:?} in {:?}", key, bucket), e)
+        })?;
+    Ok(())
+}
+
 fn check_bucket_access(
     client: &rusoto_s3::S3Client,
     bucket_name: &str,
@@ -176,58 +1154,154 @@ fn check_bucket_access(
     client
         .head_bucket(req)
         .sync()
-        .map_err(|e| format! {"error accessing the bucket {:?}", e})?;
+        .map_err(|e| {
+            BearcatError::from_rusoto(format!("Error accessing the bucket {:?}", bucket_name), e)
+        })?;
     Ok(true)
 }
 
-pub fn get_bucket_object_keys(
+// Lists every object in the bucket under the prefix, following continuation tokens
+pub fn list_bucket_objects(
     client: &rusoto_s3::S3Client,
     bucket_name: &str,
-) -> Result<Vec<String>, ()> {
-    let req = ListObjectsV2Request {
-        bucket: bucket_name.to_owned(),
-        ..Default::default()
-    };
-    info!(target: "BUCKET GET OBJECT", "getting objects from bucket: {:?}", &bucket_name);
-    match client.list_objects_v2(req).sync() {
-        Ok(result) => {
-            let mut key_list: Vec<String> = Vec::new();
-            match result.contents {
-                Some(objects) => {
-                    for object in objects {
-                        // if the object is isn't None unwrap and add to the list
-                        if let Some(e) = &object.key {
-                            if !e.ends_with('/') {
-                                key_list.push(e.to_string());
-                            }
-                        }
-                    }
-                    println!("Key List:\n{:?}", &key_list);
-                    Ok(key_list)
-                }
-                None => Err(()),
-            }
+    prefix: Option<&str>,
+) -> Result<Vec<Object>, Box<dyn Error>> {
+    let mut objects = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let req = ListObjectsV2Request {
+            bucket: bucket_name.to_owned(),
+            prefix: prefix.map(str::to_owned),
+            continuation_token: continuation_token.take(),
+            ..Default::default()
+        };
+        let res = client
+            .list_objects_v2(req)
+            .sync()
+            .map_err(|e| {
+                BearcatError::from_rusoto(format!("Error listing objects in bucket {:?}", bucket_name), e)
+            })?;
+        if let Some(contents) = res.contents {
+            objects.extend(contents);
         }
-        Err(e) => {
-            // Only want to log the error
-            println!("Error: \n{:?}", e);
-            Err(())
+        match res.next_continuation_token {
+            Some(token) if res.is_truncated == Some(true) => continuation_token = Some(token),
+            _ => break,
         }
     }
+    Ok(objects)
+}
+
+// Lists the objects in the bucket, leaving out "directory" placeholder keys
+pub fn get_bucket_objects(
+    client: &rusoto_s3::S3Client,
+    bucket_name: &str,
+) -> Result<Vec<Object>, Box<dyn Error>> {
+    info!(target: "BUCKET GET OBJECT", "getting objects from bucket: {:?}", &bucket_name);
+    let objects: Vec<Object> = list_bucket_objects(client, bucket_name, None)?
+        .into_iter()
+        .filter(|o| o.key.as_ref().map_or(false, |k| !k.ends_with('/')))
+        .collect();
+    Ok(objects)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::checksum::sha256_hex;
+    use crate::data::create_data_file;
+    use bytes::Bytes;
+    use crate::endpoint::EndpointConfig;
+    use rusoto_credential::ChainProvider;
+    use rusoto_s3::CreateBucketRequest;
+    use std::env;
 
     #[test]
     fn create_upload_part_test() {
-        let part = create_upload_part("bearcat-test", "resources/test.txt", "34", vec![1, 2, 3], 1);
+        let digest = md5::compute(&[1, 2, 3]);
+        let part = create_upload_part(
+            "bearcat-test",
+            "resources/test.txt",
+            "34",
+            vec![1, 2, 3],
+            1,
+            &digest,
+        );
         assert_eq!(part.bucket, "bearcat-test");
         assert_eq!(part.content_length, None);
         assert_eq!(part.part_number, 1);
         assert_eq!(part.upload_id, "34");
         assert_eq!(part.key, "resources/test.txt");
         assert_eq!(part.body.is_some(), true);
+        assert_eq!(part.content_md5, Some(content_md5(&digest)));
+    }
+
+    #[test]
+    fn location_region_test() {
+        assert_eq!(location_region(None), "us-east-1");
+        assert_eq!(location_region(Some("")), "us-east-1");
+        assert_eq!(location_region(Some("EU")), "eu-west-1");
+        assert_eq!(location_region(Some("us-gov-west-1")), "us-gov-west-1");
+    }
+
+    #[test]
+    fn copy_source_test() {
+        assert_eq!(
+            copy_source("bearcat-test", "go/go1.12.5 linux+amd64.tar.gz", None),
+            "bearcat-test/go/go1.12.5%20linux%2Bamd64.tar.gz"
+        );
+        assert_eq!(
+            copy_source("bearcat-test", "café.txt", Some("3HL4kqtJlcpXroDTDmJ")),
+            "bearcat-test/caf%C3%A9.txt?versionId=3HL4kqtJlcpXroDTDmJ"
+        );
+    }
+
+    // Runs against a local S3 compatible store, for example MinIO:
+    //   docker run -p 9000:9000 minio/minio server /data
+    //   AWS_ACCESS_KEY_ID=minioadmin AWS_SECRET_ACCESS_KEY=minioadmin cargo test -- --ignored
+    // BEARCAT_TEST_ENDPOINT overrides the default http://localhost:9000
+    #[test]
+    #[ignore]
+    fn s3_round_trip_test() -> Result<(), Box<dyn Error>> {
+        let endpoint = EndpointConfig {
+            url: Some(
+                env::var("BEARCAT_TEST_ENDPOINT").unwrap_or_else(|_| "http://localhost:9000".to_owned()),
+            ),
+            ..Default::default()
+        };
+        let client = endpoint.client(ChainProvider::new(), endpoint.region("us-east-1")?)?;
+        let bucket = "bearcat-test";
+        // The bucket is left over from an earlier run when this fails
+        let _ = client
+            .create_bucket(CreateBucketRequest {
+                bucket: bucket.to_owned(),
+                ..Default::default()
+            })
+            .sync();
+
+        let key = "round-trip/test.txt";
+        let content = Bytes::from(&b"bearcat round trip"[..]);
+        let job = JobConfig::default();
+        create_data_file(&job.staging.path, key, &content, &job.staging)?;
+        let source = ObjectDetails {
+            key: key.to_owned(),
+            e_tag: None,
+            version_id: None,
+            sha256: sha256_hex(&content),
+            storage_class: None,
+            headers: ObjectHeaders {
+                content_type: Some("text/plain".to_owned()),
+                ..Default::default()
+            },
+            blocked: None,
+        };
+        assert_eq!(upload(&client, &job.staging.path, key, bucket, key, &source, &job)?, true);
+
+        let details = download(&client, key, bucket, &job)?;
+        assert_eq!(details.sha256, source.sha256);
+        assert_eq!(details.headers.content_type, Some("text/plain".to_owned()));
+        assert_eq!(details.headers.metadata.get(SHA256_METADATA_KEY), Some(&source.sha256));
+        delete_data_file(&job.staging.path, key, &job.staging)?;
+        Ok(())
     }
 }
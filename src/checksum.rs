@@ -0,0 +1,220 @@
+use crate::envelope::{
+    PLAINTEXT_SHA256_METADATA_KEY, SEALED_SHA256_METADATA_KEY, WRAPPED_KEY_METADATA_KEY,
+};
+use crate::etag::{normalize_etag, reader_matches_etag, reader_md5, ETagMatch};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+// User metadata key holding the SHA-256 of the whole object, the plaintext
+// for client side encrypted objects
+pub const SHA256_METADATA_KEY: &str = "bearcat-sha256";
+// User metadata key holding the ETag the object had in the source bucket
+pub const SOURCE_ETAG_METADATA_KEY: &str = "bearcat-source-etag";
+// User metadata key holding the version id the object had in the source bucket
+pub const SOURCE_VERSION_METADATA_KEY: &str = "bearcat-source-version-id";
+
+// Base64 encoded md5 digest, the format S3 expects in the Content-MD5 header
+pub fn content_md5(digest: &md5::Digest) -> String {
+    base64::encode(&digest.0)
+}
+
+#[cfg(test)]
+pub fn sha256_hex(content: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(content);
+    format!("{:x}", hasher.result())
+}
+
+// Hashes the file in fixed size chunks so large files don't have to be read into memory
+pub fn sha256_file(path: &str) -> io::Result<String> {
+    sha256_reader(File::open(path)?)
+}
+
+pub fn sha256_reader<R: Read>(mut file: R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 65536];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.input(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.result()))
+}
+
+// Records the SHA-256 of the bytes being uploaded. Those are the sealed bytes
+// of client side encrypted objects, which get their own key so the main one
+// keeps describing the plaintext the allowlists and audits know about.
+pub fn record_sha256(metadata: &mut HashMap<String, String>, sha256: &str) {
+    if !metadata.contains_key(WRAPPED_KEY_METADATA_KEY) {
+        metadata.insert(SHA256_METADATA_KEY.to_owned(), sha256.to_owned());
+        return;
+    }
+    metadata.insert(SEALED_SHA256_METADATA_KEY.to_owned(), sha256.to_owned());
+    match metadata.get(PLAINTEXT_SHA256_METADATA_KEY).cloned() {
+        Some(plaintext) => metadata.insert(SHA256_METADATA_KEY.to_owned(), plaintext),
+        None => metadata.remove(SHA256_METADATA_KEY),
+    };
+}
+
+// The SHA-256 of an object's content. Objects sealed before the sealed bytes
+// had their own key only describe the plaintext in the envelope.
+pub fn content_sha256(metadata: &HashMap<String, String>) -> Option<&String> {
+    metadata
+        .get(PLAINTEXT_SHA256_METADATA_KEY)
+        .or_else(|| metadata.get(SHA256_METADATA_KEY))
+}
+
+// The SHA-256 of the bytes S3 stores for an object
+pub fn stored_sha256(metadata: &HashMap<String, String>) -> Option<&String> {
+    if metadata.contains_key(WRAPPED_KEY_METADATA_KEY) {
+        metadata.get(SEALED_SHA256_METADATA_KEY)
+    } else {
+        metadata.get(SHA256_METADATA_KEY)
+    }
+}
+
+// Checks the downloaded content, read from `open`, against the digest the
+// source advertises. A SHA-256 stored by a previous bearcat transfer takes
+// precedence, otherwise the content is compared with the ETag. Multipart ETags
+// whose part size can't be inferred, and ETags that aren't md5 based, are
+// left unverified.
+pub fn verify_source_digest<R, F>(
+    open: F,
+    size: u64,
+    sha256: &str,
+    e_tag: Option<&str>,
+    metadata: Option<&HashMap<String, String>>,
+) -> Result<bool, String>
+where
+    R: Read,
+    F: Fn() -> io::Result<R>,
+{
+    if let Some(expected) = metadata.and_then(stored_sha256) {
+        if expected != sha256 {
+            return Err(format!(
+                "SHA-256 mismatch: source metadata has {} but the content hashed to {}",
+                expected, sha256
+            ));
+        }
+        return Ok(true);
+    }
+    let e_tag = match e_tag {
+        Some(e_tag) => e_tag,
+        None => return Ok(false),
+    };
+    let unreadable = |e: io::Error| format!("Could not read the content back: {}", e);
+    match reader_matches_etag(&open, size, e_tag).map_err(unreadable)? {
+        ETagMatch::Match => Ok(true),
+        ETagMatch::Mismatch => Err(format!(
+            "ETag mismatch: source has {} but the content hashed to {}",
+            normalize_etag(e_tag),
+            reader_md5(open().map_err(unreadable)?).map_err(unreadable)?
+        )),
+        ETagMatch::Unknown => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: &[u8] = b"bearcat";
+    const CONTENT_MD5: &str = "974e55bc9765d6a86393b0b094a969ea";
+
+    fn verify(
+        content: &[u8],
+        sha256: &str,
+        e_tag: Option<&str>,
+        metadata: Option<&HashMap<String, String>>,
+    ) -> Result<bool, String> {
+        verify_source_digest(|| Ok(content), content.len() as u64, sha256, e_tag, metadata)
+    }
+
+    #[test]
+    fn content_md5_test() {
+        let digest = md5::compute(b"");
+        assert_eq!(content_md5(&digest), "1B2M2Y8AsgTpgAmY7PhCfg==");
+    }
+
+    #[test]
+    fn sha256_hex_test() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn verify_source_digest_etag_test() {
+        let sha256 = sha256_hex(CONTENT);
+        let e_tag = format!("\"{}\"", CONTENT_MD5);
+        assert_eq!(
+            verify(CONTENT, &sha256, Some(&e_tag), None),
+            Ok(true)
+        );
+        assert_eq!(
+            verify(b"tampered", &sha256_hex(b"tampered"), Some(&e_tag), None)
+                .is_err(),
+            true
+        );
+    }
+
+    #[test]
+    fn verify_source_digest_metadata_test() {
+        let sha256 = sha256_hex(CONTENT);
+        let mut metadata = HashMap::new();
+        metadata.insert(SHA256_METADATA_KEY.to_owned(), sha256.clone());
+        assert_eq!(
+            verify(CONTENT, &sha256, Some(CONTENT_MD5), Some(&metadata)),
+            Ok(true)
+        );
+        metadata.insert(SHA256_METADATA_KEY.to_owned(), sha256_hex(b"other"));
+        assert_eq!(
+            verify(CONTENT, &sha256, None, Some(&metadata)).is_err(),
+            true
+        );
+    }
+
+    #[test]
+    fn sealed_sha256_test() {
+        let plaintext = sha256_hex(b"plaintext");
+        let sealed = sha256_hex(CONTENT);
+        let mut metadata = HashMap::new();
+        record_sha256(&mut metadata, &plaintext);
+        assert_eq!(metadata.get(SHA256_METADATA_KEY), Some(&plaintext));
+        assert_eq!(stored_sha256(&metadata), Some(&plaintext));
+
+        metadata.insert(WRAPPED_KEY_METADATA_KEY.to_owned(), "wrapped".to_owned());
+        metadata.insert(PLAINTEXT_SHA256_METADATA_KEY.to_owned(), plaintext.clone());
+        record_sha256(&mut metadata, &sealed);
+        assert_eq!(metadata.get(SHA256_METADATA_KEY), Some(&plaintext));
+        assert_eq!(content_sha256(&metadata), Some(&plaintext));
+        assert_eq!(stored_sha256(&metadata), Some(&sealed));
+        // The sealed bytes are what a download is checked against
+        assert_eq!(
+            verify(CONTENT, &sealed, None, Some(&metadata)),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn verify_source_digest_multipart_test() {
+        let sha256 = sha256_hex(CONTENT);
+        assert_eq!(
+            verify(CONTENT, &sha256, Some("\"e0b805b5969df6c2c7dff6038d960d52-8\""), None),
+            Ok(false)
+        );
+        assert_eq!(verify(CONTENT, &sha256, None, None), Ok(false));
+        // A multipart ETag of the content itself can be verified
+        let e_tag = crate::etag::content_etag(CONTENT, 4);
+        assert_eq!(
+            verify(CONTENT, &sha256, Some(&e_tag), None),
+            Ok(true)
+        );
+    }
+}
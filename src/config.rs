@@ -0,0 +1,189 @@
+use crate::archive::ArchivePolicy;
+use crate::data::StagingConfig;
+use crate::encryption::EncryptionConfig;
+use crate::endpoint::EndpointsConfig;
+use crate::envelope::ClientEncryption;
+use crate::error::FailurePolicy;
+use crate::filetype::FileTypeConfig;
+use crate::manifest::ManifestConfig;
+use crate::metadata::HeaderPolicy;
+use crate::partition::PartitionPolicy;
+use crate::quarantine::QuarantineConfig;
+use crate::release::ReleasePolicy;
+use crate::scan::{Scanner, ScannerConfig};
+use crate::signature::SignaturePolicy;
+use crate::storage::{RestoreConfig, StorageClassConfig};
+use crate::transfer::TransferConfig;
+use crate::versions::VersionConfig;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use yaml_rust::{Yaml, YamlLoader};
+use log::info;
+
+// Settings for a sync job, read from the bearcat config file
+#[derive(Debug, Default)]
+pub struct JobConfig {
+    pub headers: HeaderPolicy,
+    pub encryption: EncryptionConfig,
+    pub storage_class: StorageClassConfig,
+    pub restore: RestoreConfig,
+    pub versions: VersionConfig,
+    pub transfer: TransferConfig,
+    pub endpoints: EndpointsConfig,
+    pub partitions: PartitionPolicy,
+    pub release: ReleasePolicy,
+    pub quarantine: QuarantineConfig,
+    pub scanner: ScannerConfig,
+    pub file_types: FileTypeConfig,
+    pub archives: ArchivePolicy,
+    pub manifest: ManifestConfig,
+    pub signatures: SignaturePolicy,
+    pub client_encryption: ClientEncryption,
+    pub staging: StagingConfig,
+    pub failures: FailurePolicy,
+}
+
+impl JobConfig {
+    pub fn from_yaml(doc: &Yaml) -> Result<JobConfig, String> {
+        let job = JobConfig {
+            headers: HeaderPolicy::from_yaml(&doc["headers"], &doc["tags"])?,
+            encryption: EncryptionConfig::from_yaml(&doc["encryption"])?,
+            storage_class: StorageClassConfig::from_yaml(&doc["storage_class"])?,
+            restore: RestoreConfig::from_yaml(&doc["restore"])?,
+            versions: VersionConfig::from_yaml(&doc["versions"])?,
+            transfer: TransferConfig::from_yaml(&doc["transfer"])?,
+            endpoints: EndpointsConfig::from_yaml(&doc["endpoints"])?,
+            partitions: PartitionPolicy::from_yaml(&doc["partitions"])?,
+            release: ReleasePolicy::from_yaml(&doc["release"])?,
+            quarantine: QuarantineConfig::from_yaml(&doc["quarantine"])?,
+            scanner: ScannerConfig::from_yaml(&doc["scanner"])?,
+            file_types: FileTypeConfig::from_yaml(&doc["file_types"])?,
+            archives: ArchivePolicy::from_yaml(&doc["archives"])?,
+            manifest: ManifestConfig::from_yaml(&doc["manifest"])?,
+            signatures: SignaturePolicy::from_yaml(&doc["signatures"])?,
+            client_encryption: ClientEncryption::from_yaml(&doc["client_encryption"])?,
+            staging: StagingConfig::from_yaml(&doc["staging"])?,
+            failures: FailurePolicy::from_yaml(&doc["failures"])?,
+        };
+        // Scanner commands read the staged file themselves, so it would have
+        // to be on disk in the clear
+        if let (true, Some(Scanner::Command(_))) = (job.staging.encrypts(), &job.scanner.scanner) {
+            return Err("staging.encrypt needs the clamd scanner, not a command".to_owned());
+        }
+        Ok(job)
+    }
+}
+
+pub fn parse_config(content: &str) -> Result<JobConfig, Box<dyn Error>> {
+    let docs = YamlLoader::load_from_str(content)?;
+    match docs.first() {
+        Some(doc) => Ok(JobConfig::from_yaml(doc)?),
+        None => Ok(JobConfig::default()),
+    }
+}
+
+// Loads the config file given on the command line, falling back to ~/.bearcat
+// when it exists and to the defaults when it doesn't
+pub fn load_config(path: Option<&str>) -> Result<JobConfig, Box<dyn Error>> {
+    let path = match path {
+        Some(path) => path.to_owned(),
+        None => {
+            let default = format!("{}/.bearcat", env::var("HOME").unwrap_or_default());
+            if !Path::new(&default).is_file() {
+                info!(target: "ARGUMENTS", "No config file found. Using the defaults");
+                return Ok(JobConfig::default());
+            }
+            default
+        }
+    };
+    info!(target: "ARGUMENTS", "Using the config file: {}", path);
+    let content = fs::read_to_string(&path)
+        .map_err(|e| format!("Could not read the config file {:?}: {}", path, e))?;
+    parse_config(&content)
+}
+
+// A missing entry is an empty list
+pub fn yaml_string_list(yaml: &Yaml, name: &str) -> Result<Vec<String>, String> {
+    match yaml {
+        Yaml::BadValue | Yaml::Null => Ok(Vec::new()),
+        Yaml::Array(items) => items
+            .iter()
+            .map(|i| yaml_scalar(i).ok_or_else(|| format!("{} must be a list of strings", name)))
+            .collect(),
+        _ => Err(format!("{} must be a list", name)),
+    }
+}
+
+// A missing entry is an empty map. Entries keep the order of the config file.
+pub fn yaml_string_map(yaml: &Yaml, name: &str) -> Result<Vec<(String, String)>, String> {
+    match yaml {
+        Yaml::BadValue | Yaml::Null => Ok(Vec::new()),
+        Yaml::Hash(hash) => hash
+            .iter()
+            .map(|(k, v)| match (yaml_scalar(k), yaml_scalar(v)) {
+                (Some(k), Some(v)) => Ok((k, v)),
+                _ => Err(format!("{} must map strings to strings", name)),
+            })
+            .collect(),
+        _ => Err(format!("{} must be a map", name)),
+    }
+}
+
+// Scalars are read as strings so `max-age=60` and `60` both work as values
+pub fn yaml_scalar(yaml: &Yaml) -> Option<String> {
+    match yaml {
+        Yaml::String(s) => Some(s.to_owned()),
+        Yaml::Integer(i) => Some(i.to_string()),
+        Yaml::Real(r) => Some(r.to_owned()),
+        Yaml::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config_test() {
+        let job = parse_config("headers:\n  strip: [cache-control]\n").unwrap();
+        assert_eq!(job.headers.strip_headers, vec!["cache-control".to_owned()]);
+    }
+
+    #[test]
+    fn parse_empty_config_test() {
+        let job = parse_config("").unwrap();
+        assert_eq!(job.headers, HeaderPolicy::default());
+    }
+
+    #[test]
+    fn parse_invalid_config_test() {
+        assert_eq!(parse_config("headers: [").is_err(), true);
+        assert_eq!(parse_config("headers:\n  strip: 3\n").is_err(), true);
+        assert_eq!(
+            parse_config("staging:\n  encrypt: true\nscanner:\n  command: [clamscan]\n").is_err(),
+            true
+        );
+    }
+
+    #[test]
+    fn load_missing_config_test() {
+        assert_eq!(load_config(Some("file_tests/no_such_config")).is_err(), true);
+    }
+
+    #[test]
+    fn yaml_values_test() {
+        let doc = &YamlLoader::load_from_str("list: [a, 1, true]\nmap:\n  b: 2.5\n").unwrap()[0];
+        assert_eq!(
+            yaml_string_list(&doc["list"], "list").unwrap(),
+            vec!["a".to_owned(), "1".to_owned(), "true".to_owned()]
+        );
+        assert_eq!(
+            yaml_string_map(&doc["map"], "map").unwrap(),
+            vec![("b".to_owned(), "2.5".to_owned())]
+        );
+        assert_eq!(yaml_string_list(&doc["missing"], "missing").unwrap().is_empty(), true);
+    }
+}
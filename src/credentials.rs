@@ -1,14 +1,16 @@
+use crate::error::BearcatError;
 use regex::{Regex, Captures};
-use rusoto_core::{HttpClient, Region, RusotoError};
+use rusoto_core::{HttpClient, Region};
 use rusoto_credential::{AwsCredentials, ProvideAwsCredentials};
-use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
+use rusoto_sts::{
+    GetCallerIdentityRequest, Sts, StsClient,
+};
 use rusoto_ssm::*;
-use std::collections::HashMap;
 use std::io;
 use std::io::{ErrorKind};
 use std::error::Error;
 use rusoto_credential::ChainProvider;
-use log::{info, warn};
+use log::info;
 
 pub enum CredentialProvider {
     AwsSsm { key: String, region: Region },
@@ -20,27 +22,46 @@ impl CredentialProvider {
     pub fn get_credentials(self) -> Result<AwsCredentials, Box<dyn Error>> {
         match self {
             Self::AwsSsm { key, region } => get_creds_from_ssm(key, region),
-            Self::AwsSts => unimplemented!("Need to add sts integration"),
-            Self::Vault => unimplemented!("Need to add Vault integration"),
+            Self::AwsSts => Err(BearcatError::Config("Credentials from STS aren't supported yet".to_owned()).into()),
+            Self::Vault => Err(BearcatError::Config("Credentials from Vault aren't supported yet".to_owned()).into()),
         }
     }
 }
 
 fn get_creds_from_ssm(key: String, region: Region) -> Result<AwsCredentials, Box<dyn Error>> {
-    let client =
-        rusoto_ssm::SsmClient::new_with(HttpClient::new().unwrap(), ChainProvider::new(), region);
+    let client = rusoto_ssm::SsmClient::new_with(HttpClient::new()?, ChainProvider::new(), region);
     let req = GetParameterRequest {
         name: key,
         with_decryption: Some(true), // forcing always on for now
     };
     info!(target: "Credential Provider Events", "Request:\n{:?}", &req);
-    let res = client.get_parameter(req).sync()?;
-    // need to get rid of these unwraps
-    let v = res.parameter.unwrap().value.unwrap();
+    let res = client
+        .get_parameter(req)
+        .sync()
+        .map_err(|e| BearcatError::from_rusoto("Could not get the credentials from SSM", e))?;
+    let v = res
+        .parameter
+        .and_then(|p| p.value)
+        .ok_or("The ssm parameter has no value")?;
     let cap = parse_ssm_value(&v)?;
     parse_cred_from_ssm_value(cap)
 }
 
+// ARN of the identity behind the credentials, as reported by STS in the region
+pub fn caller_identity_arn<P>(provider: P, region: Region) -> Result<String, Box<dyn Error>>
+where
+    P: ProvideAwsCredentials + Send + Sync + 'static,
+    P::Future: Send,
+{
+    let client = StsClient::new_with(HttpClient::new()?, provider, region);
+    let res = client
+        .get_caller_identity(GetCallerIdentityRequest {})
+        .sync()
+        .map_err(|e| BearcatError::from_rusoto("Could not get the caller identity", e))?;
+    res.arn
+        .ok_or_else(|| "STS did not return the caller identity ARN".into())
+}
+
 // This assumes the key is stored in the format "aws_id::aws_key"
 // Regex pattern (?x)(?P<id>.+)::(?P<secret>.+)
 fn parse_cred_from_ssm_value(cap: Captures) -> Result<AwsCredentials, Box<dyn Error>> {
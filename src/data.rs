@@ -1,58 +1,506 @@
-use std::fs::File;
+use crate::envelope::{sealed_length, OpeningReader, SealingWriter, NONCE_PREFIX_SIZE};
+use std::fs::{File, OpenOptions};
 use std::fs;
 use std::error::Error;
+use std::io;
 use std::io::prelude::*;
-use std::io::BufReader;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 use bytes::Bytes;
+use futures::{Future, Stream};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use yaml_rust::Yaml;
 use log::{info, warn};
 
 struct DataFile {}
 
-pub fn delete_data_file(base: &str, key: &str) -> Result<bool, Box<dyn Error>> {
-    // Duplicate code that will get removed after I go over this again
-    let object = format!("{}/{}", base, key).to_string();
-    let object_path = Path::new(&object);
+// Size of the blocks of zeros staged files are overwritten with
+const WIPE_BLOCK_SIZE: usize = 65536;
+
+// How often a download waiting for room in the staging directory looks again
+#[cfg(not(test))]
+const QUOTA_POLL_INTERVAL: Duration = Duration::from_secs(5);
+#[cfg(test)]
+const QUOTA_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Staged files can be read in order or jumped around in, like a zip's directory
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+// How files are kept in the staging directory. Encrypted staged files start
+// with a random nonce prefix followed by the sealed chunks.
+#[derive(Clone)]
+pub struct StagingConfig {
+    // Made for this run and only held in memory, so files left behind by a
+    // crash can't be read
+    key: Option<Vec<u8>>,
+    // Overwrite staged files before they are unlinked
+    pub wipe: bool,
+    // Directory objects are staged in, `data` by default
+    pub path: String,
+    // Most bytes kept in the staging directory at once, counting files
+    // staged by other runs sharing it
+    pub max_bytes: Option<u64>,
+    // How long a download waits for room under max_bytes
+    pub wait: Duration,
+}
+
+impl Default for StagingConfig {
+    fn default() -> StagingConfig {
+        StagingConfig {
+            key: None,
+            wipe: false,
+            path: "data".to_owned(),
+            max_bytes: None,
+            wait: Duration::from_secs(600),
+        }
+    }
+}
+
+// Keeps the key material out of the logs
+impl std::fmt::Debug for StagingConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "StagingConfig {{ encrypt: {}, wipe: {}, path: {:?}, max_bytes: {:?}, wait: {:?} }}",
+            self.encrypts(),
+            self.wipe,
+            self.path,
+            self.max_bytes,
+            self.wait
+        )
+    }
+}
+
+impl StagingConfig {
+    // Reads the `staging` section of the job config:
+    //
+    //   staging:
+    //     path: /var/spool/bearcat
+    //     max_bytes: 10737418240
+    //     wait: 600
+    //     encrypt: true
+    //     wipe: true
+    pub fn from_yaml(yaml: &Yaml) -> Result<StagingConfig, String> {
+        let defaults = StagingConfig::default();
+        let flag = |name: &str| match &yaml[name] {
+            Yaml::Boolean(b) => Ok(*b),
+            Yaml::BadValue => Ok(false),
+            _ => Err(format!("staging.{} must be true or false", name)),
+        };
+        let path = match &yaml["path"] {
+            Yaml::String(path) if !path.is_empty() => path.trim_end_matches('/').to_owned(),
+            Yaml::BadValue => defaults.path,
+            _ => return Err("staging.path must be a directory".to_owned()),
+        };
+        let max_bytes = match &yaml["max_bytes"] {
+            Yaml::Integer(bytes) if *bytes > 0 => Some(*bytes as u64),
+            Yaml::BadValue => None,
+            _ => return Err("staging.max_bytes must be a positive number of bytes".to_owned()),
+        };
+        let wait = match &yaml["wait"] {
+            Yaml::Integer(seconds) if *seconds >= 0 => Duration::from_secs(*seconds as u64),
+            Yaml::BadValue => defaults.wait,
+            _ => return Err("staging.wait must be a number of seconds".to_owned()),
+        };
+        let key = if flag("encrypt")? {
+            let mut key = vec![0; 32];
+            rand::thread_rng().fill_bytes(&mut key);
+            Some(key)
+        } else {
+            None
+        };
+        Ok(StagingConfig {
+            key,
+            wipe: flag("wipe")?,
+            path,
+            max_bytes,
+            wait,
+        })
+    }
+
+    pub fn encrypts(&self) -> bool {
+        self.key.is_some()
+    }
+
+    // Bytes an object of `size` takes up once staged
+    pub fn staged_size(&self, size: u64) -> u64 {
+        match &self.key {
+            None => size,
+            Some(_) => NONCE_PREFIX_SIZE as u64 + sealed_length(size),
+        }
+    }
+
+    // Most bytes a working copy of an object of `size` takes up, a sealed
+    // copy of it staged as it is
+    fn working_copy_size(&self, size: u64) -> u64 {
+        self.staged_size(sealed_length(size))
+    }
+
+    // Checked before an object is downloaded, with the number of working
+    // copies of it the transfer keeps next to the staged file. Waits while
+    // staging it would go over max_bytes, so downloads pause until uploads
+    // free up room, then makes sure the filesystem has space for it.
+    pub fn reserve(&self, key: &str, size: u64, working_copies: u64) -> Result<(), String> {
+        let size = self.staged_size(size) + working_copies * self.working_copy_size(size);
+        fs::create_dir_all(&self.path)
+            .map_err(|e| format!("Could not create the staging directory {:?}: {}", self.path, e))?;
+        if let Some(max_bytes) = self.max_bytes {
+            if size > max_bytes {
+                return Err(format!(
+                    "{:?} needs {} bytes, more than staging.max_bytes allows",
+                    key, size
+                ));
+            }
+            // Working copies are in the directory too, so other transfers'
+            // copies count against the quota
+            let started = Instant::now();
+            loop {
+                let staged = directory_size(Path::new(&self.path))
+                    .map_err(|e| format!("Could not measure the staging directory {:?}: {}", self.path, e))?;
+                if staged + size <= max_bytes {
+                    break;
+                }
+                let waited = started.elapsed();
+                if waited >= self.wait {
+                    return Err(format!(
+                        "Gave up waiting {:?} for room to stage {:?}, it needs {} bytes and {} of {} are in use",
+                        self.wait, key, size, staged, max_bytes
+                    ));
+                }
+                info!(target: "STAGING", "{} of {} bytes staged, waiting for room to stage {:?}", staged, max_bytes, key);
+                thread::sleep(std::cmp::min(QUOTA_POLL_INTERVAL, self.wait - waited));
+            }
+        }
+        let available = available_space(&self.path)
+            .map_err(|e| format!("Could not check the free space in {:?}: {}", self.path, e))?;
+        if available < size {
+            return Err(format!(
+                "{:?} needs {} bytes, only {} are free in {:?}",
+                key, size, available, self.path
+            ));
+        }
+        Ok(())
+    }
+
+    // The plaintext of a staged file
+    pub fn open(&self, path: &str) -> io::Result<Box<dyn ReadSeek>> {
+        let mut file = File::open(path)?;
+        match &self.key {
+            None => Ok(Box::new(file)),
+            Some(key) => {
+                let mut nonce_prefix = vec![0; NONCE_PREFIX_SIZE];
+                file.read_exact(&mut nonce_prefix)?;
+                Ok(Box::new(OpeningReader::new(file, key, &nonce_prefix)?))
+            }
+        }
+    }
+
+    // A new staged file, `finish` has to be called once everything is written
+    pub fn create(&self, path: &str) -> io::Result<StagedWriter> {
+        let mut file = File::create(path)?;
+        match &self.key {
+            None => Ok(StagedWriter::Plain(file)),
+            Some(key) => {
+                let mut nonce_prefix = vec![0; NONCE_PREFIX_SIZE];
+                rand::thread_rng().fill_bytes(&mut nonce_prefix);
+                file.write_all(&nonce_prefix)?;
+                Ok(StagedWriter::Sealed(SealingWriter::new(file, key, &nonce_prefix)))
+            }
+        }
+    }
+}
+
+pub enum StagedWriter {
+    Plain(File),
+    Sealed(SealingWriter<File>),
+}
+
+impl StagedWriter {
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            StagedWriter::Plain(mut file) => file.flush(),
+            StagedWriter::Sealed(writer) => writer.finish().map(|_| ()),
+        }
+    }
+}
+
+impl Write for StagedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            StagedWriter::Plain(file) => file.write(buf),
+            StagedWriter::Sealed(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            StagedWriter::Plain(file) => file.flush(),
+            StagedWriter::Sealed(writer) => writer.flush(),
+        }
+    }
+}
+
+// Bytes in the files under the directory, one missing since it was checked
+// counts as empty
+fn directory_size(path: &Path) -> io::Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if metadata.is_dir() {
+            total += match directory_size(&entry.path()) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+                size => size?,
+            };
+        } else {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+// Bytes an unprivileged process can still write on the path's filesystem
+fn available_space(path: &str) -> io::Result<u64> {
+    let c_path = CString::new(Path::new(path).as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+// Overwrites the file with zeros and flushes it to disk before it is removed
+fn wipe_file(path: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let mut remaining = file.metadata()?.len();
+    let zeros = vec![0; WIPE_BLOCK_SIZE];
+    while remaining > 0 {
+        let length = std::cmp::min(remaining, WIPE_BLOCK_SIZE as u64) as usize;
+        file.write_all(&zeros[..length])?;
+        remaining -= length as u64;
+    }
+    file.sync_all()
+}
+
+// Longest piece of an encoded key kept in one path component, well under the
+// 255 byte name limit of most filesystems
+const MAX_COMPONENT_LENGTH: usize = 200;
+
+// Bytes kept as they are in staged file names, everything else is %XX encoded.
+// `~` is encoded too, so names with a `~` are free for working copies.
+fn is_plain(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'.' || byte == b'_'
+}
+
+fn encode_key(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| {
+            if is_plain(byte) {
+                (byte as char).to_string()
+            } else {
+                format!("%{:02X}", byte)
+            }
+        })
+        .collect()
+}
+
+// None for names encode_key can't make, like those of working copies
+fn decode_key(encoded: &str) -> Option<String> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = encoded.get(index + 1..index + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else if is_plain(bytes[index]) {
+            decoded.push(bytes[index]);
+            index += 1;
+        } else {
+            return None;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Where an S3 key is staged under `base`. The whole key, `/` included, is
+// encoded into one flat name, so `.`, `..` and a leading `/` are ordinary
+// characters and keys like `a` and `a/b` can't clash. Names too long for one
+// path component are split: the leading pieces become directories starting
+// with `+` and the file itself starts with `_`, so a file never has the name
+// of a directory.
+pub fn staged_path(base: &str, key: &str) -> String {
+    let encoded = encode_key(key);
+    let mut path = base.to_owned();
+    // Encoded keys are ASCII, so they can be split at any byte
+    let mut rest = encoded.as_str();
+    while rest.len() > MAX_COMPONENT_LENGTH {
+        path.push_str("/+");
+        path.push_str(&rest[..MAX_COMPONENT_LENGTH]);
+        rest = &rest[MAX_COMPONENT_LENGTH..];
+    }
+    path.push_str("/_");
+    path.push_str(rest);
+    path
+}
+
+// The S3 key staged at `path`, the reverse of staged_path. None when the path
+// isn't one staged_path makes.
+fn staged_key(base: &str, path: &str) -> Option<String> {
+    let prefix = format!("{}/", base);
+    if !path.starts_with(&prefix) {
+        return None;
+    }
+    let components: Vec<&str> = path[prefix.len()..].split('/').collect();
+    let (file, directories) = components.split_last()?;
+    if !file.starts_with('_') || !directories.iter().all(|d| d.starts_with('+')) {
+        return None;
+    }
+    let encoded: String = components.iter().map(|c| &c[1..]).collect();
+    decode_key(&encoded)
+}
+
+// Keys of the files staged under `base`, working copies and anything else
+// that isn't a staged file are skipped
+pub fn staged_keys(base: &str) -> io::Result<Vec<String>> {
+    fn walk(base: &str, directory: &Path, keys: &mut Vec<String>) -> io::Result<()> {
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                walk(base, &path, keys)?;
+            } else if let Some(key) = path.to_str().and_then(|path| staged_key(base, path)) {
+                keys.push(key);
+            }
+        }
+        Ok(())
+    }
+    let mut keys = Vec::new();
+    match walk(base, Path::new(base), &mut keys) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        walked => walked?,
+    }
+    keys.sort();
+    Ok(keys)
+}
+
+// A working copy of a staged file, like one being decrypted. Staged names
+// never contain `~`, so these can't be mistaken for a key.
+pub fn working_path(base: &str, key: &str, purpose: &str) -> String {
+    format!("{}~{}", staged_path(base, key), purpose)
+}
+
+// A working copy that is removed once it goes out of scope, however the
+// transfer using it ends
+pub struct WorkingCopy<'a> {
+    pub path: String,
+    staging: &'a StagingConfig,
+}
+
+impl<'a> WorkingCopy<'a> {
+    pub fn new(base: &str, key: &str, purpose: &str, staging: &'a StagingConfig) -> WorkingCopy<'a> {
+        WorkingCopy {
+            path: working_path(base, key, purpose),
+            staging,
+        }
+    }
+}
+
+impl<'a> Drop for WorkingCopy<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = remove_staged_file(&self.path, self.staging) {
+            warn!(target: "STAGING", "Could not remove working copy {:?}: {}", self.path, e);
+        }
+    }
+}
+
+// Removes a staged file or working copy, overwriting it first when the job wipes
+pub fn remove_staged_file(path: &str, staging: &StagingConfig) -> io::Result<()> {
+    let object_path = Path::new(path);
     if object_path.exists() {
+        if staging.wipe {
+            wipe_file(object_path)?;
+        }
         fs::remove_file(object_path)?;
-    };
-    info!(target: "DELETE", "Deleted data file {:?} successfully", object);
+    }
+    Ok(())
+}
+
+pub fn delete_data_file(base: &str, key: &str, staging: &StagingConfig) -> Result<bool, Box<dyn Error>> {
+    let object = staged_path(base, key);
+    remove_staged_file(&object, staging)?;
+    info!(target: "DELETE", "Deleted data file {:?} for {:?} successfully", object, key);
     Ok(true)
 }
 
+// Stages content that is already in memory, like the tests' fixtures
+#[cfg(test)]
 pub fn create_data_file(
     base: &str,
     key: &str,
     content: &bytes::Bytes,
+    staging: &StagingConfig,
 ) -> Result<bool, Box<dyn Error>> {
-    let object = format!("{}/{}", base, key).to_string();
-    let object_path = Path::new(&object);
-    if !object_path.exists() {
-        // Create a directory for the object
-        // Panics if you can't create the directory
-        let mut create_path = object_path.to_str().unwrap();
-        if !create_path.ends_with('/') {
-            create_path = object_path.parent().unwrap().to_str().unwrap();
-        }
-        fs::create_dir_all(create_path).expect(
-            format!(
-                "failed to create directory {:?} for download",
-                &object_path.as_os_str()
-            )
-            .as_str(),
-        );
-        if !Path::new(object_path).is_dir() {
-            let mut file =
-                File::create(&object_path).expect("unable to write the file for download");
-            file.write_all(&content)
-                .expect("failed to write body to the file");
-        }
-    }
+    stage_data_file(base, key, futures::stream::once(Ok(content.clone())), staging)?;
     // The path of the file created might be a better return type than bool?
-    info!(target: "CREATED", "Created data file {:?} successfully", object);
     Ok(true)
 }
 
+// Writes a download to the staged file for the key one chunk at a time as it
+// arrives, so objects never have to fit in memory. Returns the SHA-256 and
+// length of the content, a partly written file is removed.
+pub fn stage_data_file<S>(
+    base: &str,
+    key: &str,
+    body: S,
+    staging: &StagingConfig,
+) -> Result<(String, u64), Box<dyn Error>>
+where
+    S: Stream<Item = Bytes, Error = io::Error>,
+{
+    let object = staged_path(base, key);
+    let object_path = Path::new(&object);
+    // Anything already staged for the key is replaced, it may be from an
+    // earlier run or encrypted with a key that is gone
+    let create_path = object_path.parent().unwrap_or_else(|| Path::new(base));
+    fs::create_dir_all(create_path).map_err(|e| {
+        io::Error::new(e.kind(), format!("failed to create directory {:?} for download: {}", create_path, e))
+    })?;
+    let write = || -> io::Result<(String, u64)> {
+        let file = staging.create(&object)?;
+        let (file, hasher, length) = body
+            .fold((file, Sha256::new(), 0), |(mut file, mut hasher, length), chunk| {
+                file.write_all(&chunk)?;
+                hasher.input(&chunk);
+                Ok::<_, io::Error>((file, hasher, length + chunk.len() as u64))
+            })
+            .wait()?;
+        file.finish()?;
+        Ok((format!("{:x}", hasher.result()), length))
+    };
+    let staged = match write() {
+        Ok(staged) => staged,
+        Err(e) => {
+            remove_staged_file(&object, staging)?;
+            return Err(io::Error::new(e.kind(), format!("failed to write {:?} for download: {}", object, e)).into());
+        }
+    };
+    info!(target: "CREATED", "Created data file {:?} for {:?} successfully", object, key);
+    Ok(staged)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -92,8 +540,8 @@ mod tests {
     fn create_file_normal_names_test() {
         let _file = bytes::Bytes::from(FILE_CONTENT);
         for (_, _object) in FILE_OBJECTS.iter().enumerate() {
-            let _file_path = format!("{}/{}", TEST_DIR, _object).to_string();
-            let res = create_data_file(TEST_DIR, _object, &_file);
+            let _file_path = staged_path(TEST_DIR, _object);
+            let res = create_data_file(TEST_DIR, _object, &_file, &StagingConfig::default());
             assert_eq!(res.unwrap(), true);
             assert_eq!(Path::new(&_file_path).exists(), true);
         }
@@ -103,8 +551,8 @@ mod tests {
         let _file = bytes::Bytes::from(FILE_CONTENT);
 
         for (_, _object) in FILE_OBJECTS_ABNORMAL.iter().enumerate() {
-            let _file_path = format!("{}/{}", TEST_DIR, _object).to_string();
-            let res = create_data_file(TEST_DIR, _object, &_file);
+            let _file_path = staged_path(TEST_DIR, _object);
+            let res = create_data_file(TEST_DIR, _object, &_file, &StagingConfig::default());
             assert_eq!(res.unwrap(), true);
             assert_eq!(Path::new(&_file_path).exists(), true);
         }
@@ -114,10 +562,188 @@ mod tests {
         let _file = bytes::Bytes::from(FILE_CONTENT);
 
         for (_, _object) in FILE_OBJECTS_DIRECTORIES.iter().enumerate() {
-            let _file_path = format!("{}/{}", TEST_DIR, _object).to_string();
-            let res = create_data_file(TEST_DIR, _object, &_file);
+            let _file_path = staged_path(TEST_DIR, _object);
+            let res = create_data_file(TEST_DIR, _object, &_file, &StagingConfig::default());
             assert_eq!(res.unwrap(), true);
             assert_eq!(Path::new(&_file_path).exists(), true);
         }
     }
+
+    #[test]
+    fn encrypted_staging_test() {
+        let doc = &yaml_rust::YamlLoader::load_from_str("staging:\n  encrypt: true\n  wipe: true\n").unwrap()[0];
+        let staging = StagingConfig::from_yaml(&doc["staging"]).unwrap();
+        assert_eq!(
+            format!("{:?}", staging),
+            "StagingConfig { encrypt: true, wipe: true, path: \"data\", max_bytes: None, wait: 600s }"
+        );
+        let content = bytes::Bytes::from(FILE_CONTENT);
+        create_data_file(TEST_DIR, "sealed/staged", &content, &StagingConfig::default()).unwrap();
+        // A plaintext file left behind is replaced
+        create_data_file(TEST_DIR, "sealed/staged", &content, &staging).unwrap();
+        let path = staged_path(TEST_DIR, "sealed/staged");
+        let on_disk = fs::read(&path).unwrap();
+        assert_eq!(on_disk.windows(8).any(|w| FILE_CONTENT.as_bytes().starts_with(w)), false);
+
+        let mut plaintext = String::new();
+        staging.open(&path).unwrap().read_to_string(&mut plaintext).unwrap();
+        assert_eq!(plaintext, FILE_CONTENT);
+        // Another run's key can't open it
+        let other = StagingConfig::from_yaml(&doc["staging"]).unwrap();
+        assert_eq!(other.open(&path).and_then(|mut f| f.read_to_end(&mut Vec::new())).is_err(), true);
+
+        delete_data_file(TEST_DIR, "sealed/staged", &staging).unwrap();
+        assert_eq!(Path::new(&path).exists(), false);
+    }
+
+    #[test]
+    fn stage_data_file_test() {
+        let chunks = vec![Bytes::from("I prematurely "), Bytes::from("shot my wad")];
+        let staging = StagingConfig { key: Some(vec![1; 32]), ..StagingConfig::default() };
+        let (sha256, length) =
+            stage_data_file(TEST_DIR, "streamed", futures::stream::iter_ok(chunks), &staging).unwrap();
+        assert_eq!(sha256, crate::checksum::sha256_hex(b"I prematurely shot my wad"));
+        assert_eq!(length, 25);
+        let path = staged_path(TEST_DIR, "streamed");
+        let mut plaintext = String::new();
+        staging.open(&path).unwrap().read_to_string(&mut plaintext).unwrap();
+        assert_eq!(plaintext, "I prematurely shot my wad");
+
+        // A download that breaks off leaves nothing staged
+        let broken = futures::stream::iter_result(vec![
+            Ok(Bytes::from("partial")),
+            Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset")),
+        ]);
+        assert_eq!(stage_data_file(TEST_DIR, "streamed", broken, &staging).is_err(), true);
+        assert_eq!(Path::new(&path).exists(), false);
+    }
+
+    #[test]
+    fn staged_path_test() {
+        assert_eq!(staged_path("data", "go/go.tar.gz"), "data/_go%2Fgo.tar.gz");
+        assert_eq!(staged_path("data", "../../etc/cron.d/x"), "data/_..%2F..%2Fetc%2Fcron.d%2Fx");
+        assert_eq!(staged_path("data", "/etc/passwd"), "data/_%2Fetc%2Fpasswd");
+        assert_eq!(staged_path("data", ".."), "data/_..");
+        assert_eq!(staged_path("data", "b~c"), "data/_b%7Ec");
+        assert_eq!(staged_path("data", "caf\u{e9} \u{1f43b}"), "data/_caf%C3%A9%20%F0%9F%90%BB");
+        let long = "x".repeat(MAX_COMPONENT_LENGTH * 2 + 1);
+        let path = staged_path("data", &long);
+        assert_eq!(path.split('/').all(|c| c.len() <= MAX_COMPONENT_LENGTH + 1), true);
+        assert_eq!(path.split('/').count(), 4);
+
+        for key in &["", "a", "a/", "/a", "..", "go/../../x", "\u{1f43b}/%41~", &long[..]] {
+            let path = staged_path("data", key);
+            let relative = &path["data/".len()..];
+            assert_eq!(relative.split('/').any(|c| c == "." || c == ".." || c.is_empty()), false);
+            assert_eq!(staged_key("data", &path), Some(key.to_string()));
+        }
+        assert_eq!(staged_key("data", "data/+abc"), None);
+        assert_eq!(staged_key("data", "data/plain"), None);
+        assert_eq!(staged_key("data", "data/_a/_b"), None);
+        assert_eq!(staged_key("data", "data/_%4"), None);
+        assert_eq!(staged_key("other", "data/_a"), None);
+        assert_eq!(staged_key("data", &working_path("data", "a", "sealed")), None);
+    }
+
+    #[test]
+    fn staged_keys_test() {
+        let base = "file_tests/staged_keys";
+        let staging = StagingConfig::default();
+        assert_eq!(staged_keys(base).unwrap(), Vec::<String>::new());
+        let long = "k".repeat(MAX_COMPONENT_LENGTH + 1);
+        let keys = ["../x", "go/go.tar.gz", &long[..]];
+        for key in keys.iter() {
+            create_data_file(base, key, &bytes::Bytes::from("staged"), &staging).unwrap();
+        }
+        fs::write(working_path(base, "go/go.tar.gz", "sealed"), "copy").unwrap();
+        fs::write(format!("{}/notes.txt", base), "not staged").unwrap();
+        assert_eq!(staged_keys(base).unwrap(), keys.iter().map(|k| k.to_string()).collect::<Vec<_>>());
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn file_and_directory_keys_test() {
+        let staging = StagingConfig::default();
+        let long = "d".repeat(MAX_COMPONENT_LENGTH);
+        let keys = ["nested", "nested/", "nested/file", &long[..], &format!("{}/file", long)[..]];
+        for (index, key) in keys.iter().enumerate() {
+            let content = bytes::Bytes::from(format!("{}", index));
+            create_data_file(TEST_DIR, key, &content, &staging).unwrap();
+        }
+        // Writing again replaces what is staged
+        create_data_file(TEST_DIR, "nested", &bytes::Bytes::from("again"), &staging).unwrap();
+        let read = |key: &str| fs::read_to_string(staged_path(TEST_DIR, key)).unwrap();
+        assert_eq!(read("nested"), "again");
+        for (index, key) in keys.iter().enumerate().skip(1) {
+            assert_eq!(read(key), format!("{}", index));
+        }
+    }
+
+    #[test]
+    fn staging_quota_test() {
+        let doc = &yaml_rust::YamlLoader::load_from_str(
+            "staging:\n  path: file_tests/quota/\n  max_bytes: 100\n  wait: 0\n",
+        )
+        .unwrap()[0];
+        let staging = StagingConfig::from_yaml(&doc["staging"]).unwrap();
+        assert_eq!(staging.path, "file_tests/quota");
+        assert_eq!(staging.max_bytes, Some(100));
+        assert_eq!(staging.wait, Duration::from_secs(0));
+        // Nothing frees up room, so the reservations that don't fit fail
+        assert_eq!(staging.reserve("small", 60, 0).is_ok(), true);
+        assert_eq!(staging.reserve("large", 101, 0).is_err(), true);
+        let content = bytes::Bytes::from(vec![0; 60]);
+        create_data_file(&staging.path, "staged", &content, &staging).unwrap();
+        assert_eq!(staging.reserve("small", 40, 0).is_ok(), true);
+        assert_eq!(staging.reserve("small", 41, 0).is_err(), true);
+        // Working copies take up room as well, their own and other transfers'
+        assert_eq!(staging.reserve("small", 20, 0).is_ok(), true);
+        assert_eq!(staging.reserve("small", 20, 1).is_err(), true);
+        let copy = working_path(&staging.path, "staged", "sealed");
+        fs::write(&copy, vec![0; 30]).unwrap();
+        assert_eq!(staging.reserve("small", 10, 0).is_ok(), true);
+        assert_eq!(staging.reserve("small", 11, 0).is_err(), true);
+        remove_staged_file(&copy, &staging).unwrap();
+        delete_data_file(&staging.path, "staged", &staging).unwrap();
+        assert_eq!(staging.reserve("small", 41, 0).is_ok(), true);
+        // No filesystem has this much room
+        let unlimited = StagingConfig { path: TEST_DIR.to_owned(), ..StagingConfig::default() };
+        assert_eq!(unlimited.reserve("huge", 1 << 62, 0).is_err(), true);
+
+        let encrypted = StagingConfig { key: Some(vec![0; 32]), ..StagingConfig::default() };
+        assert_eq!(encrypted.staged_size(0), NONCE_PREFIX_SIZE as u64 + 16);
+        for bad in &["path: ''", "max_bytes: 0", "max_bytes: lots", "wait: -1"] {
+            let doc = &yaml_rust::YamlLoader::load_from_str(&format!("staging:\n  {}\n", bad)).unwrap()[0];
+            assert_eq!(StagingConfig::from_yaml(&doc["staging"]).is_err(), true);
+        }
+    }
+
+    #[test]
+    fn staging_wait_test() {
+        let staging = StagingConfig {
+            path: "file_tests/quota_wait".to_owned(),
+            max_bytes: Some(100),
+            wait: Duration::from_secs(30),
+            ..StagingConfig::default()
+        };
+        let content = bytes::Bytes::from(vec![0; 60]);
+        create_data_file(&staging.path, "staged", &content, &staging).unwrap();
+        // The upload of the staged file frees up room while the download waits
+        let uploader = {
+            let staging = staging.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                delete_data_file(&staging.path, "staged", &staging).unwrap();
+            })
+        };
+        let started = Instant::now();
+        assert_eq!(staging.reserve("waiting", 60, 0), Ok(()));
+        assert_eq!(started.elapsed() >= Duration::from_millis(200), true);
+        uploader.join().unwrap();
+
+        create_data_file(&staging.path, "staged", &content, &staging).unwrap();
+        let impatient = StagingConfig { wait: Duration::from_millis(50), ..staging.clone() };
+        assert_eq!(impatient.reserve("waiting", 60, 0).is_err(), true);
+        delete_data_file(&staging.path, "staged", &staging).unwrap();
+    }
 }
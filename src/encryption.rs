@@ -0,0 +1,392 @@
+use crate::config::{yaml_scalar, yaml_string_map};
+use rusoto_s3::{
+    CopyObjectRequest, CreateMultipartUploadRequest, GetObjectRequest, HeadObjectRequest,
+    PutObjectRequest, UploadPartCopyRequest, UploadPartRequest,
+};
+use std::fs;
+use yaml_rust::Yaml;
+
+pub const CUSTOMER_KEY_ALGORITHM: &str = "AES256";
+
+// A 256 bit key for SSE-C. S3 doesn't store the key, the same key has to be
+// sent with every request that reads or writes the object.
+#[derive(Clone, PartialEq)]
+pub struct CustomerKey {
+    key: Vec<u8>,
+}
+
+// Keeps the key material out of the logs
+impl std::fmt::Debug for CustomerKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "CustomerKey {{ key_md5: {:?} }}", self.key_md5())
+    }
+}
+
+impl CustomerKey {
+    // Accepts 32 raw bytes or the base64 encoding of them
+    pub fn from_bytes(content: &[u8]) -> Result<CustomerKey, String> {
+        let key = if content.len() == 32 {
+            content.to_vec()
+        } else {
+            let text = String::from_utf8_lossy(content);
+            base64::decode(text.trim())
+                .map_err(|_| "SSE-C key must be 32 bytes or base64 encoded".to_owned())?
+        };
+        if key.len() != 32 {
+            return Err(format!("SSE-C key must be 32 bytes, found {}", key.len()));
+        }
+        Ok(CustomerKey { key })
+    }
+
+    pub fn from_file(path: &str) -> Result<CustomerKey, String> {
+        let content =
+            fs::read(path).map_err(|e| format!("Could not read the SSE-C key {:?}: {}", path, e))?;
+        CustomerKey::from_bytes(&content)
+    }
+
+    fn algorithm(&self) -> Option<String> {
+        Some(CUSTOMER_KEY_ALGORITHM.to_owned())
+    }
+
+    fn key_base64(&self) -> Option<String> {
+        Some(base64::encode(&self.key))
+    }
+
+    fn key_md5(&self) -> Option<String> {
+        Some(base64::encode(&md5::compute(&self.key).0))
+    }
+
+    pub fn apply_to_get_object(&self, req: &mut GetObjectRequest) {
+        req.sse_customer_algorithm = self.algorithm();
+        req.sse_customer_key = self.key_base64();
+        req.sse_customer_key_md5 = self.key_md5();
+    }
+
+    pub fn apply_to_head_object(&self, req: &mut HeadObjectRequest) {
+        req.sse_customer_algorithm = self.algorithm();
+        req.sse_customer_key = self.key_base64();
+        req.sse_customer_key_md5 = self.key_md5();
+    }
+
+    // Server side copies send the source key in the copy source headers
+    pub fn apply_to_copy_source(&self, req: &mut CopyObjectRequest) {
+        req.copy_source_sse_customer_algorithm = self.algorithm();
+        req.copy_source_sse_customer_key = self.key_base64();
+        req.copy_source_sse_customer_key_md5 = self.key_md5();
+    }
+
+    pub fn apply_to_upload_part_copy_source(&self, req: &mut UploadPartCopyRequest) {
+        req.copy_source_sse_customer_algorithm = self.algorithm();
+        req.copy_source_sse_customer_key = self.key_base64();
+        req.copy_source_sse_customer_key_md5 = self.key_md5();
+    }
+}
+
+// How objects written to the destination are encrypted
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerSideEncryption {
+    // Whatever default encryption the destination bucket has
+    BucketDefault,
+    // SSE-S3, keys managed by S3
+    S3,
+    // SSE-KMS, with the bucket's default CMK when no key id is given
+    Kms {
+        key_id: Option<String>,
+        context: Vec<(String, String)>,
+    },
+    // SSE-C, with a key provided by bearcat
+    Customer(CustomerKey),
+}
+
+impl Default for ServerSideEncryption {
+    fn default() -> ServerSideEncryption {
+        ServerSideEncryption::BucketDefault
+    }
+}
+
+// The encryption headers of the requests that write an object, which they
+// all name the same way
+#[derive(Default)]
+struct WriteHeaders {
+    server_side_encryption: Option<String>,
+    ssekms_key_id: Option<String>,
+    ssekms_encryption_context: Option<String>,
+    sse_customer_algorithm: Option<String>,
+    sse_customer_key: Option<String>,
+    sse_customer_key_md5: Option<String>,
+}
+
+macro_rules! set_write_headers {
+    ($req:expr, $headers:expr) => {{
+        let headers = $headers;
+        $req.server_side_encryption = headers.server_side_encryption;
+        $req.ssekms_key_id = headers.ssekms_key_id;
+        $req.ssekms_encryption_context = headers.ssekms_encryption_context;
+        $req.sse_customer_algorithm = headers.sse_customer_algorithm;
+        $req.sse_customer_key = headers.sse_customer_key;
+        $req.sse_customer_key_md5 = headers.sse_customer_key_md5;
+    }};
+}
+
+impl ServerSideEncryption {
+    fn write_headers(&self) -> WriteHeaders {
+        let mut headers = WriteHeaders::default();
+        match self {
+            ServerSideEncryption::BucketDefault => {}
+            ServerSideEncryption::S3 => {
+                headers.server_side_encryption = Some("AES256".to_owned());
+            }
+            ServerSideEncryption::Kms { key_id, context } => {
+                headers.server_side_encryption = Some("aws:kms".to_owned());
+                headers.ssekms_key_id = key_id.clone();
+                headers.ssekms_encryption_context = encryption_context(context);
+            }
+            ServerSideEncryption::Customer(key) => {
+                headers.sse_customer_algorithm = key.algorithm();
+                headers.sse_customer_key = key.key_base64();
+                headers.sse_customer_key_md5 = key.key_md5();
+            }
+        }
+        headers
+    }
+
+    pub fn apply_to_create_multipart_upload(&self, req: &mut CreateMultipartUploadRequest) {
+        set_write_headers!(req, self.write_headers());
+    }
+
+    // Only SSE-C needs the key repeated on every part
+    pub fn apply_to_upload_part(&self, req: &mut UploadPartRequest) {
+        if let ServerSideEncryption::Customer(key) = self {
+            req.sse_customer_algorithm = key.algorithm();
+            req.sse_customer_key = key.key_base64();
+            req.sse_customer_key_md5 = key.key_md5();
+        }
+    }
+
+    pub fn apply_to_copy_object(&self, req: &mut CopyObjectRequest) {
+        set_write_headers!(req, self.write_headers());
+    }
+
+    pub fn apply_to_put_object(&self, req: &mut PutObjectRequest) {
+        set_write_headers!(req, self.write_headers());
+    }
+
+    pub fn apply_to_upload_part_copy(&self, req: &mut UploadPartCopyRequest) {
+        if let ServerSideEncryption::Customer(key) = self {
+            req.sse_customer_algorithm = key.algorithm();
+            req.sse_customer_key = key.key_base64();
+            req.sse_customer_key_md5 = key.key_md5();
+        }
+    }
+}
+
+// The encryption context header is a base64 encoded JSON object
+fn encryption_context(context: &[(String, String)]) -> Option<String> {
+    if context.is_empty() {
+        return None;
+    }
+    let object: serde_json::Map<String, serde_json::Value> = context
+        .iter()
+        .map(|(k, v)| (k.to_owned(), serde_json::Value::String(v.to_owned())))
+        .collect();
+    Some(base64::encode(&serde_json::Value::Object(object).to_string()))
+}
+
+// ETags of objects encrypted with SSE-KMS or SSE-C aren't the md5 of the content
+pub fn etag_is_md5(server_side_encryption: Option<&str>, sse_customer_algorithm: Option<&str>) -> bool {
+    server_side_encryption != Some("aws:kms") && sse_customer_algorithm.is_none()
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EncryptionConfig {
+    pub destination: ServerSideEncryption,
+    // Key for reading SSE-C encrypted objects from the source bucket
+    pub source_customer_key: Option<CustomerKey>,
+}
+
+impl EncryptionConfig {
+    // Reads the `encryption` section of the job config:
+    //
+    //   encryption:
+    //     type: aws:kms            # AES256, aws:kms or SSE-C, the bucket default when missing
+    //     kms_key_id: arn:aws-us-gov:kms:us-gov-west-1:111122223333:key/...
+    //     context:
+    //       project: bearcat
+    //     customer_key_file: /etc/bearcat/destination.key
+    //     source_customer_key_file: /etc/bearcat/source.key
+    pub fn from_yaml(yaml: &Yaml) -> Result<EncryptionConfig, String> {
+        let string = |name: &str| yaml_scalar(&yaml[name]);
+        let encryption_type = string("type");
+        let destination = match encryption_type.as_ref().map(String::as_str) {
+            None => ServerSideEncryption::BucketDefault,
+            Some("AES256") | Some("SSE-S3") => ServerSideEncryption::S3,
+            Some("aws:kms") | Some("SSE-KMS") => {
+                // rusoto can't send the per object bucket key header yet
+                match &yaml["bucket_key"] {
+                    Yaml::Boolean(false) | Yaml::BadValue => {}
+                    Yaml::Boolean(true) => {
+                        return Err("encryption.bucket_key isn't supported yet, enable S3 Bucket Keys \
+                                    on the destination bucket instead"
+                            .to_owned())
+                    }
+                    _ => return Err("encryption.bucket_key must be true or false".to_owned()),
+                }
+                ServerSideEncryption::Kms {
+                    key_id: string("kms_key_id"),
+                    context: yaml_string_map(&yaml["context"], "encryption.context")?,
+                }
+            }
+            Some("SSE-C") => {
+                let path = string("customer_key_file").ok_or_else(|| {
+                    "encryption.customer_key_file is required for SSE-C".to_owned()
+                })?;
+                ServerSideEncryption::Customer(CustomerKey::from_file(&path)?)
+            }
+            Some(other) => return Err(format!("unknown encryption type {:?}", other)),
+        };
+        let source_customer_key = match string("source_customer_key_file") {
+            Some(path) => Some(CustomerKey::from_file(&path)?),
+            None => None,
+        };
+        Ok(EncryptionConfig {
+            destination,
+            source_customer_key,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn config(content: &str) -> Result<EncryptionConfig, String> {
+        let doc = &YamlLoader::load_from_str(content).unwrap()[0];
+        EncryptionConfig::from_yaml(&doc["encryption"])
+    }
+
+    #[test]
+    fn customer_key_test() {
+        let raw = CustomerKey::from_bytes(&KEY).unwrap();
+        let encoded = CustomerKey::from_bytes(format!("{}\n", base64::encode(&KEY)).as_bytes());
+        assert_eq!(encoded, Ok(raw.clone()));
+        assert_eq!(CustomerKey::from_bytes(b"short").is_err(), true);
+        assert_eq!(format!("{:?}", raw).contains(&base64::encode(&KEY)), false);
+
+        let mut req = GetObjectRequest::default();
+        raw.apply_to_get_object(&mut req);
+        assert_eq!(req.sse_customer_algorithm, Some("AES256".to_owned()));
+        assert_eq!(req.sse_customer_key, Some(base64::encode(&KEY)));
+        assert_eq!(req.sse_customer_key_md5, Some(base64::encode(&md5::compute(&KEY).0)));
+    }
+
+    #[test]
+    fn kms_config_test() {
+        let encryption = config(
+            "encryption:\n  type: aws:kms\n  kms_key_id: alias/bearcat\n  context:\n    project: bearcat\n",
+        )
+        .unwrap();
+        let mut req = CreateMultipartUploadRequest::default();
+        encryption
+            .destination
+            .apply_to_create_multipart_upload(&mut req);
+        assert_eq!(req.server_side_encryption, Some("aws:kms".to_owned()));
+        assert_eq!(req.ssekms_key_id, Some("alias/bearcat".to_owned()));
+        assert_eq!(
+            req.ssekms_encryption_context,
+            Some(base64::encode("{\"project\":\"bearcat\"}"))
+        );
+        assert_eq!(encryption.source_customer_key, None);
+        // Every request that writes an object gets the same headers
+        let mut copy = CopyObjectRequest::default();
+        encryption.destination.apply_to_copy_object(&mut copy);
+        let mut put = PutObjectRequest::default();
+        encryption.destination.apply_to_put_object(&mut put);
+        assert_eq!((copy.ssekms_key_id, put.ssekms_key_id), (req.ssekms_key_id.clone(), req.ssekms_key_id));
+        assert_eq!(copy.ssekms_encryption_context, req.ssekms_encryption_context);
+    }
+
+    #[test]
+    fn default_config_test() {
+        let encryption = config("other: value").unwrap();
+        assert_eq!(encryption, EncryptionConfig::default());
+        let mut req = CreateMultipartUploadRequest::default();
+        encryption
+            .destination
+            .apply_to_create_multipart_upload(&mut req);
+        assert_eq!(req.server_side_encryption, None);
+    }
+
+    #[test]
+    fn sse_s3_config_test() {
+        let encryption = config("encryption:\n  type: AES256\n").unwrap();
+        assert_eq!(encryption.destination, ServerSideEncryption::S3);
+    }
+
+    #[test]
+    fn invalid_config_test() {
+        assert_eq!(config("encryption:\n  type: rot13\n").is_err(), true);
+        assert_eq!(config("encryption:\n  type: SSE-C\n").is_err(), true);
+        assert_eq!(
+            config("encryption:\n  type: aws:kms\n  bucket_key: sometimes\n").is_err(),
+            true
+        );
+        // Asking for bucket keys fails rather than quietly not sending them
+        assert_eq!(config("encryption:\n  type: aws:kms\n  bucket_key: true\n").is_err(), true);
+        assert_eq!(config("encryption:\n  type: aws:kms\n  bucket_key: false\n").is_ok(), true);
+        assert_eq!(
+            config("encryption:\n  source_customer_key_file: file_tests/missing.key\n").is_err(),
+            true
+        );
+    }
+
+    #[test]
+    fn customer_upload_part_test() {
+        let encryption = ServerSideEncryption::Customer(CustomerKey::from_bytes(&KEY).unwrap());
+        let mut req = UploadPartRequest::default();
+        encryption.apply_to_upload_part(&mut req);
+        assert_eq!(req.sse_customer_key, Some(base64::encode(&KEY)));
+
+        let mut req = UploadPartRequest::default();
+        ServerSideEncryption::S3.apply_to_upload_part(&mut req);
+        assert_eq!(req.sse_customer_key, None);
+    }
+
+    #[test]
+    fn copy_object_test() {
+        let key = CustomerKey::from_bytes(&KEY).unwrap();
+        let mut req = CopyObjectRequest::default();
+        ServerSideEncryption::S3.apply_to_copy_object(&mut req);
+        key.apply_to_copy_source(&mut req);
+        assert_eq!(req.server_side_encryption, Some("AES256".to_owned()));
+        assert_eq!(req.sse_customer_key, None);
+        assert_eq!(req.copy_source_sse_customer_key, Some(base64::encode(&KEY)));
+
+        let mut req = UploadPartCopyRequest::default();
+        ServerSideEncryption::Customer(key.clone()).apply_to_upload_part_copy(&mut req);
+        assert_eq!(req.sse_customer_key, Some(base64::encode(&KEY)));
+        assert_eq!(req.copy_source_sse_customer_key, None);
+    }
+
+    #[test]
+    fn put_object_test() {
+        let mut req = PutObjectRequest::default();
+        ServerSideEncryption::Customer(CustomerKey::from_bytes(&KEY).unwrap())
+            .apply_to_put_object(&mut req);
+        assert_eq!(req.sse_customer_algorithm, Some(CUSTOMER_KEY_ALGORITHM.to_owned()));
+        assert_eq!(req.sse_customer_key, Some(base64::encode(&KEY)));
+        let mut req = PutObjectRequest::default();
+        ServerSideEncryption::BucketDefault.apply_to_put_object(&mut req);
+        assert_eq!(req.server_side_encryption, None);
+    }
+
+    #[test]
+    fn etag_is_md5_test() {
+        assert_eq!(etag_is_md5(None, None), true);
+        assert_eq!(etag_is_md5(Some("AES256"), None), true);
+        assert_eq!(etag_is_md5(Some("aws:kms"), None), false);
+        assert_eq!(etag_is_md5(None, Some("AES256")), false);
+    }
+}
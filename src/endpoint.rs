@@ -0,0 +1,299 @@
+use crate::config::yaml_scalar;
+use crate::partition::Partition;
+use futures::Future;
+use rusoto_core::credential::ProvideAwsCredentials;
+use rusoto_core::region::Region;
+use rusoto_core::request::{DispatchSignedRequest, HttpClient, HttpDispatchError, HttpResponse};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::Client;
+use rusoto_s3::S3Client;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use yaml_rust::Yaml;
+
+// Where the S3 requests for one side of the sync are sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct EndpointConfig {
+    // Full endpoint URL, for VPC interface endpoints and S3 compatible stores
+    pub url: Option<String>,
+    // Address buckets by path instead of by their own host name
+    pub path_style: bool,
+    pub fips: bool,
+    pub dual_stack: bool,
+    // The endpoint is AWS, so the partition of its credentials is checked.
+    // Custom URLs are usually VPC or FIPS endpoints, only S3 compatible
+    // stores turn this off.
+    pub aws: bool,
+}
+
+impl Default for EndpointConfig {
+    fn default() -> EndpointConfig {
+        EndpointConfig {
+            url: None,
+            path_style: true,
+            fips: false,
+            dual_stack: false,
+            aws: true,
+        }
+    }
+}
+
+impl EndpointConfig {
+    pub fn from_yaml(yaml: &Yaml, name: &str) -> Result<EndpointConfig, String> {
+        let defaults = EndpointConfig::default();
+        let flag = |field: &str, default: bool| match &yaml[field] {
+            Yaml::Boolean(b) => Ok(*b),
+            Yaml::BadValue => Ok(default),
+            _ => Err(format!("{}.{} must be true or false", name, field)),
+        };
+        let config = EndpointConfig {
+            url: yaml_scalar(&yaml["url"]),
+            path_style: flag("path_style", defaults.path_style)?,
+            fips: flag("fips", defaults.fips)?,
+            dual_stack: flag("dual_stack", defaults.dual_stack)?,
+            aws: flag("aws", defaults.aws)?,
+        };
+        if config.url.is_some() && (config.fips || config.dual_stack) {
+            return Err(format!(
+                "{}.url can't be combined with fips or dual_stack, include them in the URL",
+                name
+            ));
+        }
+        Ok(config)
+    }
+
+    // Builds the region the S3 client is created with. FIPS and dual-stack
+    // endpoints aren't part of rusoto's regions so they are custom regions
+    // that keep the real region name for request signing.
+    pub fn region(&self, region: &str) -> Result<Region, String> {
+        if let Some(url) = &self.url {
+            return Ok(Region::Custom {
+                name: region.to_owned(),
+                endpoint: url.trim_end_matches('/').to_owned(),
+            });
+        }
+        let partition = Partition::from_region(region);
+        if self.fips && partition == Partition::AwsCn {
+            return Err(format!("S3 has no FIPS endpoints in {}", region));
+        }
+        let service = match (self.fips, self.dual_stack) {
+            (false, false) => {
+                return Region::from_str(region)
+                    .map_err(|e| format!("Unknown region {:?}: {}", region, e))
+            }
+            (true, false) => format!("s3-fips.{}", region),
+            (false, true) => format!("s3.dualstack.{}", region),
+            (true, true) => format!("s3-fips.dualstack.{}", region),
+        };
+        let domain = match partition {
+            Partition::AwsCn => "amazonaws.com.cn",
+            _ => "amazonaws.com",
+        };
+        Ok(Region::Custom {
+            name: region.to_owned(),
+            endpoint: format!("https://{}.{}", service, domain),
+        })
+    }
+
+    // Creates the S3 client for this side. rusoto builds every S3 request by
+    // path, so virtual hosted clients leave the requests unsigned and sign
+    // them once they have been moved to the bucket's host.
+    pub fn client<P>(&self, provider: P, region: Region) -> Result<S3Client, String>
+    where
+        P: ProvideAwsCredentials + Send + Sync + 'static,
+        P::Future: Send,
+    {
+        let http = HttpClient::new().map_err(|e| format!("Could not create the HTTP client: {}", e))?;
+        if self.path_style {
+            return Ok(S3Client::new_with(http, provider, region));
+        }
+        let dispatcher = VirtualHostedDispatcher {
+            http: Arc::new(http),
+            provider: Arc::new(provider),
+        };
+        Ok(S3Client::new_with_client(Client::new_not_signing(dispatcher), region))
+    }
+}
+
+struct VirtualHostedDispatcher<P> {
+    http: Arc<HttpClient>,
+    provider: Arc<P>,
+}
+
+impl<P> DispatchSignedRequest for VirtualHostedDispatcher<P>
+where
+    P: ProvideAwsCredentials + Send + Sync + 'static,
+    P::Future: Send,
+{
+    type Future = Box<dyn Future<Item = HttpResponse, Error = HttpDispatchError> + Send>;
+
+    fn dispatch(&self, mut request: SignedRequest, timeout: Option<Duration>) -> Self::Future {
+        virtual_host(&mut request);
+        let http = self.http.clone();
+        Box::new(
+            self.provider
+                .credentials()
+                .map_err(|e| HttpDispatchError::new(format!("Could not get credentials: {}", e)))
+                .and_then(move |credentials| {
+                    request.sign_with_plus(&credentials, true);
+                    http.dispatch(request, timeout)
+                }),
+        )
+    }
+}
+
+// Moves the bucket from the start of the request path to the host, for
+// buckets whose name can be a label under the endpoint's host name. Names
+// with dots would break the certificate match of https endpoints, so those
+// buckets are still addressed by path.
+fn virtual_host(request: &mut SignedRequest) {
+    let path = request.path().trim_start_matches('/').to_owned();
+    let mut split = path.splitn(2, '/');
+    let bucket = split.next().unwrap_or_default();
+    let https = request.scheme() == "https";
+    let valid = !bucket.is_empty()
+        && bucket.len() <= 63
+        && !bucket.starts_with('-')
+        && !bucket.ends_with('-')
+        && bucket
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || (b == b'.' && !https));
+    if !valid {
+        return;
+    }
+    let hostname = format!("{}.{}", bucket, request.hostname());
+    request.path = format!("/{}", split.next().unwrap_or_default());
+    request.set_hostname(Some(hostname));
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EndpointsConfig {
+    pub source: EndpointConfig,
+    pub destination: EndpointConfig,
+}
+
+impl EndpointsConfig {
+    // Reads the `endpoints` section of the job config:
+    //
+    //   endpoints:
+    //     source:
+    //       url: http://localhost:9000
+    //       aws: false
+    //     destination:
+    //       fips: true
+    //       dual_stack: true
+    pub fn from_yaml(yaml: &Yaml) -> Result<EndpointsConfig, String> {
+        Ok(EndpointsConfig {
+            source: EndpointConfig::from_yaml(&yaml["source"], "endpoints.source")?,
+            destination: EndpointConfig::from_yaml(&yaml["destination"], "endpoints.destination")?,
+        })
+    }
+
+    // Server side copies only work when both sides talk to the same service
+    pub fn same_service(&self) -> bool {
+        self.source.url == self.destination.url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn config(content: &str) -> Result<EndpointsConfig, String> {
+        let doc = &YamlLoader::load_from_str(content).unwrap()[0];
+        EndpointsConfig::from_yaml(&doc["endpoints"])
+    }
+
+    fn endpoint(region: Region) -> String {
+        match region {
+            Region::Custom { endpoint, .. } => endpoint,
+            other => panic!("expected a custom region, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn default_endpoint_test() {
+        let endpoints = config("other: value").unwrap();
+        assert_eq!(endpoints, EndpointsConfig::default());
+        assert_eq!(endpoints.source.region("us-west-2"), Ok(Region::UsWest2));
+        assert_eq!(endpoints.source.region("mars-1").is_err(), true);
+    }
+
+    #[test]
+    fn custom_url_test() {
+        let endpoints = config("endpoints:\n  source:\n    url: http://localhost:9000/\n").unwrap();
+        assert_eq!(
+            endpoints.source.region("us-east-1"),
+            Ok(Region::Custom {
+                name: "us-east-1".to_owned(),
+                endpoint: "http://localhost:9000".to_owned(),
+            })
+        );
+        assert_eq!(endpoints.same_service(), false);
+    }
+
+    #[test]
+    fn fips_dual_stack_test() {
+        let endpoints =
+            config("endpoints:\n  destination:\n    fips: true\n    dual_stack: true\n").unwrap();
+        assert_eq!(
+            endpoint(endpoints.destination.region("us-gov-west-1").unwrap()),
+            "https://s3-fips.dualstack.us-gov-west-1.amazonaws.com"
+        );
+        let dual_stack = EndpointConfig {
+            dual_stack: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            endpoint(dual_stack.region("cn-north-1").unwrap()),
+            "https://s3.dualstack.cn-north-1.amazonaws.com.cn"
+        );
+        assert_eq!(endpoints.destination.region("cn-north-1").is_err(), true);
+    }
+
+    #[test]
+    fn virtual_host_test() {
+        let endpoints = config("endpoints:\n  source:\n    path_style: false\n").unwrap();
+        assert_eq!(endpoints.source.path_style, false);
+        let mut request = SignedRequest::new("GET", "s3", &Region::UsWest2, "/bearcat/go/go1.12.5.tar.gz");
+        virtual_host(&mut request);
+        assert_eq!(request.hostname(), "bearcat.s3-us-west-2.amazonaws.com");
+        assert_eq!(request.path(), "/go/go1.12.5.tar.gz");
+        let mut request = SignedRequest::new("HEAD", "s3", &Region::UsEast1, "/bearcat");
+        virtual_host(&mut request);
+        assert_eq!(request.hostname(), "bearcat.s3.amazonaws.com");
+        assert_eq!(request.path(), "/");
+        // Dotted names don't match the wildcard certificate
+        let mut request = SignedRequest::new("GET", "s3", &Region::UsEast1, "/bear.cat/key");
+        virtual_host(&mut request);
+        assert_eq!(request.hostname(), "s3.amazonaws.com");
+        assert_eq!(request.path(), "/bear.cat/key");
+        let minio = Region::Custom {
+            name: "us-east-1".to_owned(),
+            endpoint: "http://minio:9000".to_owned(),
+        };
+        let mut request = SignedRequest::new("GET", "s3", &minio, "/bear.cat/key");
+        virtual_host(&mut request);
+        assert_eq!(request.hostname(), "bear.cat.minio:9000");
+    }
+
+    #[test]
+    fn aws_endpoint_test() {
+        let vpc = "endpoints:\n  source:\n    url: https://bucket.vpce-1a2b.s3.us-west-2.vpce.amazonaws.com\n";
+        assert_eq!(config(vpc).unwrap().source.aws, true);
+        let minio = config("endpoints:\n  source:\n    url: http://localhost:9000\n    aws: false\n").unwrap();
+        assert_eq!((minio.source.aws, minio.destination.aws), (false, true));
+    }
+
+    #[test]
+    fn invalid_endpoint_test() {
+        assert_eq!(config("endpoints:\n  source:\n    fips: sometimes\n").is_err(), true);
+        assert_eq!(config("endpoints:\n  source:\n    aws: maybe\n").is_err(), true);
+        assert_eq!(
+            config("endpoints:\n  source:\n    url: http://minio:9000\n    fips: true\n").is_err(),
+            true
+        );
+    }
+}
@@ -0,0 +1,623 @@
+use crate::config::yaml_scalar;
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use bytes::Bytes;
+use rand::RngCore;
+use rusoto_core::Region;
+use rusoto_kms::{DecryptRequest, GenerateDataKeyRequest, Kms, KmsClient};
+use std::collections::HashMap;
+use std::fs;
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use yaml_rust::Yaml;
+
+// User metadata key holding the data key, wrapped by KMS or the local key
+pub const WRAPPED_KEY_METADATA_KEY: &str = "bearcat-cse-wrapped-key";
+// User metadata key holding the nonce prefix the chunks were sealed with
+pub const NONCE_METADATA_KEY: &str = "bearcat-cse-nonce";
+// User metadata key naming what wrapped the data key, aws:kms or local
+pub const KEY_WRAP_METADATA_KEY: &str = "bearcat-cse-key-wrap";
+// User metadata key holding the SHA-256 of the plaintext
+pub const PLAINTEXT_SHA256_METADATA_KEY: &str = "bearcat-cse-sha256";
+// User metadata key holding the SHA-256 of the sealed bytes S3 stores
+pub const SEALED_SHA256_METADATA_KEY: &str = "bearcat-cse-sealed-sha256";
+
+// Content is sealed in chunks so objects never have to fit in memory. Each
+// chunk has its own nonce, the random prefix followed by the chunk number and
+// a flag marking the last chunk, so chunks can't be reordered or dropped.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+const TAG_SIZE: u64 = 16;
+pub const NONCE_PREFIX_SIZE: usize = 7;
+const WRAP_NONCE_SIZE: usize = 12;
+
+// What protects the per object data keys
+#[derive(Clone, PartialEq)]
+pub enum KeyWrap {
+    // A KMS key, data keys come from GenerateDataKey
+    Kms { key_id: String, region: String },
+    // A 256 bit key read from a local file, data keys are sealed with AES-256-GCM
+    Local(Vec<u8>),
+}
+
+// Keeps the key material out of the logs
+impl std::fmt::Debug for KeyWrap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KeyWrap::Kms { key_id, region } => {
+                write!(f, "Kms {{ key_id: {:?}, region: {:?} }}", key_id, region)
+            }
+            KeyWrap::Local(_) => write!(f, "Local"),
+        }
+    }
+}
+
+impl KeyWrap {
+    fn name(&self) -> &'static str {
+        match self {
+            KeyWrap::Kms { .. } => "aws:kms",
+            KeyWrap::Local(_) => "local",
+        }
+    }
+
+    // Returns a new data key and its wrapped form
+    fn generate(&self) -> Result<(Vec<u8>, Vec<u8>), String> {
+        match self {
+            KeyWrap::Kms { key_id, region } => {
+                let res = kms_client(region)?
+                    .generate_data_key(GenerateDataKeyRequest {
+                        key_id: key_id.to_owned(),
+                        key_spec: Some("AES_256".to_owned()),
+                        ..Default::default()
+                    })
+                    .sync()
+                    .map_err(|e| format!("Could not generate a data key with {:?}: {}", key_id, e))?;
+                match (res.plaintext, res.ciphertext_blob) {
+                    (Some(plaintext), Some(wrapped)) => Ok((plaintext.to_vec(), wrapped.to_vec())),
+                    _ => Err(format!("KMS returned no data key for {:?}", key_id)),
+                }
+            }
+            KeyWrap::Local(key) => {
+                let mut data_key = vec![0; 32];
+                let mut nonce = vec![0; WRAP_NONCE_SIZE];
+                rand::thread_rng().fill_bytes(&mut data_key);
+                rand::thread_rng().fill_bytes(&mut nonce);
+                let sealed = cipher(key)
+                    .encrypt(GenericArray::from_slice(&nonce), data_key.as_slice())
+                    .map_err(|_| "Could not wrap the data key".to_owned())?;
+                nonce.extend(sealed);
+                Ok((data_key, nonce))
+            }
+        }
+    }
+
+    fn unwrap_key(&self, wrapped: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            KeyWrap::Kms { key_id, region } => {
+                let res = kms_client(region)?
+                    .decrypt(DecryptRequest {
+                        ciphertext_blob: Bytes::from(wrapped.to_vec()),
+                        ..Default::default()
+                    })
+                    .sync()
+                    .map_err(|e| format!("Could not unwrap the data key with {:?}: {}", key_id, e))?;
+                res.plaintext
+                    .map(|plaintext| plaintext.to_vec())
+                    .ok_or_else(|| format!("KMS returned no data key for {:?}", key_id))
+            }
+            KeyWrap::Local(key) => {
+                if wrapped.len() < WRAP_NONCE_SIZE {
+                    return Err("The wrapped data key is too short".to_owned());
+                }
+                let (nonce, sealed) = wrapped.split_at(WRAP_NONCE_SIZE);
+                cipher(key)
+                    .decrypt(GenericArray::from_slice(nonce), sealed)
+                    .map_err(|_| "The data key wasn't wrapped with the local key".to_owned())
+            }
+        }
+    }
+}
+
+// KMS is called with the default credential chain of the sync host
+fn kms_client(region: &str) -> Result<KmsClient, String> {
+    let region: Region = region
+        .parse()
+        .map_err(|e| format!("Invalid KMS region {:?}: {}", region, e))?;
+    Ok(KmsClient::new(region))
+}
+
+fn cipher(key: &[u8]) -> Aes256Gcm {
+    Aes256Gcm::new(GenericArray::from_slice(key))
+}
+
+// The data key of one object and what is needed to find it again
+pub struct Envelope {
+    data_key: Vec<u8>,
+    wrapped_key: Vec<u8>,
+    nonce_prefix: Vec<u8>,
+    key_wrap: &'static str,
+}
+
+impl Envelope {
+    // Seals everything read from `input` into `output`
+    pub fn encrypt<R: Read, W: Write>(&self, mut input: R, output: W) -> io::Result<W> {
+        let mut sealing = SealingWriter::new(output, &self.data_key, &self.nonce_prefix);
+        io::copy(&mut input, &mut sealing)?;
+        sealing.finish()
+    }
+
+    // Opens the sealed `input` into `output`, failing when any chunk was
+    // changed, reordered or cut off
+    pub fn decrypt<R: Read + Seek, W: Write>(&self, input: R, mut output: W) -> io::Result<W> {
+        let mut opening = OpeningReader::new(input, &self.data_key, &self.nonce_prefix)?;
+        io::copy(&mut opening, &mut output)?;
+        Ok(output)
+    }
+
+    pub fn apply_to_metadata(&self, metadata: &mut HashMap<String, String>) {
+        metadata.insert(WRAPPED_KEY_METADATA_KEY.to_owned(), base64::encode(&self.wrapped_key));
+        metadata.insert(NONCE_METADATA_KEY.to_owned(), base64::encode(&self.nonce_prefix));
+        metadata.insert(KEY_WRAP_METADATA_KEY.to_owned(), self.key_wrap.to_owned());
+    }
+}
+
+// Size of `length` bytes once sealed, every chunk carries a tag and there is
+// always at least one chunk
+pub fn sealed_length(length: u64) -> u64 {
+    let chunks = std::cmp::max(1, (length + CHUNK_SIZE - 1) / CHUNK_SIZE);
+    length + chunks * TAG_SIZE
+}
+
+fn chunk_nonce(nonce_prefix: &[u8], index: u64, last: bool) -> Vec<u8> {
+    let mut nonce = nonce_prefix.to_vec();
+    nonce.extend_from_slice(&(index as u32).to_be_bytes());
+    nonce.push(last as u8);
+    nonce
+}
+
+fn corrupted() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "the sealed content was changed or cut off",
+    )
+}
+
+// Seals what is written to it chunk by chunk. A chunk is only sealed once
+// more content follows it, `finish` seals the last one.
+pub struct SealingWriter<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    nonce_prefix: Vec<u8>,
+    buffer: Vec<u8>,
+    index: u64,
+}
+
+impl<W: Write> SealingWriter<W> {
+    pub fn new(inner: W, key: &[u8], nonce_prefix: &[u8]) -> SealingWriter<W> {
+        SealingWriter {
+            inner,
+            cipher: cipher(key),
+            nonce_prefix: nonce_prefix.to_vec(),
+            buffer: Vec::new(),
+            index: 0,
+        }
+    }
+
+    fn seal_chunk(&mut self, last: bool) -> io::Result<()> {
+        let length = std::cmp::min(self.buffer.len(), CHUNK_SIZE as usize);
+        let chunk: Vec<u8> = self.buffer.drain(..length).collect();
+        let nonce = chunk_nonce(&self.nonce_prefix, self.index, last);
+        let sealed = self
+            .cipher
+            .encrypt(GenericArray::from_slice(&nonce), chunk.as_slice())
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "could not seal the content"))?;
+        self.inner.write_all(&sealed)?;
+        self.index += 1;
+        Ok(())
+    }
+
+    // Empty content is still one chunk, so the tag covers it
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal_chunk(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for SealingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while self.buffer.len() as u64 > CHUNK_SIZE {
+            self.seal_chunk(false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Reads the plaintext of sealed content from where `inner` is positioned to
+// its end. Chunks are opened as they are reached, so it can seek.
+pub struct OpeningReader<R: Read + Seek> {
+    inner: R,
+    cipher: Aes256Gcm,
+    nonce_prefix: Vec<u8>,
+    offset: u64,
+    chunks: u64,
+    length: u64,
+    position: u64,
+    chunk: Vec<u8>,
+    chunk_index: Option<u64>,
+}
+
+impl<R: Read + Seek> OpeningReader<R> {
+    pub fn new(mut inner: R, key: &[u8], nonce_prefix: &[u8]) -> io::Result<OpeningReader<R>> {
+        let offset = inner.seek(SeekFrom::Current(0))?;
+        let sealed_length = inner.seek(SeekFrom::End(0))? - offset;
+        let sealed_chunk = CHUNK_SIZE + TAG_SIZE;
+        let chunks = std::cmp::max(1, (sealed_length + sealed_chunk - 1) / sealed_chunk);
+        let last_chunk = sealed_length - (chunks - 1) * sealed_chunk;
+        if last_chunk < TAG_SIZE {
+            return Err(corrupted());
+        }
+        Ok(OpeningReader {
+            inner,
+            cipher: cipher(key),
+            nonce_prefix: nonce_prefix.to_vec(),
+            offset,
+            chunks,
+            length: (chunks - 1) * CHUNK_SIZE + last_chunk - TAG_SIZE,
+            position: 0,
+            chunk: Vec::new(),
+            chunk_index: None,
+        })
+    }
+
+    fn open_chunk(&mut self, index: u64) -> io::Result<()> {
+        let sealed_chunk = CHUNK_SIZE + TAG_SIZE;
+        self.inner
+            .seek(SeekFrom::Start(self.offset + index * sealed_chunk))?;
+        let mut sealed = Vec::new();
+        (&mut self.inner).take(sealed_chunk).read_to_end(&mut sealed)?;
+        let nonce = chunk_nonce(&self.nonce_prefix, index, index + 1 == self.chunks);
+        self.chunk = self
+            .cipher
+            .decrypt(GenericArray::from_slice(&nonce), sealed.as_slice())
+            .map_err(|_| corrupted())?;
+        self.chunk_index = Some(index);
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for OpeningReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.length || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.position / CHUNK_SIZE;
+        if self.chunk_index != Some(index) {
+            self.open_chunk(index)?;
+        }
+        let start = (self.position - index * CHUNK_SIZE) as usize;
+        let read = std::cmp::min(buf.len(), self.chunk.len() - start);
+        buf[..read].copy_from_slice(&self.chunk[start..start + read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for OpeningReader<R> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(position) => position as i64,
+            SeekFrom::End(offset) => self.length as i64 + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+        if position < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't seek before the start",
+            ));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+// Strips the envelope from the metadata of a decrypted object
+pub fn remove_envelope_metadata(metadata: &mut HashMap<String, String>) {
+    for key in &[
+        WRAPPED_KEY_METADATA_KEY,
+        NONCE_METADATA_KEY,
+        KEY_WRAP_METADATA_KEY,
+        PLAINTEXT_SHA256_METADATA_KEY,
+        SEALED_SHA256_METADATA_KEY,
+    ] {
+        metadata.remove(*key);
+    }
+}
+
+// Client side envelope encryption. Each object gets its own AES-256-GCM data
+// key, which is stored next to it in the metadata wrapped by a KMS key or a
+// local key. Sealed objects can pass through buckets and sync hosts that
+// don't hold the key.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientEncryption {
+    // Seal objects before they are uploaded
+    pub encrypt: bool,
+    // Open sealed source objects after they are downloaded
+    pub decrypt: bool,
+    pub key_wrap: Option<KeyWrap>,
+}
+
+impl ClientEncryption {
+    // Reads the `client_encryption` section of the job config. Either a KMS
+    // key or a local key file wraps the data keys:
+    //
+    //   client_encryption:
+    //     encrypt: true
+    //     decrypt: false
+    //     kms_key_id: arn:aws-us-gov:kms:us-gov-west-1:111122223333:key/...
+    //     kms_region: us-gov-west-1       # taken from the key ARN when missing
+    //     key_file: /etc/bearcat/envelope.key
+    pub fn from_yaml(yaml: &Yaml) -> Result<ClientEncryption, String> {
+        let flag = |name: &str| match &yaml[name] {
+            Yaml::Boolean(b) => Ok(*b),
+            Yaml::BadValue => Ok(false),
+            _ => Err(format!("client_encryption.{} must be true or false", name)),
+        };
+        let encrypt = flag("encrypt")?;
+        let decrypt = flag("decrypt")?;
+        let key_wrap = match (yaml_scalar(&yaml["kms_key_id"]), yaml_scalar(&yaml["key_file"])) {
+            (Some(_), Some(_)) => {
+                return Err("client_encryption takes a kms_key_id or a key_file, not both".to_owned())
+            }
+            (Some(key_id), None) => {
+                let region = yaml_scalar(&yaml["kms_region"])
+                    .or_else(|| arn_region(&key_id))
+                    .ok_or_else(|| {
+                        "client_encryption.kms_region is required unless kms_key_id is an ARN"
+                            .to_owned()
+                    })?;
+                Some(KeyWrap::Kms { key_id, region })
+            }
+            (None, Some(path)) => Some(KeyWrap::Local(read_local_key(&path)?)),
+            (None, None) => None,
+        };
+        if (encrypt || decrypt) && key_wrap.is_none() {
+            return Err("client_encryption needs a kms_key_id or a key_file".to_owned());
+        }
+        Ok(ClientEncryption {
+            encrypt,
+            decrypt,
+            key_wrap,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.encrypt || self.decrypt
+    }
+
+    // A new data key for an object about to be uploaded
+    pub fn seal(&self) -> Result<Envelope, String> {
+        let key_wrap = self
+            .key_wrap
+            .as_ref()
+            .ok_or_else(|| "Client side encryption has no key".to_owned())?;
+        let (data_key, wrapped_key) = key_wrap.generate()?;
+        let mut nonce_prefix = vec![0; NONCE_PREFIX_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce_prefix);
+        Ok(Envelope {
+            data_key,
+            wrapped_key,
+            nonce_prefix,
+            key_wrap: key_wrap.name(),
+        })
+    }
+
+    // The envelope of a downloaded object, None when it wasn't sealed
+    pub fn open(&self, metadata: &HashMap<String, String>) -> Result<Option<Envelope>, String> {
+        let wrapped_key = match metadata.get(WRAPPED_KEY_METADATA_KEY) {
+            Some(wrapped_key) => wrapped_key,
+            None => return Ok(None),
+        };
+        let key_wrap = self
+            .key_wrap
+            .as_ref()
+            .ok_or_else(|| "Client side encryption has no key".to_owned())?;
+        let name = metadata.get(KEY_WRAP_METADATA_KEY).map(String::as_str);
+        if name != Some(key_wrap.name()) {
+            return Err(format!(
+                "The data key was wrapped with {:?} but the job has a {} key",
+                name,
+                key_wrap.name()
+            ));
+        }
+        let wrapped_key =
+            base64::decode(wrapped_key).map_err(|_| "The wrapped data key isn't base64".to_owned())?;
+        let nonce_prefix = metadata
+            .get(NONCE_METADATA_KEY)
+            .and_then(|nonce| base64::decode(nonce).ok())
+            .filter(|nonce| nonce.len() == NONCE_PREFIX_SIZE)
+            .ok_or_else(|| "The object has no valid client side encryption nonce".to_owned())?;
+        let data_key = key_wrap.unwrap_key(&wrapped_key)?;
+        if data_key.len() != 32 {
+            return Err(format!("The data key must be 32 bytes, found {}", data_key.len()));
+        }
+        Ok(Some(Envelope {
+            data_key,
+            wrapped_key,
+            nonce_prefix,
+            key_wrap: key_wrap.name(),
+        }))
+    }
+}
+
+// arn:<partition>:kms:<region>:<account>:key/<id>
+fn arn_region(key_id: &str) -> Option<String> {
+    let parts: Vec<&str> = key_id.split(':').collect();
+    if parts.len() >= 6 && parts[0] == "arn" && parts[2] == "kms" && !parts[3].is_empty() {
+        Some(parts[3].to_owned())
+    } else {
+        None
+    }
+}
+
+// Accepts 32 raw bytes or the base64 encoding of them
+fn read_local_key(path: &str) -> Result<Vec<u8>, String> {
+    let content = fs::read(path)
+        .map_err(|e| format!("Could not read the client side encryption key {:?}: {}", path, e))?;
+    let key = if content.len() == 32 {
+        content
+    } else {
+        base64::decode(String::from_utf8_lossy(&content).trim())
+            .map_err(|_| "The client side encryption key must be 32 bytes or base64 encoded".to_owned())?
+    };
+    if key.len() != 32 {
+        return Err(format!(
+            "The client side encryption key must be 32 bytes, found {}",
+            key.len()
+        ));
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use yaml_rust::YamlLoader;
+
+    const KEY: [u8; 32] = [9; 32];
+
+    fn config(content: &str) -> Result<ClientEncryption, String> {
+        let doc = &YamlLoader::load_from_str(content).unwrap()[0];
+        ClientEncryption::from_yaml(&doc["client_encryption"])
+    }
+
+    fn local() -> ClientEncryption {
+        ClientEncryption {
+            encrypt: true,
+            decrypt: true,
+            key_wrap: Some(KeyWrap::Local(KEY.to_vec())),
+        }
+    }
+
+    fn staged(name: &str, content: &[u8]) -> String {
+        fs::create_dir_all("file_tests").unwrap();
+        let path = format!("file_tests/{}", name);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let encryption = local();
+        // Empty, partial and exactly one chunk, and more than one chunk
+        for length in &[0, 100, CHUNK_SIZE, CHUNK_SIZE * 2 + 5] {
+            let content: Vec<u8> = (0..*length).map(|i| (i % 251) as u8).collect();
+            let envelope = encryption.seal().unwrap();
+            let sealed = envelope.encrypt(content.as_slice(), Vec::new()).unwrap();
+            assert_eq!(sealed.len() as u64, sealed_length(*length));
+            assert_eq!(sealed.starts_with(&content), *length == 0);
+            let mut metadata = HashMap::new();
+            envelope.apply_to_metadata(&mut metadata);
+
+            let envelope = encryption.open(&metadata).unwrap().unwrap();
+            let opened = envelope.decrypt(Cursor::new(sealed), Vec::new()).unwrap();
+            assert_eq!(opened, content);
+        }
+    }
+
+    #[test]
+    fn tampered_test() {
+        let envelope = local().seal().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| (i % 7) as u8).collect();
+        let mut sealed = envelope.encrypt(content.as_slice(), Vec::new()).unwrap();
+
+        // Dropping the last chunk leaves a chunk that isn't marked last
+        let truncated = sealed[..(CHUNK_SIZE + TAG_SIZE) as usize].to_vec();
+        assert_eq!(envelope.decrypt(Cursor::new(truncated), Vec::new()).is_err(), true);
+
+        sealed[3] ^= 1;
+        assert_eq!(envelope.decrypt(Cursor::new(sealed), Vec::new()).is_err(), true);
+    }
+
+    #[test]
+    fn seek_test() {
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 5).map(|i| (i % 251) as u8).collect();
+        let mut writer = SealingWriter::new(b"header".to_vec(), &KEY, &[1; NONCE_PREFIX_SIZE]);
+        writer.write_all(&content).unwrap();
+        let sealed = writer.finish().unwrap();
+
+        // Opening starts wherever the reader is positioned
+        let mut inner = Cursor::new(sealed);
+        inner.seek(SeekFrom::Start(6)).unwrap();
+        let mut reader = OpeningReader::new(inner, &KEY, &[1; NONCE_PREFIX_SIZE]).unwrap();
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), content.len() as u64);
+        let mut tail = Vec::new();
+        reader.seek(SeekFrom::Start(CHUNK_SIZE * 2 - 3)).unwrap();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, &content[(CHUNK_SIZE * 2 - 3) as usize..]);
+        let mut head = [0; 4];
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_exact(&mut head).unwrap();
+        assert_eq!(&head, &content[..4]);
+    }
+
+    #[test]
+    fn open_test() {
+        let encryption = local();
+        assert_eq!(encryption.open(&HashMap::new()).unwrap().is_none(), true);
+
+        let mut metadata = HashMap::new();
+        encryption.seal().unwrap().apply_to_metadata(&mut metadata);
+        assert_eq!(metadata.get(KEY_WRAP_METADATA_KEY), Some(&"local".to_owned()));
+        let other = ClientEncryption {
+            key_wrap: Some(KeyWrap::Local(vec![1; 32])),
+            ..local()
+        };
+        assert_eq!(other.open(&metadata).is_err(), true);
+        let kms = ClientEncryption {
+            key_wrap: Some(KeyWrap::Kms {
+                key_id: "alias/bearcat".to_owned(),
+                region: "us-west-2".to_owned(),
+            }),
+            ..local()
+        };
+        assert_eq!(kms.open(&metadata).is_err(), true);
+
+        metadata.insert(PLAINTEXT_SHA256_METADATA_KEY.to_owned(), "abc".to_owned());
+        metadata.insert("project".to_owned(), "bearcat".to_owned());
+        remove_envelope_metadata(&mut metadata);
+        assert_eq!(metadata.len(), 1);
+    }
+
+    #[test]
+    fn config_test() {
+        assert_eq!(config("other: value"), Ok(ClientEncryption::default()));
+        let kms = config(
+            "client_encryption:\n  encrypt: true\n  kms_key_id: arn:aws-us-gov:kms:us-gov-west-1:111122223333:key/abc\n",
+        )
+        .unwrap();
+        assert_eq!(
+            kms.key_wrap,
+            Some(KeyWrap::Kms {
+                key_id: "arn:aws-us-gov:kms:us-gov-west-1:111122223333:key/abc".to_owned(),
+                region: "us-gov-west-1".to_owned(),
+            })
+        );
+        assert_eq!(config("client_encryption:\n  encrypt: true\n  kms_key_id: alias/bearcat\n").is_err(), true);
+        assert_eq!(config("client_encryption:\n  decrypt: true\n").is_err(), true);
+        assert_eq!(config("client_encryption:\n  encrypt: maybe\n").is_err(), true);
+
+        let path = staged("cse.key", base64::encode(&KEY).as_bytes());
+        let local = config(&format!("client_encryption:\n  decrypt: true\n  key_file: {}\n", path)).unwrap();
+        assert_eq!(local.key_wrap, Some(KeyWrap::Local(KEY.to_vec())));
+        assert_eq!(format!("{:?}", local).contains("9, 9"), false);
+        let short = staged("cse-short.key", b"short");
+        assert_eq!(config(&format!("client_encryption:\n  key_file: {}\n", short)).is_err(), true);
+    }
+}
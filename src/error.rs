@@ -0,0 +1,414 @@
+use crate::inspect::BlockedFile;
+use rusoto_core::RusotoError;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use yaml_rust::Yaml;
+use log::{error, info};
+
+// What went wrong in a sync, which decides the exit code. It implements
+// std's Error so the modules can return it in a Box<dyn Error>, and gets
+// failure's Fail from that so context can be chained on top of it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BearcatError {
+    // Invalid arguments or job config
+    Config(String),
+    Credentials(String),
+    AccessDenied(String),
+    NotFound(String),
+    Throttled(String),
+    Integrity(String),
+    PolicyRejection(String),
+    LocalIo(String),
+    Other(String),
+}
+
+// Error codes AWS services answer with. They are compared whole, against
+// the code of the response and never against a formatted message, which
+// holds keys and bucket names.
+const CREDENTIALS_CODES: [&str; 6] = [
+    "InvalidAccessKeyId",
+    "InvalidClientTokenId",
+    "ExpiredToken",
+    "ExpiredTokenException",
+    "SignatureDoesNotMatch",
+    "UnrecognizedClientException",
+];
+const ACCESS_DENIED_CODES: [&str; 3] = ["AccessDenied", "AccessDeniedException", "AllAccessDisabled"];
+const NOT_FOUND_CODES: [&str; 6] = [
+    "NoSuchKey",
+    "NoSuchBucket",
+    "NoSuchVersion",
+    "NoSuchUpload",
+    "NotFound",
+    "NotFoundException",
+];
+const THROTTLED_CODES: [&str; 6] = [
+    "SlowDown",
+    "Throttling",
+    "ThrottlingException",
+    "RequestLimitExceeded",
+    "TooManyRequests",
+    "TooManyRequestsException",
+];
+const INTEGRITY_CODES: [&str; 2] = ["BadDigest", "InvalidDigest"];
+
+impl BearcatError {
+    // Sorts an error from one of the modules by its type. Errors that were
+    // formatted into a string have lost it and count as other.
+    pub fn classify(e: Box<dyn Error>) -> BearcatError {
+        let e = match e.downcast::<BearcatError>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        if e.is::<BlockedFile>() {
+            return BearcatError::PolicyRejection(e.to_string());
+        }
+        if e.is::<io::Error>() {
+            return BearcatError::LocalIo(e.to_string());
+        }
+        BearcatError::Other(e.to_string())
+    }
+
+    // Sorts the error of a rusoto call by its variant, by the code of the
+    // service error and, for responses rusoto couldn't parse, by their status
+    // and the code in their body
+    pub fn from_rusoto<D, E>(context: D, e: RusotoError<E>) -> BearcatError
+    where
+        D: fmt::Display,
+        E: fmt::Debug,
+    {
+        let message = format!("{}: {:?}", context, e);
+        match e {
+            RusotoError::Credentials(_) => BearcatError::Credentials(message),
+            RusotoError::Service(e) => BearcatError::from_code(&service_code(&e), message),
+            RusotoError::Unknown(res) => {
+                let body = String::from_utf8_lossy(&res.body);
+                BearcatError::from_status(res.status.as_u16(), response_code(&body).unwrap_or(""), message)
+            }
+            _ => BearcatError::Other(message),
+        }
+    }
+
+    fn from_status(status: u16, code: &str, message: String) -> BearcatError {
+        match status {
+            // S3 turns down unknown keys and bad signatures with a 403 as well
+            401 | 403 if CREDENTIALS_CODES.contains(&code) => BearcatError::Credentials(message),
+            401 => BearcatError::Credentials(message),
+            403 => BearcatError::AccessDenied(message),
+            404 => BearcatError::NotFound(message),
+            429 | 503 => BearcatError::Throttled(message),
+            _ => BearcatError::from_code(code, message),
+        }
+    }
+
+    fn from_code(code: &str, message: String) -> BearcatError {
+        if CREDENTIALS_CODES.contains(&code) {
+            BearcatError::Credentials(message)
+        } else if ACCESS_DENIED_CODES.contains(&code) {
+            BearcatError::AccessDenied(message)
+        } else if NOT_FOUND_CODES.contains(&code) {
+            BearcatError::NotFound(message)
+        } else if THROTTLED_CODES.contains(&code) {
+            BearcatError::Throttled(message)
+        } else if INTEGRITY_CODES.contains(&code) {
+            BearcatError::Integrity(message)
+        } else {
+            BearcatError::Other(message)
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BearcatError::Config(_) => "invalid config",
+            BearcatError::Credentials(_) => "credentials",
+            BearcatError::AccessDenied(_) => "access denied",
+            BearcatError::NotFound(_) => "not found",
+            BearcatError::Throttled(_) => "throttled",
+            BearcatError::Integrity(_) => "integrity",
+            BearcatError::PolicyRejection(_) => "policy rejection",
+            BearcatError::LocalIo(_) => "local I/O",
+            BearcatError::Other(_) => "other",
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BearcatError::Other(_) => 1,
+            BearcatError::Config(_) => 2,
+            BearcatError::Credentials(_) => 3,
+            BearcatError::AccessDenied(_) => 4,
+            BearcatError::NotFound(_) => 5,
+            BearcatError::Throttled(_) => 6,
+            BearcatError::Integrity(_) => 7,
+            BearcatError::PolicyRejection(_) => 8,
+            BearcatError::LocalIo(_) => 9,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            BearcatError::Config(m)
+            | BearcatError::Credentials(m)
+            | BearcatError::AccessDenied(m)
+            | BearcatError::NotFound(m)
+            | BearcatError::Throttled(m)
+            | BearcatError::Integrity(m)
+            | BearcatError::PolicyRejection(m)
+            | BearcatError::LocalIo(m)
+            | BearcatError::Other(m) => m,
+        }
+    }
+}
+
+// Rusoto names the variants of its service errors after their codes, like
+// `NoSuchKey("...")`
+fn service_code<E: fmt::Debug>(e: &E) -> String {
+    let debug = format!("{:?}", e);
+    debug
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .next()
+        .unwrap_or("")
+        .to_owned()
+}
+
+// The <Code> of an error response rusoto couldn't parse
+fn response_code(body: &str) -> Option<&str> {
+    let start = body.find("<Code>")? + "<Code>".len();
+    let end = body[start..].find("</Code>")? + start;
+    Some(&body[start..end])
+}
+
+impl fmt::Display for BearcatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl Error for BearcatError {}
+
+// Adds context to the errors the modules return, classifying them on the way
+// into a failure chain
+pub trait Classify<T> {
+    fn classify<D>(self, context: D) -> Result<T, failure::Error>
+    where
+        D: fmt::Display + Send + Sync + 'static;
+}
+
+impl<T, E: Into<Box<dyn Error>>> Classify<T> for Result<T, E> {
+    fn classify<D>(self, context: D) -> Result<T, failure::Error>
+    where
+        D: fmt::Display + Send + Sync + 'static,
+    {
+        self.map_err(|e| in_context(e.into(), context))
+    }
+}
+
+pub fn in_context<D>(e: Box<dyn Error>, context: D) -> failure::Error
+where
+    D: fmt::Display + Send + Sync + 'static,
+{
+    failure::Error::from(BearcatError::classify(e)).context(context).into()
+}
+
+// The BearcatError at the bottom of a chain, errors from elsewhere count as other
+pub fn error_kind(e: &failure::Error) -> BearcatError {
+    e.iter_chain()
+        .filter_map(|cause| cause.downcast_ref::<BearcatError>())
+        .last()
+        .cloned()
+        .unwrap_or_else(|| BearcatError::Other(e.to_string()))
+}
+
+// The messages of the chain from the outermost context in
+pub fn describe(e: &failure::Error) -> String {
+    e.iter_chain().map(|cause| cause.to_string()).collect::<Vec<_>>().join(": ")
+}
+
+// What the job does when an object can't be synced
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FailurePolicy {
+    // Carry on with the other objects instead of stopping the run
+    pub continue_on_error: bool,
+}
+
+impl FailurePolicy {
+    // Reads the `failures` section of the job config:
+    //
+    //   failures:
+    //     on_error: continue    # or stop, the default
+    pub fn from_yaml(yaml: &Yaml) -> Result<FailurePolicy, String> {
+        let continue_on_error = match yaml["on_error"].as_str() {
+            Some("continue") => true,
+            Some("stop") => false,
+            None if yaml["on_error"].is_badvalue() => false,
+            _ => return Err("failures.on_error must be stop or continue".to_owned()),
+        };
+        Ok(FailurePolicy { continue_on_error })
+    }
+}
+
+// How an object that didn't fail was handled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Synced,
+    // Left for a later run, like archived objects that are being restored
+    Skipped,
+    // Stopped by the release rules, the content checks or quarantine
+    Rejected,
+}
+
+// Tally of a sync run, reported once it is over
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    pub synced: usize,
+    pub skipped: usize,
+    pub rejected: usize,
+    // Failures that didn't belong to an object have no key
+    pub failures: Vec<(Option<String>, failure::Error)>,
+}
+
+impl RunSummary {
+    pub fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Synced => self.synced += 1,
+            Outcome::Skipped => self.skipped += 1,
+            Outcome::Rejected => self.rejected += 1,
+        }
+    }
+
+    pub fn fail(&mut self, key: Option<&str>, e: failure::Error) {
+        error!(target: "SYNC", "{} ({})", describe(&e), error_kind(&e).kind());
+        self.failures.push((key.map(str::to_owned), e));
+    }
+
+    pub fn report(&self) {
+        info!(
+            target: "SUMMARY",
+            "{} synced, {} skipped, {} rejected, {} failed",
+            self.synced,
+            self.skipped,
+            self.rejected,
+            self.failures.len()
+        );
+        for (key, e) in &self.failures {
+            let kind = error_kind(e);
+            match key {
+                Some(key) => error!(target: "SUMMARY", "{:?} failed ({}): {}", key, kind.kind(), describe(e)),
+                None => error!(target: "SUMMARY", "The run failed ({}): {}", kind.kind(), describe(e)),
+            }
+        }
+    }
+
+    // 0 when nothing failed, the failures' own code when they are all of one
+    // kind and 1 for a mix
+    pub fn exit_code(&self) -> i32 {
+        let mut codes = self.failures.iter().map(|(_, e)| error_kind(e).exit_code());
+        match codes.next() {
+            None => 0,
+            Some(first) if codes.all(|code| code == first) => first,
+            Some(_) => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn classify(message: &str) -> BearcatError {
+        BearcatError::classify(message.into())
+    }
+
+    // A service error the way rusoto generates them
+    #[derive(Debug)]
+    enum GetError {
+        NoSuchKey(String),
+        InvalidObjectState(String),
+    }
+
+    #[test]
+    fn classify_test() {
+        // Messages are never searched, they hold user supplied keys
+        assert_eq!(classify("Error getting \"AccessDenied/NoSuchKey\" from \"b\"").kind(), "other");
+        assert_eq!(classify("Failed to upload part: status: 403").kind(), "other");
+        assert_eq!(classify("something else").kind(), "other");
+
+        let blocked = BlockedFile { key: "k".to_owned(), reason: "infected".to_owned() };
+        assert_eq!(BearcatError::classify(Box::new(blocked)).kind(), "policy rejection");
+        let io_error = io::Error::new(io::ErrorKind::Other, "disk full");
+        assert_eq!(BearcatError::classify(Box::new(io_error)).kind(), "local I/O");
+        // Typed errors keep their kind whatever they say
+        let typed = BearcatError::Throttled("AccessDenied".to_owned());
+        assert_eq!(BearcatError::classify(Box::new(typed.clone())), typed);
+    }
+
+    #[test]
+    fn from_rusoto_test() {
+        let expired = RusotoError::<String>::Credentials(rusoto_core::CredentialsError::new("expired"));
+        assert_eq!(BearcatError::from_rusoto("Error listing", expired).kind(), "credentials");
+        // Service errors are sorted by their variant
+        let missing = RusotoError::Service(GetError::NoSuchKey("SlowDown".to_owned()));
+        let e = BearcatError::from_rusoto("Error getting \"k\"", missing);
+        assert_eq!(
+            e,
+            BearcatError::NotFound("Error getting \"k\": Service(NoSuchKey(\"SlowDown\"))".to_owned())
+        );
+        let archived = RusotoError::Service(GetError::InvalidObjectState("NoSuchKey".to_owned()));
+        assert_eq!(BearcatError::from_rusoto("Error getting \"NoSuchKey\"", archived).kind(), "other");
+        let invalid = RusotoError::<GetError>::Validation("AccessDenied".to_owned());
+        assert_eq!(BearcatError::from_rusoto("Error", invalid).kind(), "other");
+
+        let status = |status: u16, body: &str| {
+            BearcatError::from_status(status, response_code(body).unwrap_or(""), "m".to_owned()).kind()
+        };
+        assert_eq!(status(403, "<Error><Code>AccessDenied</Code></Error>"), "access denied");
+        // Nothing in the body is needed to tell what the status means
+        assert_eq!(status(403, ""), "access denied");
+        assert_eq!(status(403, "<Code>SignatureDoesNotMatch</Code>"), "credentials");
+        assert_eq!(status(401, ""), "credentials");
+        assert_eq!(status(404, ""), "not found");
+        assert_eq!(status(503, "<Code>SlowDown</Code>"), "throttled");
+        assert_eq!(status(400, "<Code>BadDigest</Code>"), "integrity");
+        assert_eq!(status(400, "<Code>RequestTimeout</Code>"), "other");
+        // Codes have to match whole, a key in the message doesn't count
+        assert_eq!(status(500, "<Key>NoSuchKey</Key><Code>InternalError</Code>"), "other");
+        assert_eq!(status(500, ""), "other");
+    }
+
+    #[test]
+    fn context_chain_test() {
+        let denied = BearcatError::AccessDenied("Failed to upload part: status: 403".to_owned());
+        let result: Result<(), Box<dyn Error>> = Err(denied.into());
+        let e = result.classify("Upload of \"k\" failed").unwrap_err();
+        assert_eq!(error_kind(&e).exit_code(), 4);
+        assert_eq!(describe(&e), "Upload of \"k\" failed: Failed to upload part: status: 403");
+        let e = failure::Error::from(e.context("The run failed"));
+        assert_eq!(error_kind(&e).kind(), "access denied");
+    }
+
+    #[test]
+    fn summary_test() {
+        let failed = |e: BearcatError| Err::<(), _>(e).classify("context").unwrap_err();
+        let mut summary = RunSummary::default();
+        summary.add(Outcome::Synced);
+        summary.add(Outcome::Rejected);
+        assert_eq!(summary.exit_code(), 0);
+        summary.fail(Some("a"), failed(BearcatError::NotFound("NoSuchKey".to_owned())));
+        summary.fail(Some("b"), failed(BearcatError::NotFound("NoSuchBucket".to_owned())));
+        assert_eq!(summary.exit_code(), 5);
+        summary.fail(None, failed(BearcatError::Integrity("Integrity check failed".to_owned())));
+        assert_eq!(summary.exit_code(), 1);
+        assert_eq!((summary.synced, summary.rejected, summary.failures.len()), (1, 1, 3));
+    }
+
+    #[test]
+    fn failure_policy_test() {
+        let doc = |content: &str| YamlLoader::load_from_str(content).unwrap().remove(0);
+        assert_eq!(FailurePolicy::from_yaml(&doc("other: 1")["failures"]).unwrap(), FailurePolicy::default());
+        assert_eq!(FailurePolicy::from_yaml(&doc("on_error: continue")).unwrap().continue_on_error, true);
+        assert_eq!(FailurePolicy::from_yaml(&doc("on_error: stop")).unwrap().continue_on_error, false);
+        assert_eq!(FailurePolicy::from_yaml(&doc("on_error: maybe")).is_err(), true);
+    }
+}
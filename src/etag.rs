@@ -0,0 +1,293 @@
+use crate::objects::FileParts;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+const MIB: u64 = 1048576;
+
+// Part sizes used by common S3 clients (bearcat, aws cli, s3cmd, boto and the
+// consoles), tried when inferring how a multipart object was uploaded
+const COMMON_PART_SIZES: [u64; 10] = [
+    5 * MIB,
+    8 * MIB,
+    15 * MIB,
+    16 * MIB,
+    32 * MIB,
+    64 * MIB,
+    100 * MIB,
+    128 * MIB,
+    512 * MIB,
+    1024 * MIB,
+];
+
+// An S3 ETag split into its md5 digest and, for multipart uploads, the part count
+#[derive(Debug, PartialEq)]
+pub struct ETag {
+    pub digest: String,
+    pub parts: Option<usize>,
+}
+
+// Result of comparing local content with an ETag
+#[derive(Debug, PartialEq)]
+pub enum ETagMatch {
+    Match,
+    Mismatch,
+    // The ETag isn't md5 based, or is a multipart ETag that none of the
+    // likely part sizes reproduce
+    Unknown,
+}
+
+impl ETag {
+    // Parses ETags like "9b2cf535f27731c974343645a3985328" and
+    // "e0b805b5969df6c2c7dff6038d960d52-8". ETags that aren't md5 based
+    // (SSE-KMS and SSE-C objects for example) return None.
+    pub fn parse(e_tag: &str) -> Option<ETag> {
+        let e_tag = normalize_etag(e_tag);
+        let mut split = e_tag.splitn(2, '-');
+        let digest = split.next()?;
+        if digest.len() != 32 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let parts = match split.next() {
+            Some(count) => Some(count.parse::<usize>().ok().filter(|c| *c > 0)?),
+            None => None,
+        };
+        Some(ETag {
+            digest: digest.to_ascii_lowercase(),
+            parts,
+        })
+    }
+}
+
+// S3 returns ETags wrapped in double quotes
+pub fn normalize_etag(e_tag: &str) -> &str {
+    e_tag.trim_matches('"')
+}
+
+// The ETag S3 assigns to a completed multipart upload is the md5 of the
+// concatenated part digests, followed by the number of parts
+pub fn multipart_etag(part_digests: &[md5::Digest]) -> String {
+    let mut context = md5::Context::new();
+    for digest in part_digests {
+        context.consume(digest.0);
+    }
+    format!("{:x}-{}", context.compute(), part_digests.len())
+}
+
+// The md5 digest in a single part ETag, like the ones UploadPart and
+// UploadPartCopy return
+pub fn part_digest(e_tag: &str) -> Option<md5::Digest> {
+    let e_tag = ETag::parse(e_tag).filter(|e_tag| e_tag.parts.is_none())?;
+    let mut digest = [0u8; 16];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&e_tag.digest[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(md5::Digest(digest))
+}
+
+// Multipart ETag of in memory content uploaded with the given part size
+#[cfg(test)]
+pub fn content_etag(content: &[u8], part_size: usize) -> String {
+    let digests: Vec<md5::Digest> = content.chunks(part_size).map(md5::compute).collect();
+    multipart_etag(&digests)
+}
+
+// Multipart ETag of content read from the reader, uploaded with the given part size
+pub fn reader_etag<R: Read>(reader: R, part_size: usize) -> io::Result<String> {
+    let mut digests = Vec::new();
+    for part in FileParts::new(reader, part_size) {
+        digests.push(md5::compute(&part?.body));
+    }
+    Ok(multipart_etag(&digests))
+}
+
+// Single part ETag of content read from the reader, which is its md5
+pub fn reader_md5<R: Read>(mut file: R) -> io::Result<String> {
+    let mut context = md5::Context::new();
+    let mut buffer = vec![0; 65536];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        context.consume(&buffer[..read]);
+    }
+    Ok(format!("{:x}", context.compute()))
+}
+
+// Part sizes that split an object of the given size into exactly the given
+// number of parts. The common client defaults are tried first, followed by
+// the smallest MiB aligned size and the smallest exact size that fit.
+pub fn candidate_part_sizes(object_size: u64, parts: usize) -> Vec<usize> {
+    let parts = parts as u64;
+    if parts == 0 || object_size < parts {
+        return Vec::new();
+    }
+    let splits_into = |size: u64| size > 0 && (object_size + size - 1) / size == parts;
+    let smallest = (object_size + parts - 1) / parts;
+    let smallest_aligned = (smallest + MIB - 1) / MIB * MIB;
+    let mut sizes: Vec<usize> = Vec::new();
+    for size in COMMON_PART_SIZES
+        .iter()
+        .cloned()
+        .chain(vec![smallest_aligned, smallest])
+    {
+        if splits_into(size) && !sizes.contains(&(size as usize)) {
+            sizes.push(size as usize);
+        }
+    }
+    sizes
+}
+
+// Compares content of the given size with an ETag, inferring the part size
+// for multipart ETags. The content is read again from `open` for each part
+// size tried, so it never has to be held in memory.
+pub fn reader_matches_etag<R, F>(open: F, size: u64, e_tag: &str) -> io::Result<ETagMatch>
+where
+    R: Read,
+    F: Fn() -> io::Result<R>,
+{
+    let e_tag = match ETag::parse(e_tag) {
+        Some(e_tag) => e_tag,
+        None => return Ok(ETagMatch::Unknown),
+    };
+    match e_tag.parts {
+        None => {
+            if reader_md5(open()?)? == e_tag.digest {
+                Ok(ETagMatch::Match)
+            } else {
+                Ok(ETagMatch::Mismatch)
+            }
+        }
+        Some(parts) => {
+            let expected = format!("{}-{}", e_tag.digest, parts);
+            for part_size in candidate_part_sizes(size, parts) {
+                if reader_etag(open()?, part_size)? == expected {
+                    return Ok(ETagMatch::Match);
+                }
+            }
+            Ok(ETagMatch::Unknown)
+        }
+    }
+}
+
+// Compares a file with an ETag, inferring the part size for multipart ETags
+pub fn file_matches_etag(path: &str, e_tag: &str) -> io::Result<ETagMatch> {
+    reader_matches_etag(|| File::open(path), std::fs::metadata(path)?.len(), e_tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    static TEST_DIR: &str = "file_tests";
+
+    fn test_content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn parse_etag_test() {
+        assert_eq!(
+            ETag::parse("\"e0b805b5969df6c2c7dff6038d960d52-8\""),
+            Some(ETag {
+                digest: "e0b805b5969df6c2c7dff6038d960d52".to_owned(),
+                parts: Some(8),
+            })
+        );
+        assert_eq!(
+            ETag::parse("974E55BC9765D6A86393B0B094A969EA"),
+            Some(ETag {
+                digest: "974e55bc9765d6a86393b0b094a969ea".to_owned(),
+                parts: None,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_invalid_etag_test() {
+        assert_eq!(ETag::parse(""), None);
+        assert_eq!(ETag::parse("\"not-an-etag\""), None);
+        assert_eq!(ETag::parse("e0b805b5969df6c2c7dff6038d960d52-"), None);
+        assert_eq!(ETag::parse("e0b805b5969df6c2c7dff6038d960d52-0"), None);
+        assert_eq!(ETag::parse("e0b805b5969df6c2c7dff6038d960d5z"), None);
+    }
+
+    #[test]
+    fn normalize_etag_test() {
+        assert_eq!(normalize_etag("\"abc-2\""), "abc-2");
+        assert_eq!(normalize_etag("abc"), "abc");
+    }
+
+    #[test]
+    fn multipart_etag_single_part_test() {
+        let digest = md5::compute(b"bearcat");
+        let expected = format!("{:x}-1", md5::compute(&digest.0));
+        assert_eq!(multipart_etag(&[digest]), expected);
+    }
+
+    #[test]
+    fn part_digest_test() {
+        let digest = md5::compute(b"bearcat");
+        assert_eq!(part_digest(&format!("\"{:x}\"", digest)), Some(digest));
+        assert_eq!(part_digest(&format!("{:x}-2", digest)), None);
+        assert_eq!(part_digest("not an md5"), None);
+    }
+
+    #[test]
+    fn candidate_part_sizes_test() {
+        // The go1.12.5.linux-amd64.tar.gz object from resources/list_bucket_response
+        let sizes = candidate_part_sizes(127938445, 8);
+        assert_eq!(sizes[0], 16 * MIB as usize);
+        for size in sizes {
+            assert_eq!((127938445 + size - 1) / size, 8);
+        }
+        assert_eq!(candidate_part_sizes(10, 0).is_empty(), true);
+        assert_eq!(candidate_part_sizes(3, 4).is_empty(), true);
+    }
+
+    #[test]
+    fn content_etag_test() {
+        let content = test_content(10);
+        let expected = multipart_etag(&[
+            md5::compute(&content[..4]),
+            md5::compute(&content[4..8]),
+            md5::compute(&content[8..]),
+        ]);
+        assert_eq!(content_etag(&content, 4), expected);
+        assert_eq!(expected.ends_with("-3"), true);
+    }
+
+    #[test]
+    fn reader_matches_etag_test() {
+        let matches = |content: &[u8], e_tag: &str| {
+            reader_matches_etag(|| Ok(content), content.len() as u64, e_tag).unwrap()
+        };
+        let content = test_content(3 * MIB as usize);
+        let single = format!("\"{:x}\"", md5::compute(&content));
+        assert_eq!(matches(&content, &single), ETagMatch::Match);
+        assert_eq!(matches(b"other", &single), ETagMatch::Mismatch);
+
+        let multipart = content_etag(&content, MIB as usize);
+        assert_eq!(matches(&content, &multipart), ETagMatch::Match);
+        assert_eq!(matches(&content[1..], &multipart), ETagMatch::Unknown);
+        assert_eq!(matches(&content, "opaque"), ETagMatch::Unknown);
+    }
+
+    #[test]
+    fn file_matches_etag_test() {
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let path = format!("{}/{}", TEST_DIR, "etag_file");
+        let content = test_content(11 * MIB as usize);
+        fs::write(&path, &content).unwrap();
+
+        let multipart = content_etag(&content, 5 * MIB as usize);
+        assert_eq!(reader_etag(File::open(&path).unwrap(), 5 * MIB as usize).unwrap(), multipart);
+        assert_eq!(file_matches_etag(&path, &multipart).unwrap(), ETagMatch::Match);
+
+        let single = format!("{:x}", md5::compute(&content));
+        assert_eq!(reader_md5(File::open(&path).unwrap()).unwrap(), single);
+        assert_eq!(file_matches_etag(&path, &single).unwrap(), ETagMatch::Match);
+    }
+}
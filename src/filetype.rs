@@ -0,0 +1,300 @@
+use crate::config::yaml_string_list;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::str::FromStr;
+use yaml_rust::Yaml;
+
+// Enough of the file to find every signature, the tar one is at offset 257
+pub const SNIFF_LENGTH: usize = 512;
+
+// Content types S3 and most clients use when they don't know better, these
+// fit any file type
+const GENERIC_CONTENT_TYPES: [&str; 2] = ["application/octet-stream", "binary/octet-stream"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    TarGz,
+    Tar,
+    Zip,
+    Rpm,
+    Deb,
+    Qcow2,
+}
+
+impl FileType {
+    // Works out the type from the magic bytes at the start of the file.
+    // Gzip streams are taken to be tarballs, the archive inspection looks inside.
+    pub fn sniff(head: &[u8]) -> Option<FileType> {
+        if head.starts_with(&[0x1f, 0x8b]) {
+            Some(FileType::TarGz)
+        } else if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+            Some(FileType::Zip)
+        } else if head.starts_with(&[0xed, 0xab, 0xee, 0xdb]) {
+            Some(FileType::Rpm)
+        } else if head.starts_with(b"!<arch>\ndebian-binary") {
+            Some(FileType::Deb)
+        } else if head.starts_with(b"QFI\xfb") {
+            Some(FileType::Qcow2)
+        } else if head.len() >= 262 && &head[257..262] == b"ustar" {
+            Some(FileType::Tar)
+        } else {
+            None
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<FileType> {
+        let key = key.to_lowercase();
+        [
+            (".tar.gz", FileType::TarGz),
+            (".tgz", FileType::TarGz),
+            (".tar", FileType::Tar),
+            (".zip", FileType::Zip),
+            (".rpm", FileType::Rpm),
+            (".deb", FileType::Deb),
+            (".qcow2", FileType::Qcow2),
+        ]
+        .iter()
+        .find(|(extension, _)| key.ends_with(extension))
+        .map(|(_, file_type)| *file_type)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FileType::TarGz => "tar.gz",
+            FileType::Tar => "tar",
+            FileType::Zip => "zip",
+            FileType::Rpm => "rpm",
+            FileType::Deb => "deb",
+            FileType::Qcow2 => "qcow2",
+        }
+    }
+
+    fn content_types(self) -> &'static [&'static str] {
+        match self {
+            FileType::TarGz => &[
+                "application/gzip",
+                "application/x-gzip",
+                "application/x-tar+gzip",
+                "application/x-compressed-tar",
+            ],
+            FileType::Tar => &["application/x-tar"],
+            FileType::Zip => &["application/zip", "application/x-zip-compressed"],
+            FileType::Rpm => &["application/x-rpm", "application/x-redhat-package-manager"],
+            FileType::Deb => &[
+                "application/vnd.debian.binary-package",
+                "application/x-debian-package",
+            ],
+            FileType::Qcow2 => &["application/x-qemu-disk", "application/x-qcow2"],
+        }
+    }
+
+    // Parameters like charset are ignored, generic types always match
+    pub fn matches_content_type(self, content_type: &str) -> bool {
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_lowercase();
+        GENERIC_CONTENT_TYPES.contains(&media_type.as_str())
+            || self.content_types().contains(&media_type.as_str())
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for FileType {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<FileType, String> {
+        match name.to_lowercase().trim_start_matches('.') {
+            "tar.gz" | "tgz" => Ok(FileType::TarGz),
+            "tar" => Ok(FileType::Tar),
+            "zip" => Ok(FileType::Zip),
+            "rpm" => Ok(FileType::Rpm),
+            "deb" => Ok(FileType::Deb),
+            "qcow2" => Ok(FileType::Qcow2),
+            _ => Err(format!("unknown file type {:?}", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileTypeConfig {
+    pub enabled: bool,
+    pub allowed: Vec<FileType>,
+}
+
+impl Default for FileTypeConfig {
+    fn default() -> FileTypeConfig {
+        FileTypeConfig {
+            enabled: false,
+            allowed: vec![
+                FileType::TarGz,
+                FileType::Zip,
+                FileType::Rpm,
+                FileType::Deb,
+                FileType::Qcow2,
+            ],
+        }
+    }
+}
+
+impl FileTypeConfig {
+    // Reads the `file_types` section of the job config. The listed types
+    // replace the default allowlist:
+    //
+    //   file_types:
+    //     enabled: true
+    //     allowed: [tar.gz, rpm]
+    pub fn from_yaml(yaml: &Yaml) -> Result<FileTypeConfig, String> {
+        let defaults = FileTypeConfig::default();
+        let enabled = match &yaml["enabled"] {
+            Yaml::Boolean(b) => *b,
+            Yaml::BadValue => defaults.enabled,
+            _ => return Err("file_types.enabled must be true or false".to_owned()),
+        };
+        let allowed = yaml_string_list(&yaml["allowed"], "file_types.allowed")?;
+        let allowed = if allowed.is_empty() {
+            defaults.allowed
+        } else {
+            allowed
+                .iter()
+                .map(|name| name.parse())
+                .collect::<Result<Vec<FileType>, String>>()?
+        };
+        Ok(FileTypeConfig { enabled, allowed })
+    }
+
+    // The sniffed type has to be allowed and agree with both the key's
+    // extension and the Content-Type
+    pub fn check(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+        head: &[u8],
+    ) -> Result<FileType, String> {
+        let sniffed = FileType::sniff(head)
+            .ok_or_else(|| "the content is not a recognised file type".to_owned())?;
+        if !self.allowed.contains(&sniffed) {
+            return Err(format!("{} files are not allowed", sniffed));
+        }
+        match FileType::from_key(key) {
+            Some(extension) if extension == sniffed => {}
+            Some(extension) => {
+                return Err(format!(
+                    "the content is {} but the extension says {}",
+                    sniffed, extension
+                ))
+            }
+            None => {
+                return Err(format!(
+                    "the content is {} but the key has no matching extension",
+                    sniffed
+                ))
+            }
+        }
+        match content_type {
+            Some(content_type) if !sniffed.matches_content_type(content_type) => Err(format!(
+                "the content is {} but the Content-Type is {:?}",
+                sniffed, content_type
+            )),
+            _ => Ok(sniffed),
+        }
+    }
+}
+
+// The first SNIFF_LENGTH bytes of a file, or all of it when it's shorter
+pub fn read_head<R: Read>(reader: R) -> io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(SNIFF_LENGTH);
+    reader.take(SNIFF_LENGTH as u64).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn tar_head() -> Vec<u8> {
+        let mut head = vec![0; SNIFF_LENGTH];
+        head[257..262].copy_from_slice(b"ustar");
+        head
+    }
+
+    fn enabled() -> FileTypeConfig {
+        FileTypeConfig {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sniff_test() {
+        assert_eq!(FileType::sniff(&[0x1f, 0x8b, 0x08, 0x00]), Some(FileType::TarGz));
+        assert_eq!(FileType::sniff(b"PK\x03\x04\x14\x00"), Some(FileType::Zip));
+        assert_eq!(FileType::sniff(&[0xed, 0xab, 0xee, 0xdb, 0x03]), Some(FileType::Rpm));
+        assert_eq!(FileType::sniff(b"!<arch>\ndebian-binary   "), Some(FileType::Deb));
+        assert_eq!(FileType::sniff(b"QFI\xfb\x00\x00\x00\x03"), Some(FileType::Qcow2));
+        assert_eq!(FileType::sniff(&tar_head()), Some(FileType::Tar));
+        assert_eq!(FileType::sniff(b"#!/bin/sh\n"), None);
+        assert_eq!(FileType::sniff(b""), None);
+    }
+
+    #[test]
+    fn from_key_test() {
+        assert_eq!(FileType::from_key("go/go1.12.5.linux-amd64.tar.gz"), Some(FileType::TarGz));
+        assert_eq!(FileType::from_key("images/base.QCOW2"), Some(FileType::Qcow2));
+        assert_eq!(FileType::from_key("backup.tar"), Some(FileType::Tar));
+        assert_eq!(FileType::from_key("install.sh"), None);
+    }
+
+    #[test]
+    fn content_type_test() {
+        assert_eq!(FileType::Zip.matches_content_type("application/zip"), true);
+        assert_eq!(FileType::TarGz.matches_content_type("Application/Gzip; charset=binary"), true);
+        assert_eq!(FileType::Rpm.matches_content_type("binary/octet-stream"), true);
+        assert_eq!(FileType::Deb.matches_content_type("text/plain"), false);
+    }
+
+    #[test]
+    fn check_test() {
+        let config = enabled();
+        let gzip = [0x1f, 0x8b, 0x08, 0x00];
+        assert_eq!(
+            config.check("go.tar.gz", Some("application/gzip"), &gzip),
+            Ok(FileType::TarGz)
+        );
+        assert_eq!(config.check("go.tgz", None, &gzip), Ok(FileType::TarGz));
+        // A renamed executable
+        assert_eq!(config.check("go.tar.gz", None, b"\x7fELF\x02\x01").is_err(), true);
+        assert_eq!(config.check("go.zip", None, &gzip).is_err(), true);
+        assert_eq!(config.check("go", None, &gzip).is_err(), true);
+        assert_eq!(config.check("go.tar.gz", Some("text/html"), &gzip).is_err(), true);
+        // Plain tarballs aren't on the default allowlist
+        assert_eq!(config.check("backup.tar", None, &tar_head()).is_err(), true);
+    }
+
+    #[test]
+    fn file_type_config_test() {
+        let config = |content: &str| {
+            let doc = &YamlLoader::load_from_str(content).unwrap()[0];
+            FileTypeConfig::from_yaml(&doc["file_types"])
+        };
+        assert_eq!(config("other: value"), Ok(FileTypeConfig::default()));
+        assert_eq!(
+            config("file_types:\n  enabled: true\n  allowed: [tar.gz, .rpm, tar]\n"),
+            Ok(FileTypeConfig {
+                enabled: true,
+                allowed: vec![FileType::TarGz, FileType::Rpm, FileType::Tar],
+            })
+        );
+        assert_eq!(config("file_types:\n  allowed: [exe]\n").is_err(), true);
+        assert_eq!(config("file_types:\n  enabled: yes please\n").is_err(), true);
+    }
+}
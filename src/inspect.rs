@@ -0,0 +1,82 @@
+use crate::archive::{inspect_archive, is_archive};
+use crate::config::JobConfig;
+use crate::filetype::{read_head, FileType};
+use crate::objects::ObjectDetails;
+use crate::scan::ScanVerdict;
+use std::error::Error;
+use std::fmt;
+
+// A staged file that failed inspection. The file is removed from staging and
+// the source object is left where it is, unless quarantine holds it instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockedFile {
+    pub key: String,
+    pub reason: String,
+}
+
+impl fmt::Display for BlockedFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} was blocked: {}", self.key, self.reason)
+    }
+}
+
+impl Error for BlockedFile {}
+
+// Whether the job has to look at the content of objects, which rules out
+// server side copies. The quarantine allowlist needs a digest bearcat
+// computed itself, not one copied from the source metadata.
+pub fn inspects_content(job: &JobConfig) -> bool {
+    job.scanner.enabled()
+        || job.file_types.enabled
+        || job.archives.enabled
+        || job.signatures.enabled
+        || (job.quarantine.enabled && job.quarantine.sha256_allowlist.is_some())
+}
+
+// Runs the configured checks against a staged file before it is uploaded.
+// `signature` is the object's companion signature when one was found.
+pub fn inspect_staged_file(
+    staged: &str,
+    details: &ObjectDetails,
+    signature: Option<&[u8]>,
+    job: &JobConfig,
+) -> Result<(), BlockedFile> {
+    let blocked = |reason: String| BlockedFile {
+        key: details.key.clone(),
+        reason,
+    };
+    // Every check reads the plaintext, even when staged files are encrypted
+    let open = || {
+        job.staging
+            .open(staged)
+            .map_err(|e| blocked(format!("could not be read: {}", e)))
+    };
+    if job.signatures.enabled {
+        let signature = signature.ok_or_else(|| blocked("it has no companion signature".to_owned()))?;
+        job.signatures.verify(open()?, signature).map_err(blocked)?;
+    }
+    if job.file_types.enabled || job.archives.enabled {
+        let head = read_head(open()?).map_err(|e| blocked(format!("could not be read: {}", e)))?;
+        if job.file_types.enabled {
+            let content_type = details.headers.content_type.as_ref().map(String::as_str);
+            job.file_types
+                .check(&details.key, content_type, &head)
+                .map_err(blocked)?;
+        }
+        match FileType::sniff(&head) {
+            Some(file_type) if job.archives.enabled && is_archive(file_type) => {
+                inspect_archive(open()?, file_type, &job.archives, &job.file_types)
+                    .map_err(blocked)?
+            }
+            _ => {}
+        }
+    }
+    if !job.scanner.enabled() {
+        return Ok(());
+    }
+    match job.scanner.scan_file(staged, open()?) {
+        Ok(ScanVerdict::Clean) => Ok(()),
+        Ok(ScanVerdict::Infected(signature)) => Err(blocked(format!("infected with {}", signature))),
+        Err(e) => Err(blocked(format!("could not be scanned: {}", e))),
+    }
+}
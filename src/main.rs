@@ -1,13 +1,10 @@
 #[macro_use]
 extern crate clap;
 
-#[macro_use]
 extern crate lazy_static;
 
-#[macro_use]
 extern crate log;
 
-#[macro_use]
 extern crate simplelog;
 
 extern crate regex;
@@ -18,23 +15,67 @@ extern crate rusoto_sts;
 extern crate rusoto_ssm;
 extern crate futures;
 extern crate bytes;
+extern crate md5;
+extern crate sha2;
+extern crate base64;
+extern crate serde;
+extern crate serde_json;
+extern crate csv;
+extern crate rand;
+extern crate yaml_rust;
 
+mod archive;
 mod bucket;
+mod checksum;
+mod config;
 mod credentials;
 mod data;
+mod encryption;
+mod endpoint;
+mod envelope;
+mod error;
+mod etag;
+mod filetype;
+mod inspect;
+mod manifest;
+mod metadata;
+mod objects;
+mod partition;
+mod quarantine;
+mod release;
+mod scan;
+mod signature;
+mod storage;
+mod transfer;
+mod verify;
+mod versions;
 
-use bucket::{download, upload, get_bucket_object_keys};
+use bucket::{bucket_region, can_copy_server_side, copy, download, upload, get_bucket_objects};
 use clap::{App, ArgMatches};
-use credentials::{CredentialProvider};
-use log::{info, warn};
-use std::env;
-use std::fs::File;
+use config::{load_config, JobConfig};
+use endpoint::EndpointConfig;
+use error::{in_context, BearcatError, Classify, Outcome, RunSummary};
+use inspect::{inspects_content, BlockedFile};
+use credentials::{caller_identity_arn, CredentialProvider};
+use log::{error, info, warn};
+use manifest::{publish_manifest, verify_manifest, Manifest};
+use objects::ObjectDetails;
+use data::{staged_keys, staged_path};
+use partition::Partition;
+use quarantine::process_quarantined;
+use release::check_release;
+use signature::sync_signature;
+use std::collections::HashSet;
+use std::error::Error;
 use std::str::FromStr;
 use rusoto_core::region::Region;
-use rusoto_core::request::{HttpClient};
-use rusoto_credential::{ChainProvider, StaticProvider};
-use rusoto_s3::S3Client;
+use rusoto_credential::{AwsCredentials, ChainProvider, StaticProvider};
+use rusoto_s3::{Object, S3Client};
 use simplelog::*;
+use storage::{is_archived, prepare_archived_object, ArchiveStatus, RestoreState};
+use verify::{verify_buckets, KeyFilter, OutputFormat, VerifyOptions};
+use versions::replicate_versions;
+use regex::Regex;
 // Flow of the application
 // Set the AWS profile for the commercial role with bucket access
 // Download the contents of the commercial bucket
@@ -50,31 +91,72 @@ fn main() {
     // Gather the config/app args
     info!(target: "INITIALIZATION", "Parsing cli parameters");
     let matches = app.get_matches();
+    if let Some(verify_matches) = matches.subcommand_matches("verify") {
+        std::process::exit(run_verify(verify_matches));
+    }
+    if let Some(manifest_matches) = matches.subcommand_matches("manifest") {
+        if let Some(verify_matches) = manifest_matches.subcommand_matches("verify") {
+            std::process::exit(run_manifest_verify(verify_matches));
+        }
+    }
+    std::process::exit(run_sync(&matches));
+}
+
+// Runs the sync and returns the process exit code: 0 when nothing failed, the
+// code of the kind of failure when every failure was of one kind and 1 for a
+// mix. Objects that were rejected or left for a later run don't count as
+// failures.
+fn run_sync(matches: &ArgMatches) -> i32 {
+    let mut summary = RunSummary::default();
+    if let Err(e) = sync(matches, &mut summary) {
+        summary.fail(None, e);
+    }
+    summary.report();
+    summary.exit_code()
+}
+
+fn sync(matches: &ArgMatches, summary: &mut RunSummary) -> Result<(), failure::Error> {
     let profile = matches.value_of("profile"); // Get rid of this option
     let ssm_key = matches.value_of("ssm_key");
     let source_bucket = matches.value_of("source");
     let destination_bucket = matches.value_of("destination");
-    let source_region = matches.value_of("region_source").unwrap_or("us-west-2");
-    let destination_region = matches
-        .value_of("region_destination")
-        .unwrap_or("us-west-2");
 
-    if let Some(config) = matches.value_of("config") {
-        info!(target: "ARGUMENTS", "Using the config file: {}", config)
-    } else {
-        info!(target: "ARGUMENTS", "No config file found. Using the defaults")
+    let mut job = load_config(matches.value_of("config"))
+        .map_err(|e| BearcatError::Config(format!("Could not load the config file: {}", e)))?;
+    if let Some(staging_dir) = matches.value_of("staging_dir") {
+        job.staging.path = staging_dir.trim_end_matches('/').to_owned();
+    }
+    info!(target: "ARGUMENTS", "Staging objects in {:?}", job.staging.path);
+    if job.failures.continue_on_error {
+        info!(target: "ARGUMENTS", "Objects that fail are reported at the end of the run");
     }
 
     // These can be simplified as well
-    match source_bucket {
-        Some(p) => info!(target: "ARGUMENTS", "You provided the source bucket, {}", p),
-        None => panic!("No source bucket provided. You need to specify a source bucket"),
-    }
+    let source_bucket = match source_bucket {
+        Some(p) => {
+            info!(target: "ARGUMENTS", "You provided the source bucket, {}", p);
+            p
+        }
+        None => {
+            return Err(BearcatError::Config(
+                "No source bucket provided. You need to specify a source bucket".to_owned(),
+            )
+            .into())
+        }
+    };
 
-    match destination_bucket {
-        Some(p) => info!(target: "ARGUMENTS", "You provided the destination bucket, {}", p),
-        None => panic!("No destination bucket provided. You need to specify a source bucket"),
-    }
+    let destination_bucket = match destination_bucket {
+        Some(p) => {
+            info!(target: "ARGUMENTS", "You provided the destination bucket, {}", p);
+            p
+        }
+        None => {
+            return Err(BearcatError::Config(
+                "No destination bucket provided. You need to specify a destination bucket".to_owned(),
+            )
+            .into())
+        }
+    };
 
     match profile {
         Some(p) => info!(target: "ARGUMENTS", "You provided the profile, {}", p),
@@ -83,71 +165,655 @@ fn main() {
             "No profile provided. Falling back to using IAM default"
         ),
     }
-    match ssm_key {
-        Some(p) => info!(target: "ARGUMENTS", "You provided the ssm key, {}", p),
-        None => info!(target:
-            "ARGUMENTS",
-            "No ssm key provided. Falling back to using IAM default"
-        ),
-    }
+    // The destination credentials always come from ssm for now
+    let ssm_key = match ssm_key {
+        Some(p) => {
+            info!(target: "ARGUMENTS", "You provided the ssm key, {}", p);
+            p
+        }
+        None => {
+            return Err(BearcatError::Config(
+                "No ssm key provided. You need to specify the ssm key of the destination credentials".to_owned(),
+            )
+            .into())
+        }
+    };
 
-    ////// MOVE/BREAK THIS OUT ////////////
+    // Regions that aren't given are looked up from the buckets, which only
+    // needs to know the partition each bucket is in
+    let (source_partition, destination_partition) = match (
+        matches.value_of("source_partition").unwrap_or("aws").parse::<Partition>(),
+        matches.value_of("destination_partition").unwrap_or("aws").parse::<Partition>(),
+    ) {
+        (Ok(source), Ok(destination)) => (source, destination),
+        (Err(e), _) | (_, Err(e)) => {
+            return Err(BearcatError::Config(format!("Invalid partition: {}", e)).into())
+        }
+    };
+    let source_region = resolve_region(
+        matches.value_of("region_source"),
+        source_partition,
+        source_bucket,
+        &job.endpoints.source,
+        |region| source_client(&job.endpoints.source, region),
+    )
+    .classify("Could not find the region of the source bucket")?;
+    let source_region = source_region.as_str();
     // Use the chain provider credentials for access to ssm
     // This will move to using just the containerprovider credentials provider later
-    info!(target: "CRED EVENTS", "Creating the credentials provider");
-    let cred_prov = CredentialProvider::AwsSsm {
-        key: ssm_key.unwrap().to_owned(),
-        region: Region::from_str(&source_region).unwrap(),
-    };
-    let sync_creds = cred_prov.get_credentials().unwrap();
-    info!(target: "CRED EVENTS", "Sync Creds:\n{:?}", &sync_creds);
+    let credentials = destination_credentials(ssm_key, source_region)
+        .map_err(|e| BearcatError::Credentials(format!("Could not get the destination credentials from ssm: {}", e)))?;
+    let destination_region = resolve_region(
+        matches.value_of("region_destination"),
+        destination_partition,
+        destination_bucket,
+        &job.endpoints.destination,
+        |region| destination_client(&job.endpoints.destination, &credentials, region),
+    )
+    .classify("Could not find the region of the destination bucket")?;
+    let destination_region = destination_region.as_str();
+    info!(target: "ARGUMENTS", "Syncing from {} to {}", source_region, destination_region);
+    check_partitions(&job, source_region, destination_region, &credentials)
+        .classify("Could not check the transfer against the partition policy")?;
+
     // Upload the artifact from the local machine to the destination bucket
-    info!(target: "UPLOAD CLIENT", "Creating upload client...");
-    let upload_client = rusoto_s3::S3Client::new_with(
-        HttpClient::new().unwrap(),
-        StaticProvider::from(sync_creds),
-        Region::from_str(&destination_region).unwrap(),
-    );
-    info!(target: "UPLOAD CLIENT", "Upload client created successfully");
+    let destination_endpoint = job
+        .endpoints
+        .destination
+        .region(destination_region)
+        .map_err(|e| BearcatError::Config(format!("Invalid destination endpoint: {}", e)))?;
+    let upload_client = destination_client(&job.endpoints.destination, &credentials, destination_endpoint)
+        .map_err(|e| BearcatError::Config(format!("Could not create the destination client: {}", e)))?;
 
     // The path, region, etc... will come from environment variables, command line args or can be
     // parsed out of a config file if that is present.
-    info!(target: "DOWNLOAD CLIENT", "Creating download client...");
-    let client = rusoto_s3::S3Client::new_with(
-        HttpClient::new().unwrap(),
-        ChainProvider::new(),
-        Region::from_str(&source_region).unwrap(),
-    );
-    info!(target: "DOWNLOAD CLIENT", "Download client created successfully");
+    let source_endpoint = job
+        .endpoints
+        .source
+        .region(source_region)
+        .map_err(|e| BearcatError::Config(format!("Invalid source endpoint: {}", e)))?;
+    let client = source_client(&job.endpoints.source, source_endpoint)
+        .map_err(|e| BearcatError::Config(format!("Could not create the source client: {}", e)))?;
+    // Within a partition the objects can be copied without leaving S3, unless
+    // their content has to be inspected or encrypted on the way
+    let server_side = job.transfer.server_side_copy
+        && job.endpoints.same_service()
+        && !inspects_content(&job)
+        && !job.client_encryption.enabled()
+        && can_copy_server_side(
+            &upload_client,
+            source_bucket,
+            Partition::from_region(source_region),
+            Partition::from_region(destination_region),
+        );
+    if server_side {
+        info!(target: "COPY", "Using server side copies between the buckets");
+    }
+    // Every object that reaches its final key is listed in the run's manifest
+    let mut manifest = Manifest::new(source_bucket, destination_bucket);
+    // Versioned jobs replay the full history of the source bucket instead
+    if job.versions.enabled {
+        let replicated = replicate_versions(
+            &client,
+            source_bucket,
+            &upload_client,
+            destination_bucket,
+            server_side,
+            &job,
+            &mut manifest,
+            summary,
+        );
+        if let Err(e) = replicated {
+            summary.fail(None, e);
+        }
+        return finish_manifest(&upload_client, &mut manifest, &job);
+    }
     // If there are objects in the bucket then get all of the objects and
     // sync them over to the destination bucket
-    if let Ok(file_names) = get_bucket_object_keys(&client, source_bucket.unwrap()) {
-        for file_name in file_names {
-            // Download the artifact from the source S3 bucket
-            let download_result = download(&client, &file_name, source_bucket.unwrap());
-            match download_result {
-                Ok(res) => info!(
+    let mut restores = RestoreState::load(&job.restore.state_file).classify("Could not load the restore state")?;
+    let objects = get_bucket_objects(&client, source_bucket)
+        .classify(format!("Could not list the objects in {:?}", source_bucket))?;
+    report_stranded(&objects, &job).classify("Could not read the staging directory")?;
+    for object in objects {
+        let file_name = object.key.unwrap_or_default();
+        // Signatures move with the objects they sign
+        if job.signatures.is_signature(&file_name) {
+            continue;
+        }
+        let synced = sync_object(
+            &client,
+            source_bucket,
+            &upload_client,
+            destination_bucket,
+            &file_name,
+            object.storage_class.as_ref().map(String::as_str),
+            server_side,
+            &job,
+            &mut manifest,
+            &mut restores,
+        );
+        match synced {
+            Ok(outcome) => summary.add(outcome),
+            Err(e) => {
+                summary.fail(Some(&file_name), e);
+                // The manifest still lists the objects synced before the failure
+                if !job.failures.continue_on_error {
+                    break;
+                }
+            }
+        }
+    }
+    finish_manifest(&upload_client, &mut manifest, &job)
+}
+
+// Moves one object from the source bucket to the destination
+#[allow(clippy::too_many_arguments)]
+fn sync_object(
+    client: &S3Client,
+    source_bucket: &str,
+    upload_client: &S3Client,
+    destination_bucket: &str,
+    file_name: &str,
+    storage_class: Option<&str>,
+    server_side: bool,
+    job: &JobConfig,
+    manifest: &mut Manifest,
+    restores: &mut RestoreState,
+) -> Result<Outcome, failure::Error> {
+    // Objects the release policy rejects stay in the source bucket
+    let release = check_release(
+        client,
+        source_bucket,
+        file_name,
+        None,
+        &job.release,
+        job.encryption.source_customer_key.as_ref(),
+    )
+    .classify(format!("Release check of {:?} failed", file_name))?;
+    if let Err(reason) = release {
+        warn!(target: "RELEASE", "Rejected {:?}: {}", file_name, reason);
+        return Ok(Outcome::Rejected);
+    }
+    // Archived objects can only be downloaded once they have been restored
+    if is_archived(storage_class) {
+        let status = prepare_archived_object(
+            client,
+            source_bucket,
+            file_name,
+            None,
+            &job.restore,
+            job.encryption.source_customer_key.as_ref(),
+            restores,
+        )
+        .classify(format!("Restore of {:?} failed", file_name))?;
+        match status {
+            ArchiveStatus::Available => {}
+            ArchiveStatus::Pending => {
+                restores.save(&job.restore.state_file).classify("Could not save the restore state")?;
+                return Ok(Outcome::Skipped);
+            }
+            status => {
+                info!(target: "RESTORE", "Skipping {:?} for now: {:?}", file_name, status);
+                return Ok(Outcome::Skipped);
+            }
+        }
+    }
+    // With quarantine enabled objects are written to the quarantine
+    // location first and promoted once they pass their checks
+    let (upload_bucket, upload_key) = job.quarantine.location(destination_bucket, file_name);
+    let promoted = if server_side {
+        let res = copy(
+            upload_client,
+            client,
+            file_name,
+            source_bucket,
+            None,
+            &upload_bucket,
+            &upload_key,
+            job,
+        )
+        .classify(format!("Could not copy {:?}", file_name))?;
+        info!(target: "COPY", "Copy was successful!\n{:?}", res);
+        let promoted = promote(upload_client, destination_bucket, file_name, None, job)?;
+        if promoted {
+            record(upload_client, manifest, file_name, None, job)?;
+        }
+        promoted
+    } else {
+        // Download the artifact from the source S3 bucket
+        let source_object = match download(client, file_name, source_bucket, job) {
+            Ok(res) => {
+                info!(
                     target:
                     "S3 DOWNLOAD",
                     "Download of {:?} completed successfully!\n{:?}", file_name, res
-                ),
-                Err(e) => panic!(format!(
-                    "Download of {:?} failed with error:\n{:?}",
-                    file_name, e
-                )),
+                );
+                res
+            }
+            // Without quarantine blocked objects stay in the source bucket
+            Err(e) if e.is::<BlockedFile>() => {
+                error!(target: "INSPECTION", "{}", e);
+                return Ok(Outcome::Rejected);
             }
+            Err(e) => return Err(in_context(e, format!("Download of {:?} failed", file_name))),
+        };
 
-            let upload_result = upload(
-                &upload_client,
-                "data",
-                &file_name,
-                destination_bucket.unwrap(),
-            );
-            match upload_result {
-                Ok(res) => info!(target:"UPLOAD", "Upload was successful!\n{:?}", res),
-                Err(e) => panic!(format!("Could not upload artifact...\nError:\n{:?}", e)),
+        let res = upload(
+            upload_client,
+            &job.staging.path,
+            file_name,
+            &upload_bucket,
+            &upload_key,
+            &source_object,
+            job,
+        )
+        .classify(format!("Upload of {:?} failed", file_name))?;
+        info!(target:"UPLOAD", "Upload was successful!\n{:?}", res);
+        let promoted = promote(upload_client, destination_bucket, file_name, Some(&source_object), job)?;
+        if promoted {
+            record(upload_client, manifest, file_name, None, job)?;
+            if job.signatures.enabled {
+                sync_signature(
+                    client,
+                    source_bucket,
+                    upload_client,
+                    destination_bucket,
+                    file_name,
+                    true,
+                    job,
+                )
+                .classify(format!("Could not sync the signature of {:?}", file_name))?;
             }
         }
+        promoted
+    };
+    if restores.complete(file_name) {
+        restores.save(&job.restore.state_file).classify("Could not save the restore state")?;
+    }
+    // Objects held in quarantine wait there for review
+    if promoted {
+        Ok(Outcome::Synced)
+    } else {
+        Ok(Outcome::Rejected)
+    }
+}
+
+// Objects are removed from the source once they are staged, so a run that
+// stopped before uploading one left its staged file as the only copy
+fn report_stranded(objects: &[Object], job: &JobConfig) -> Result<(), Box<dyn Error>> {
+    let listed: HashSet<&str> = objects.iter().filter_map(|o| o.key.as_ref()).map(String::as_str).collect();
+    for key in staged_keys(&job.staging.path)? {
+        if !listed.contains(key.as_str()) {
+            warn!(
+                target: "STAGING",
+                "{:?} is staged at {:?} but isn't in the source bucket, it may be the only copy",
+                key,
+                staged_path(&job.staging.path, &key)
+            );
+        }
+    }
+    Ok(())
+}
+
+// Checks an object written to quarantine and moves it to its final key.
+// Objects that fail their checks stay in quarantine and the sync carries on.
+// Returns whether the object is at its final key.
+fn promote(
+    client: &S3Client,
+    destination_bucket: &str,
+    key: &str,
+    source: Option<&ObjectDetails>,
+    job: &JobConfig,
+) -> Result<bool, failure::Error> {
+    if !job.quarantine.enabled {
+        return Ok(true);
+    }
+    let promoted = process_quarantined(client, destination_bucket, key, source, job)
+        .classify(format!("Could not promote {:?} from quarantine", key))?;
+    if promoted {
+        info!(target: "QUARANTINE", "Promoted {:?}", key);
+    } else {
+        warn!(target: "QUARANTINE", "{:?} is held in quarantine", key);
+    }
+    Ok(promoted)
+}
+
+// Adds an object that reached its final key to the run's manifest
+fn record(
+    client: &S3Client,
+    manifest: &mut Manifest,
+    key: &str,
+    source_version_id: Option<&str>,
+    job: &JobConfig,
+) -> Result<(), failure::Error> {
+    if !job.manifest.enabled {
+        return Ok(());
+    }
+    manifest
+        .record_transfer(client, key, source_version_id, job)
+        .classify(format!("Could not add {:?} to the manifest", key))
+}
+
+fn finish_manifest(client: &S3Client, manifest: &mut Manifest, job: &JobConfig) -> Result<(), failure::Error> {
+    publish_manifest(client, manifest, job).classify("Could not write the manifest")?;
+    Ok(())
+}
+
+// Uses the region given on the command line, or finds the bucket's region with
+// a client in the default region of its partition when none or "auto" is given
+fn resolve_region<F: Fn(Region) -> Result<S3Client, Box<dyn Error>>>(
+    region: Option<&str>,
+    partition: Partition,
+    bucket: &str,
+    endpoint: &EndpointConfig,
+    client: F,
+) -> Result<String, Box<dyn Error>> {
+    match region {
+        Some(region) if region != "auto" => Ok(region.to_owned()),
+        _ => {
+            let client_region = partition.default_region();
+            let region = bucket_region(&client(endpoint.region(client_region)?)?, bucket, client_region)?;
+            info!(target: "REGION", "Found bucket {:?} in {}", bucket, region);
+            Ok(region)
+        }
+    }
+}
+
+// Checks the direction of the transfer against the job's partition policy,
+// including which partitions the credentials for each side belong to. Only
+// sides whose endpoint is marked as not being AWS skip the identity lookup,
+// and a lookup that fails stops the run.
+fn check_partitions(
+    job: &JobConfig,
+    source_region: &str,
+    destination_region: &str,
+    destination_credentials: &AwsCredentials,
+) -> Result<(), Box<dyn Error>> {
+    let source_identity = if job.endpoints.source.aws {
+        Some(caller_identity_arn(
+            ChainProvider::new(),
+            Region::from_str(source_region)?,
+        )?)
+    } else {
+        None
+    };
+    let destination_identity = if job.endpoints.destination.aws {
+        Some(caller_identity_arn(
+            StaticProvider::from(destination_credentials.clone()),
+            Region::from_str(destination_region)?,
+        )?)
+    } else {
+        None
+    };
+    info!(
+        target: "PARTITION",
+        "Source identity {:?}, destination identity {:?}", source_identity, destination_identity
+    );
+    job.partitions
+        .validate(
+            source_region,
+            destination_region,
+            source_identity.as_ref().map(String::as_str),
+            destination_identity.as_ref().map(String::as_str),
+        )
+        .map_err(BearcatError::PolicyRejection)?;
+    Ok(())
+}
+
+// Cross account credentials for the bucket being sync'd to, stored in ssm
+fn destination_credentials(ssm_key: &str, ssm_region: &str) -> Result<AwsCredentials, Box<dyn Error>> {
+    info!(target: "CRED EVENTS", "Creating the credentials provider");
+    let cred_prov = CredentialProvider::AwsSsm {
+        key: ssm_key.to_owned(),
+        region: Region::from_str(ssm_region)?,
+    };
+    let sync_creds = cred_prov.get_credentials()?;
+    info!(target: "CRED EVENTS", "Sync Creds:\n{:?}", &sync_creds);
+    Ok(sync_creds)
+}
+
+// Client for the bucket being sync'd to, using the cross account credentials stored in ssm
+fn destination_client(
+    endpoint: &EndpointConfig,
+    credentials: &AwsCredentials,
+    region: Region,
+) -> Result<S3Client, Box<dyn Error>> {
+    info!(target: "UPLOAD CLIENT", "Creating upload client...");
+    let client = endpoint.client(StaticProvider::from(credentials.clone()), region)?;
+    info!(target: "UPLOAD CLIENT", "Upload client created successfully");
+    Ok(client)
+}
+
+// Client for the bucket being sync'd from, using the default credential chain
+fn source_client(endpoint: &EndpointConfig, region: Region) -> Result<S3Client, Box<dyn Error>> {
+    info!(target: "DOWNLOAD CLIENT", "Creating download client...");
+    let client = endpoint.client(ChainProvider::new(), region)?;
+    info!(target: "DOWNLOAD CLIENT", "Download client created successfully");
+    Ok(client)
+}
+
+// Runs the verify subcommand and returns the process exit code:
+// 0 when the buckets are in sync, 1 when drift was found and 2 on errors
+fn run_verify(matches: &ArgMatches) -> i32 {
+    let (source_bucket, destination_bucket) = match (matches.value_of("source"), matches.value_of("destination")) {
+        (Some(source), Some(destination)) => (source, destination),
+        _ => {
+            error!(target: "VERIFY", "Both a source and a destination bucket are needed");
+            return 2;
+        }
+    };
+    let format: OutputFormat = match matches.value_of("format").unwrap_or("text").parse() {
+        Ok(format) => format,
+        Err(e) => {
+            error!(target: "VERIFY", "Invalid output format: {}", e);
+            return 2;
+        }
+    };
+    if format != OutputFormat::Text {
+        // Only errors are logged so stdout stays machine readable
+        log::set_max_level(LevelFilter::Error);
+    }
+    let sample = match matches.value_of("sample").unwrap_or("0").parse::<usize>() {
+        Ok(sample) => sample,
+        Err(e) => {
+            error!(target: "VERIFY", "Invalid sample size: {}", e);
+            return 2;
+        }
+    };
+    let regex_arg = |name: &str| -> Result<Option<Regex>, regex::Error> {
+        matches.value_of(name).map(Regex::new).transpose()
+    };
+    let filter = match (regex_arg("include"), regex_arg("exclude")) {
+        (Ok(include), Ok(exclude)) => KeyFilter {
+            prefix: matches.value_of("prefix").map(str::to_owned),
+            include,
+            exclude,
+        },
+        (Err(e), _) | (_, Err(e)) => {
+            error!(target: "VERIFY", "Invalid key filter: {}", e);
+            return 2;
+        }
+    };
+
+    let job = match load_config(matches.value_of("config")) {
+        Ok(job) => job,
+        Err(e) => {
+            error!(target: "VERIFY", "Could not load the config file: {}", e);
+            return 2;
+        }
+    };
+    let (source_partition, destination_partition) = match (
+        matches.value_of("source_partition").unwrap_or("aws").parse::<Partition>(),
+        matches.value_of("destination_partition").unwrap_or("aws").parse::<Partition>(),
+    ) {
+        (Ok(source), Ok(destination)) => (source, destination),
+        (Err(e), _) | (_, Err(e)) => {
+            error!(target: "VERIFY", "Invalid partition: {}", e);
+            return 2;
+        }
+    };
+    let source_region = match resolve_region(
+        matches.value_of("region_source"),
+        source_partition,
+        source_bucket,
+        &job.endpoints.source,
+        |region| source_client(&job.endpoints.source, region),
+    ) {
+        Ok(region) => region,
+        Err(e) => {
+            error!(target: "VERIFY", "Could not find the region of the source bucket: {}", e);
+            return 2;
+        }
+    };
+    let source_region = source_region.as_str();
+    let ssm_key = matches.value_of("ssm_key");
+    let credentials = match ssm_key.map(|ssm_key| destination_credentials(ssm_key, source_region)).transpose() {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            error!(target: "VERIFY", "Could not get the destination credentials from ssm: {}", e);
+            return 2;
+        }
+    };
+    let client_for = |region| match &credentials {
+        Some(credentials) => destination_client(&job.endpoints.destination, credentials, region),
+        None => source_client(&job.endpoints.destination, region),
+    };
+    let destination_region = match resolve_region(
+        matches.value_of("region_destination"),
+        destination_partition,
+        destination_bucket,
+        &job.endpoints.destination,
+        client_for,
+    ) {
+        Ok(region) => region,
+        Err(e) => {
+            error!(target: "VERIFY", "Could not find the region of the destination bucket: {}", e);
+            return 2;
+        }
+    };
+    let destination_region = destination_region.as_str();
+    let (source_endpoint, destination_endpoint) = match (
+        job.endpoints.source.region(source_region),
+        job.endpoints.destination.region(destination_region),
+    ) {
+        (Ok(source), Ok(destination)) => (source, destination),
+        (Err(e), _) | (_, Err(e)) => {
+            error!(target: "VERIFY", "Invalid endpoint: {}", e);
+            return 2;
+        }
+    };
+    // Without cross account credentials the default chain is used for both buckets
+    let (client, upload_client) = match (
+        source_client(&job.endpoints.source, source_endpoint),
+        client_for(destination_endpoint),
+    ) {
+        (Ok(client), Ok(upload_client)) => (client, upload_client),
+        (Err(e), _) | (_, Err(e)) => {
+            error!(target: "VERIFY", "Could not create the S3 clients: {}", e);
+            return 2;
+        }
+    };
+    let options = VerifyOptions { filter, sample };
+    let report = match verify_buckets(
+        &client,
+        source_bucket,
+        &upload_client,
+        destination_bucket,
+        &options,
+    ) {
+        Ok(report) => report,
+        Err(e) => {
+            error!(target: "VERIFY", "Verification failed: {:?}", e);
+            return 2;
+        }
+    };
+    match report.render(&format) {
+        Ok(output) => print!("{}", output),
+        Err(e) => {
+            error!(target: "VERIFY", "Could not render the report: {:?}", e);
+            return 2;
+        }
+    }
+    if report.has_drift() {
+        1
+    } else {
+        0
+    }
+}
+
+// Runs `manifest verify` and returns the process exit code: 0 when the
+// signature and every object check out, 1 when something doesn't match and 2
+// on errors
+fn run_manifest_verify(matches: &ArgMatches) -> i32 {
+    let (bucket, key) = match (matches.value_of("bucket"), matches.value_of("key")) {
+        (Some(bucket), Some(key)) => (bucket, key),
+        _ => {
+            error!(target: "MANIFEST", "Both the bucket and the key of the manifest are needed");
+            return 2;
+        }
+    };
+    let job = match load_config(matches.value_of("config")) {
+        Ok(job) => job,
+        Err(e) => {
+            error!(target: "MANIFEST", "Could not load the config file: {}", e);
+            return 2;
+        }
+    };
+    let partition = match matches.value_of("partition").unwrap_or("aws").parse::<Partition>() {
+        Ok(partition) => partition,
+        Err(e) => {
+            error!(target: "MANIFEST", "Invalid partition: {}", e);
+            return 2;
+        }
+    };
+    let ssm_key = matches.value_of("ssm_key");
+    let ssm_region = matches.value_of("ssm_region").unwrap_or("us-east-1");
+    let credentials = match ssm_key.map(|ssm_key| destination_credentials(ssm_key, ssm_region)).transpose() {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            error!(target: "MANIFEST", "Could not get the credentials from ssm: {}", e);
+            return 2;
+        }
+    };
+    // Manifests are written to the destination, so its endpoint settings apply
+    let endpoint = &job.endpoints.destination;
+    let client_for = |region| match &credentials {
+        Some(credentials) => destination_client(endpoint, credentials, region),
+        None => source_client(endpoint, region),
+    };
+    let region = match resolve_region(matches.value_of("region"), partition, bucket, endpoint, client_for) {
+        Ok(region) => region,
+        Err(e) => {
+            error!(target: "MANIFEST", "Could not find the region of the bucket: {}", e);
+            return 2;
+        }
+    };
+    let client = match endpoint.region(&region).map_err(Box::<dyn Error>::from).and_then(client_for) {
+        Ok(client) => client,
+        Err(e) => {
+            error!(target: "MANIFEST", "Could not create the S3 client: {}", e);
+            return 2;
+        }
+    };
+    let check = match verify_manifest(&client, bucket, key, &job) {
+        Ok(check) => check,
+        Err(e) => {
+            error!(target: "MANIFEST", "Could not verify {:?}: {}", key, e);
+            return 2;
+        }
+    };
+    for problem in &check.problems {
+        println!("{}", problem);
+    }
+    println!(
+        "Checked {} objects against {:?}, {} problems found",
+        check.checked,
+        key,
+        check.problems.len()
+    );
+    if check.problems.is_empty() {
+        0
+    } else {
+        1
     }
-    ////// MOVE/BREAK THIS OUT END //////////////////
 }
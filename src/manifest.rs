@@ -0,0 +1,443 @@
+use crate::bucket::{
+    get_bucket_object, hash_bucket_object_version, head_bucket_object_version, put_bucket_object,
+};
+use crate::checksum::{content_sha256, stored_sha256};
+use crate::envelope::WRAPPED_KEY_METADATA_KEY;
+use crate::config::{yaml_scalar, JobConfig};
+use crate::encryption::{CustomerKey, ServerSideEncryption};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+use yaml_rust::Yaml;
+use log::info;
+
+// Added to the manifest key for its detached signature
+pub const SIGNATURE_SUFFIX: &str = ".sig";
+
+// An Ed25519 key the manifests are signed with
+#[derive(Clone, PartialEq)]
+pub struct SigningKey {
+    seed: [u8; 32],
+    // Worked out from the seed when the key is read
+    public: PublicKey,
+}
+
+// Keeps the key material out of the logs
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "SigningKey {{ public_key: {:?} }}", base64::encode(self.public_key().as_bytes()))
+    }
+}
+
+impl SigningKey {
+    // Accepts the 32 byte seed or the base64 encoding of it
+    pub fn from_bytes(content: &[u8]) -> Result<SigningKey, String> {
+        let seed = if content.len() == 32 {
+            content.to_vec()
+        } else {
+            let text = String::from_utf8_lossy(content);
+            base64::decode(text.trim()).map_err(|e| format!("Invalid signing key: {}", e))?
+        };
+        if seed.len() != 32 {
+            return Err(format!("Signing keys are 32 bytes, got {}", seed.len()));
+        }
+        let secret = SecretKey::from_bytes(&seed).map_err(|e| format!("Invalid signing key: {}", e))?;
+        let mut key = SigningKey {
+            seed: [0; 32],
+            public: PublicKey::from(&secret),
+        };
+        key.seed.copy_from_slice(&seed);
+        Ok(key)
+    }
+
+    pub fn from_file(path: &str) -> Result<SigningKey, String> {
+        let content =
+            fs::read(path).map_err(|e| format!("Could not read the signing key {:?}: {}", path, e))?;
+        SigningKey::from_bytes(&content)
+    }
+
+    fn keypair(&self) -> Result<Keypair, String> {
+        let secret =
+            SecretKey::from_bytes(&self.seed).map_err(|e| format!("Invalid signing key: {}", e))?;
+        Ok(Keypair {
+            secret,
+            public: self.public,
+        })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public
+    }
+
+    pub fn sign(&self, content: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(self.keypair()?.sign(content).to_bytes().to_vec())
+    }
+}
+
+pub fn parse_public_key(encoded: &str) -> Result<PublicKey, String> {
+    let bytes = base64::decode(encoded.trim()).map_err(|e| format!("Invalid public key: {}", e))?;
+    PublicKey::from_bytes(&bytes).map_err(|e| format!("Invalid public key: {}", e))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestConfig {
+    pub enabled: bool,
+    pub signing_key: Option<SigningKey>,
+    // Checks manifests on hosts that don't have the signing key
+    pub public_key: Option<PublicKey>,
+    pub prefix: String,
+}
+
+impl Default for ManifestConfig {
+    fn default() -> ManifestConfig {
+        ManifestConfig {
+            enabled: false,
+            signing_key: None,
+            public_key: None,
+            prefix: ".bearcat/manifests/".to_owned(),
+        }
+    }
+}
+
+impl ManifestConfig {
+    // Reads the `manifest` section of the job config. The signing key file
+    // holds the base64 encoded 32 byte Ed25519 seed:
+    //
+    //   manifest:
+    //     enabled: true
+    //     signing_key: /etc/bearcat/manifest.key
+    //     public_key: 3q2+7w...
+    //     prefix: .bearcat/manifests/
+    pub fn from_yaml(yaml: &Yaml) -> Result<ManifestConfig, String> {
+        let defaults = ManifestConfig::default();
+        let enabled = match &yaml["enabled"] {
+            Yaml::Boolean(b) => *b,
+            Yaml::BadValue => defaults.enabled,
+            _ => return Err("manifest.enabled must be true or false".to_owned()),
+        };
+        let config = ManifestConfig {
+            enabled,
+            signing_key: yaml_scalar(&yaml["signing_key"])
+                .map(|path| SigningKey::from_file(&path))
+                .transpose()?,
+            public_key: yaml_scalar(&yaml["public_key"])
+                .map(|key| parse_public_key(&key))
+                .transpose()?,
+            prefix: yaml_scalar(&yaml["prefix"]).unwrap_or(defaults.prefix),
+        };
+        if config.enabled && config.signing_key.is_none() {
+            return Err("manifest.signing_key is needed to sign manifests".to_owned());
+        }
+        Ok(config)
+    }
+
+    // The configured public key, or the one belonging to the signing key
+    pub fn verification_key(&self) -> Option<PublicKey> {
+        self.public_key
+            .or_else(|| self.signing_key.as_ref().map(SigningKey::public_key))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub key: String,
+    pub size: i64,
+    // The plaintext for client side encrypted objects
+    pub sha256: String,
+    // The bytes S3 stores for client side encrypted objects
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed_sha256: Option<String>,
+    pub source_bucket: String,
+    pub destination_bucket: String,
+    pub source_version_id: Option<String>,
+    pub destination_version_id: Option<String>,
+    pub transferred_at: u64,
+}
+
+// Every object a run transferred, signed and written next to the objects
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub source_bucket: String,
+    pub destination_bucket: String,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub entries: Vec<ManifestEntry>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn destination_customer_key(job: &JobConfig) -> Option<&CustomerKey> {
+    match &job.encryption.destination {
+        ServerSideEncryption::Customer(customer_key) => Some(customer_key),
+        _ => None,
+    }
+}
+
+impl Manifest {
+    pub fn new(source_bucket: &str, destination_bucket: &str) -> Manifest {
+        Manifest {
+            source_bucket: source_bucket.to_owned(),
+            destination_bucket: destination_bucket.to_owned(),
+            started_at: now(),
+            finished_at: None,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn key(&self, prefix: &str) -> String {
+        format!("{}manifest-{}.json", prefix, self.started_at)
+    }
+
+    // Adds an object that reached its final key, reading its size, version and
+    // digest back from the destination
+    pub fn record_transfer(
+        &mut self,
+        client: &rusoto_s3::S3Client,
+        key: &str,
+        source_version_id: Option<&str>,
+        job: &JobConfig,
+    ) -> Result<(), Box<dyn Error>> {
+        let bucket = self.destination_bucket.clone();
+        let customer_key = destination_customer_key(job);
+        let head = head_bucket_object_version(client, &bucket, key, None, customer_key)?;
+        let metadata = head.metadata.clone().unwrap_or_default();
+        let stored = match stored_sha256(&metadata) {
+            Some(sha256) => sha256.to_owned(),
+            None => hash_bucket_object_version(
+                client,
+                &bucket,
+                key,
+                head.version_id.as_ref().map(String::as_str),
+                customer_key,
+            )?,
+        };
+        let (sha256, sealed_sha256) = match content_sha256(&metadata) {
+            Some(plaintext) if metadata.contains_key(WRAPPED_KEY_METADATA_KEY) => {
+                (plaintext.to_owned(), Some(stored))
+            }
+            _ => (stored, None),
+        };
+        self.entries.push(ManifestEntry {
+            key: key.to_owned(),
+            size: head.content_length.unwrap_or(0),
+            sha256,
+            sealed_sha256,
+            source_bucket: self.source_bucket.clone(),
+            destination_bucket: bucket,
+            source_version_id: source_version_id.map(str::to_owned),
+            destination_version_id: head.version_id,
+            transferred_at: now(),
+        });
+        Ok(())
+    }
+
+    // The manifest as it is written and the signature over exactly those bytes
+    pub fn sign(&self, signing_key: &SigningKey) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+        let content = serde_json::to_vec_pretty(self)?;
+        let signature = base64::encode(&signing_key.sign(&content)?).into_bytes();
+        Ok((content, signature))
+    }
+}
+
+// Checks the signature before anything in the manifest is trusted
+pub fn open_manifest(
+    content: &[u8],
+    signature: &[u8],
+    public_key: &PublicKey,
+) -> Result<Manifest, String> {
+    let signature = base64::decode(String::from_utf8_lossy(signature).trim())
+        .map_err(|e| format!("Invalid manifest signature: {}", e))?;
+    let signature = Signature::try_from(&signature[..])
+        .map_err(|e| format!("Invalid manifest signature: {}", e))?;
+    public_key
+        .verify(content, &signature)
+        .map_err(|_| "The manifest signature doesn't match the trusted key".to_owned())?;
+    serde_json::from_slice(content).map_err(|e| format!("Could not parse the manifest: {}", e))
+}
+
+// Signs the manifest and writes it with its signature to the destination
+// bucket. Returns the manifest key when one was written.
+pub fn publish_manifest(
+    client: &rusoto_s3::S3Client,
+    manifest: &mut Manifest,
+    job: &JobConfig,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let signing_key = match (&job.manifest.signing_key, job.manifest.enabled) {
+        (Some(signing_key), true) => signing_key,
+        _ => return Ok(None),
+    };
+    manifest.finished_at = Some(now());
+    let (content, signature) = manifest.sign(signing_key)?;
+    let key = manifest.key(&job.manifest.prefix);
+    let bucket = manifest.destination_bucket.clone();
+    put_bucket_object(client, &bucket, &key, content, "application/json", job)?;
+    let signature_key = format!("{}{}", key, SIGNATURE_SUFFIX);
+    put_bucket_object(client, &bucket, &signature_key, signature, "text/plain", job)?;
+    info!(target: "MANIFEST", "Wrote the signed manifest {:?} with {} objects", key, manifest.entries.len());
+    Ok(Some(key))
+}
+
+// What `bearcat manifest verify` found
+#[derive(Debug, Default)]
+pub struct ManifestCheck {
+    pub checked: usize,
+    pub problems: Vec<String>,
+}
+
+// Checks the manifest's signature, then re-hashes every object it lists at the
+// destination version it recorded
+pub fn verify_manifest(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
+    job: &JobConfig,
+) -> Result<ManifestCheck, Box<dyn Error>> {
+    let public_key = job
+        .manifest
+        .verification_key()
+        .ok_or("manifest.public_key or manifest.signing_key is needed to verify manifests")?;
+    let customer_key = destination_customer_key(job);
+    let content = get_bucket_object(client, bucket, key, customer_key)?;
+    let signature_key = format!("{}{}", key, SIGNATURE_SUFFIX);
+    let signature = get_bucket_object(client, bucket, &signature_key, customer_key)?;
+    let manifest = open_manifest(&content, &signature, &public_key)?;
+    let mut check = ManifestCheck::default();
+    for entry in &manifest.entries {
+        check.checked += 1;
+        let version_id = entry.destination_version_id.as_ref().map(String::as_str);
+        let head = match head_bucket_object_version(
+            client,
+            &entry.destination_bucket,
+            &entry.key,
+            version_id,
+            customer_key,
+        ) {
+            Ok(head) => head,
+            Err(e) => {
+                check.problems.push(format!("{:?} could not be read: {}", entry.key, e));
+                continue;
+            }
+        };
+        if head.content_length != Some(entry.size) {
+            check.problems.push(format!(
+                "{:?} is {:?} bytes, the manifest says {}",
+                entry.key, head.content_length, entry.size
+            ));
+            continue;
+        }
+        let sha256 = hash_bucket_object_version(
+            client,
+            &entry.destination_bucket,
+            &entry.key,
+            version_id,
+            customer_key,
+        )?;
+        // Sealed objects are read back as S3 stores them
+        let expected = entry.sealed_sha256.as_ref().unwrap_or(&entry.sha256);
+        if &sha256 != expected {
+            check.problems.push(format!(
+                "{:?} has SHA-256 {}, the manifest says {}",
+                entry.key, sha256, expected
+            ));
+        }
+    }
+    Ok(check)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    const SEED: [u8; 32] = [7; 32];
+
+    fn manifest() -> Manifest {
+        Manifest {
+            source_bucket: "commercial-releases".to_owned(),
+            destination_bucket: "gov-releases".to_owned(),
+            started_at: 1_576_800_000,
+            finished_at: Some(1_576_800_060),
+            entries: vec![ManifestEntry {
+                key: "go/go1.12.5.linux-amd64.tar.gz".to_owned(),
+                size: 127_938_445,
+                sha256: "aea86e3c73495f205929cfebba0d63f1382c8ac59be081b6351681415f4063cf"
+                    .to_owned(),
+                sealed_sha256: None,
+                source_bucket: "commercial-releases".to_owned(),
+                destination_bucket: "gov-releases".to_owned(),
+                source_version_id: None,
+                destination_version_id: Some("3HL4kqtJlcpXroDTDmjVBH40Nrjfkd".to_owned()),
+                transferred_at: 1_576_800_030,
+            }],
+        }
+    }
+
+    #[test]
+    fn sign_and_open_test() {
+        let signing_key = SigningKey::from_bytes(&SEED).unwrap();
+        let (content, signature) = manifest().sign(&signing_key).unwrap();
+        assert_eq!(
+            open_manifest(&content, &signature, &signing_key.public_key()),
+            Ok(manifest())
+        );
+    }
+
+    #[test]
+    fn tampered_manifest_test() {
+        let signing_key = SigningKey::from_bytes(&SEED).unwrap();
+        let (content, signature) = manifest().sign(&signing_key).unwrap();
+        let tampered = String::from_utf8(content.clone())
+            .unwrap()
+            .replace("127938445", "127938446");
+        assert_eq!(
+            open_manifest(tampered.as_bytes(), &signature, &signing_key.public_key()).is_err(),
+            true
+        );
+        let other_key = SigningKey::from_bytes(&[9; 32]).unwrap();
+        assert_eq!(
+            open_manifest(&content, &signature, &other_key.public_key()).is_err(),
+            true
+        );
+        assert_eq!(
+            open_manifest(&content, b"not a signature", &signing_key.public_key()).is_err(),
+            true
+        );
+    }
+
+    #[test]
+    fn manifest_key_test() {
+        assert_eq!(
+            manifest().key(".bearcat/manifests/"),
+            ".bearcat/manifests/manifest-1576800000.json"
+        );
+    }
+
+    #[test]
+    fn manifest_config_test() {
+        fs::create_dir_all("file_tests").unwrap();
+        fs::write("file_tests/manifest.key", base64::encode(&SEED)).unwrap();
+        let config = |content: &str| {
+            let doc = &YamlLoader::load_from_str(content).unwrap()[0];
+            ManifestConfig::from_yaml(&doc["manifest"])
+        };
+        let signing =
+            config("manifest:\n  enabled: true\n  signing_key: file_tests/manifest.key\n").unwrap();
+        let signing_key = SigningKey::from_bytes(&SEED).unwrap();
+        assert_eq!(signing.signing_key, Some(signing_key.clone()));
+        assert_eq!(signing.verification_key(), Some(signing_key.public_key()));
+        let public_key = base64::encode(signing_key.public_key().as_bytes());
+        let verifying = config(&format!("manifest:\n  public_key: {}\n", public_key)).unwrap();
+        assert_eq!(verifying.verification_key(), Some(signing_key.public_key()));
+        assert_eq!(config("other: value"), Ok(ManifestConfig::default()));
+        assert_eq!(config("manifest:\n  enabled: true\n").is_err(), true);
+        assert_eq!(config("manifest:\n  public_key: c2hvcnQ=\n").is_err(), true);
+    }
+}
@@ -0,0 +1,260 @@
+use crate::config::{yaml_string_list, yaml_string_map};
+use rusoto_s3::{GetObjectOutput, HeadObjectOutput, Tag};
+use std::collections::HashMap;
+use yaml_rust::Yaml;
+
+// Prefix S3 uses for user metadata headers
+pub const USER_METADATA_PREFIX: &str = "x-amz-meta-";
+
+// Content headers, user metadata and tags of an object, carried from the
+// source to the destination alongside the content
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectHeaders {
+    pub content_type: Option<String>,
+    pub cache_control: Option<String>,
+    pub content_encoding: Option<String>,
+    pub content_disposition: Option<String>,
+    pub content_language: Option<String>,
+    pub expires: Option<String>,
+    pub metadata: HashMap<String, String>,
+    pub tags: Vec<Tag>,
+}
+
+impl ObjectHeaders {
+    pub fn from_get_object(res: &GetObjectOutput, tags: Vec<Tag>) -> ObjectHeaders {
+        ObjectHeaders {
+            content_type: res.content_type.clone(),
+            cache_control: res.cache_control.clone(),
+            content_encoding: res.content_encoding.clone(),
+            content_disposition: res.content_disposition.clone(),
+            content_language: res.content_language.clone(),
+            expires: res.expires.clone(),
+            metadata: res.metadata.clone().unwrap_or_default(),
+            tags,
+        }
+    }
+
+    pub fn from_head_object(res: &HeadObjectOutput, tags: Vec<Tag>) -> ObjectHeaders {
+        ObjectHeaders {
+            content_type: res.content_type.clone(),
+            cache_control: res.cache_control.clone(),
+            content_encoding: res.content_encoding.clone(),
+            content_disposition: res.content_disposition.clone(),
+            content_language: res.content_language.clone(),
+            expires: res.expires.clone(),
+            metadata: res.metadata.clone().unwrap_or_default(),
+            tags,
+        }
+    }
+
+    fn header_mut(&mut self, name: &str) -> Option<&mut Option<String>> {
+        match name {
+            "content-type" => Some(&mut self.content_type),
+            "cache-control" => Some(&mut self.cache_control),
+            "content-encoding" => Some(&mut self.content_encoding),
+            "content-disposition" => Some(&mut self.content_disposition),
+            "content-language" => Some(&mut self.content_language),
+            "expires" => Some(&mut self.expires),
+            _ => None,
+        }
+    }
+
+    // Removes a content header, a user metadata key (x-amz-meta-<key>) or all
+    // user metadata (x-amz-meta-*)
+    fn strip_header(&mut self, name: &str) {
+        if name == "x-amz-meta-*" {
+            self.metadata.clear();
+        } else if name.starts_with(USER_METADATA_PREFIX) {
+            self.metadata.remove(&name[USER_METADATA_PREFIX.len()..]);
+        } else if let Some(header) = self.header_mut(name) {
+            *header = None;
+        }
+    }
+
+    fn set_header(&mut self, name: &str, value: &str) {
+        if name.starts_with(USER_METADATA_PREFIX) {
+            self.metadata
+                .insert(name[USER_METADATA_PREFIX.len()..].to_owned(), value.to_owned());
+        } else if let Some(header) = self.header_mut(name) {
+            *header = Some(value.to_owned());
+        }
+    }
+
+    fn strip_tag(&mut self, key: &str) {
+        if key == "*" {
+            self.tags.clear();
+        } else {
+            self.tags.retain(|t| t.key != key);
+        }
+    }
+
+    fn set_tag(&mut self, key: &str, value: &str) {
+        self.tags.retain(|t| t.key != key);
+        self.tags.push(Tag {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        });
+    }
+}
+
+// Per job changes to the headers and tags copied from the source object.
+// Entries are stripped first and then set, so a header can be replaced.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeaderPolicy {
+    pub strip_headers: Vec<String>,
+    pub set_headers: Vec<(String, String)>,
+    pub strip_tags: Vec<String>,
+    pub set_tags: Vec<(String, String)>,
+}
+
+impl HeaderPolicy {
+    // Reads the `headers` and `tags` sections of the job config:
+    //
+    //   headers:
+    //     strip: [cache-control, x-amz-meta-build-host]
+    //     set:
+    //       content-type: application/gzip
+    //   tags:
+    //     strip: ["*"]
+    //     set:
+    //       origin: commercial
+    pub fn from_yaml(headers: &Yaml, tags: &Yaml) -> Result<HeaderPolicy, String> {
+        let policy = HeaderPolicy {
+            strip_headers: yaml_string_list(&headers["strip"], "headers.strip")?
+                .into_iter()
+                .map(|h| h.to_lowercase())
+                .collect(),
+            set_headers: yaml_string_map(&headers["set"], "headers.set")?
+                .into_iter()
+                .map(|(h, v)| (h.to_lowercase(), v))
+                .collect(),
+            strip_tags: yaml_string_list(&tags["strip"], "tags.strip")?,
+            set_tags: yaml_string_map(&tags["set"], "tags.set")?,
+        };
+        for header in policy
+            .strip_headers
+            .iter()
+            .chain(policy.set_headers.iter().map(|(h, _)| h))
+        {
+            if !is_supported_header(header) {
+                return Err(format!("unsupported header {:?} in the job config", header));
+            }
+        }
+        if policy.set_headers.iter().any(|(h, _)| h == "x-amz-meta-*") {
+            return Err("x-amz-meta-* can only be stripped".to_owned());
+        }
+        Ok(policy)
+    }
+
+    pub fn apply(&self, headers: &mut ObjectHeaders) {
+        for name in &self.strip_headers {
+            headers.strip_header(name);
+        }
+        for (name, value) in &self.set_headers {
+            headers.set_header(name, value);
+        }
+        for key in &self.strip_tags {
+            headers.strip_tag(key);
+        }
+        for (key, value) in &self.set_tags {
+            headers.set_tag(key, value);
+        }
+    }
+}
+
+fn is_supported_header(name: &str) -> bool {
+    ObjectHeaders::default().header_mut(name).is_some()
+        || (name.starts_with(USER_METADATA_PREFIX) && name.len() > USER_METADATA_PREFIX.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    const POLICY: &str = "
+headers:
+  strip: [Cache-Control, x-amz-meta-build-host]
+  set:
+    content-type: application/gzip
+    x-amz-meta-origin: commercial
+tags:
+  strip: [internal]
+  set:
+    release: 1
+";
+
+    fn headers() -> ObjectHeaders {
+        let mut metadata = HashMap::new();
+        metadata.insert("build-host".to_owned(), "ci-01".to_owned());
+        metadata.insert("owner".to_owned(), "platform".to_owned());
+        ObjectHeaders {
+            content_type: Some("binary/octet-stream".to_owned()),
+            cache_control: Some("max-age=60".to_owned()),
+            content_encoding: Some("gzip".to_owned()),
+            metadata,
+            tags: vec![
+                Tag {
+                    key: "internal".to_owned(),
+                    value: "true".to_owned(),
+                },
+                Tag {
+                    key: "team".to_owned(),
+                    value: "platform".to_owned(),
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn policy(config: &str) -> Result<HeaderPolicy, String> {
+        let doc = &YamlLoader::load_from_str(config).unwrap()[0];
+        HeaderPolicy::from_yaml(&doc["headers"], &doc["tags"])
+    }
+
+    #[test]
+    fn apply_header_policy_test() {
+        let mut headers = headers();
+        policy(POLICY).unwrap().apply(&mut headers);
+        assert_eq!(headers.content_type, Some("application/gzip".to_owned()));
+        assert_eq!(headers.cache_control, None);
+        assert_eq!(headers.content_encoding, Some("gzip".to_owned()));
+        assert_eq!(headers.metadata.get("build-host"), None);
+        assert_eq!(headers.metadata.get("owner"), Some(&"platform".to_owned()));
+        assert_eq!(headers.metadata.get("origin"), Some(&"commercial".to_owned()));
+        let tags: Vec<(&str, &str)> = headers
+            .tags
+            .iter()
+            .map(|t| (t.key.as_str(), t.value.as_str()))
+            .collect();
+        assert_eq!(tags, vec![("team", "platform"), ("release", "1")]);
+    }
+
+    #[test]
+    fn strip_everything_test() {
+        let mut headers = headers();
+        policy("headers:\n  strip: [x-amz-meta-*]\ntags:\n  strip: ['*']\n")
+            .unwrap()
+            .apply(&mut headers);
+        assert_eq!(headers.metadata.is_empty(), true);
+        assert_eq!(headers.tags.is_empty(), true);
+        assert_eq!(headers.content_type, Some("binary/octet-stream".to_owned()));
+    }
+
+    #[test]
+    fn empty_policy_test() {
+        let mut headers = headers();
+        let empty = policy("other: value").unwrap();
+        assert_eq!(empty, HeaderPolicy::default());
+        empty.apply(&mut headers);
+        assert_eq!(headers, self::headers());
+    }
+
+    #[test]
+    fn invalid_policy_test() {
+        assert_eq!(policy("headers:\n  strip: [x-custom]\n").is_err(), true);
+        assert_eq!(policy("headers:\n  strip: content-type\n").is_err(), true);
+        assert_eq!(policy("headers:\n  set: [content-type]\n").is_err(), true);
+        assert_eq!(policy("headers:\n  set:\n    x-amz-meta-*: a\n").is_err(), true);
+    }
+}
@@ -1,7 +1,221 @@
+use crate::metadata::ObjectHeaders;
 use std::fs::File;
-use std::io::BufReader;
+use std::io;
 use std::io::prelude::*;
+use std::io::SeekFrom;
 
-pub fn create_file_parts(path: &str) -> std::io::Result<()> {
-    let mut buffer = File::open(path)?;
-}
\ No newline at end of file
+// Size of each part sent in a multipart upload (5 MiB, the S3 minimum)
+pub const PART_SIZE: usize = 5242880;
+
+// Largest object a single CopyObject request can copy (5 GiB)
+pub const MAX_COPY_OBJECT_SIZE: i64 = 5368709120;
+
+// Size of each part copied with UploadPartCopy (512 MiB)
+const COPY_PART_SIZE: i64 = 536870912;
+
+// S3 allows at most 10,000 parts in a multipart upload
+const MAX_PARTS: i64 = 10000;
+
+// Largest object S3 stores (5 TiB)
+const MAX_OBJECT_SIZE: u64 = 5497558138880;
+
+// Details of a source object that are carried from the download to the upload
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectDetails {
+    pub key: String,
+    pub e_tag: Option<String>,
+    // Set when a specific version of the source object was copied
+    pub version_id: Option<String>,
+    pub sha256: String,
+    pub storage_class: Option<String>,
+    pub headers: ObjectHeaders,
+    // Why inspection blocked the object, when it's carried on to quarantine
+    pub blocked: Option<String>,
+}
+
+// A single part of a file, numbered the way S3 expects (starting at 1)
+pub struct FilePart {
+    pub part_number: i64,
+    pub body: Vec<u8>,
+}
+
+// Lazily reads a file one part at a time. Only the part that is about to be
+// uploaded is held in memory, so memory use is bounded by the part size and
+// not by the size of the file. Any reader works, like an encrypted staged file.
+// An empty file still gives one empty part, S3 won't complete an upload
+// without any.
+pub struct FileParts<R: Read = File> {
+    file: R,
+    part_size: usize,
+    part_number: i64,
+    done: bool,
+}
+
+impl<R: Read> FileParts<R> {
+    pub fn new(file: R, part_size: usize) -> FileParts<R> {
+        FileParts {
+            file,
+            part_size,
+            part_number: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Iterator for FileParts<R> {
+    type Item = io::Result<FilePart>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut body = Vec::with_capacity(self.part_size);
+        match (&mut self.file)
+            .take(self.part_size as u64)
+            .read_to_end(&mut body)
+        {
+            Ok(0) if self.part_number > 0 => {
+                self.done = true;
+                None
+            }
+            Ok(length) => {
+                self.done = length < self.part_size;
+                self.part_number += 1;
+                Some(Ok(FilePart {
+                    part_number: self.part_number,
+                    body,
+                }))
+            }
+            Err(e) => {
+                // Stop producing parts after the first read failure
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// Size of the parts an object of `size` bytes is uploaded in. It grows for
+// objects too large to fit in 10,000 parts of PART_SIZE.
+pub fn upload_part_size(size: u64) -> io::Result<usize> {
+    if size > MAX_OBJECT_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} bytes is more than the {} bytes S3 allows in an object", size, MAX_OBJECT_SIZE),
+        ));
+    }
+    let parts = MAX_PARTS as u64;
+    Ok(std::cmp::max(PART_SIZE as u64, (size + parts - 1) / parts) as usize)
+}
+
+// Opens the file at the path and returns a producer of its upload parts
+pub fn create_file_parts(path: &str) -> io::Result<FileParts> {
+    let file = File::open(path)?;
+    let part_size = upload_part_size(file.metadata()?.len())?;
+    Ok(FileParts::new(file, part_size))
+}
+
+// Upload parts of whatever the reader produces, measured by seeking to its end
+pub fn create_reader_parts<R: Read + Seek>(mut reader: R) -> io::Result<FileParts<R>> {
+    let size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(FileParts::new(reader, upload_part_size(size)?))
+}
+
+// Byte ranges for UploadPartCopy, numbered the way S3 expects. The part size
+// grows for objects too large to fit in 10,000 parts.
+pub fn copy_part_ranges(size: i64) -> Vec<(i64, String)> {
+    let part_size = std::cmp::max(COPY_PART_SIZE, (size + MAX_PARTS - 1) / MAX_PARTS);
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while start < size {
+        let end = std::cmp::min(start + part_size, size) - 1;
+        ranges.push((ranges.len() as i64 + 1, format!("bytes={}-{}", start, end)));
+        start = end + 1;
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    static TEST_DIR: &str = "file_tests";
+
+    fn write_test_file(name: &str, content: &[u8]) -> String {
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let path = format!("{}/{}", TEST_DIR, name);
+        let mut file = File::create(&path).unwrap();
+        file.write_all(content).unwrap();
+        path
+    }
+
+    #[test]
+    fn file_parts_split_test() {
+        let path = write_test_file("parts_split", b"abcdefghij");
+        let parts: Vec<FilePart> = FileParts::new(File::open(&path).unwrap(), 4)
+            .map(|p| p.unwrap())
+            .collect();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0].part_number, 1);
+        assert_eq!(parts[0].body, b"abcd".to_vec());
+        assert_eq!(parts[1].part_number, 2);
+        assert_eq!(parts[1].body, b"efgh".to_vec());
+        assert_eq!(parts[2].part_number, 3);
+        assert_eq!(parts[2].body, b"ij".to_vec());
+    }
+
+    #[test]
+    fn file_parts_exact_multiple_test() {
+        let path = write_test_file("parts_exact", b"abcdefgh");
+        let parts = FileParts::new(File::open(&path).unwrap(), 4);
+        assert_eq!(parts.count(), 2);
+    }
+
+    #[test]
+    fn file_parts_empty_file_test() {
+        let path = write_test_file("parts_empty", b"");
+        let parts: Vec<FilePart> = FileParts::new(File::open(&path).unwrap(), 4)
+            .map(|p| p.unwrap())
+            .collect();
+        assert_eq!(parts.len(), 1);
+        assert_eq!((parts[0].part_number, parts[0].body.len()), (1, 0));
+        let parts = create_reader_parts(io::Cursor::new(Vec::new())).unwrap();
+        assert_eq!(parts.count(), 1);
+    }
+
+    #[test]
+    fn upload_part_size_test() {
+        assert_eq!(upload_part_size(0).unwrap(), PART_SIZE);
+        assert_eq!(upload_part_size(PART_SIZE as u64 * MAX_PARTS as u64).unwrap(), PART_SIZE);
+        // One byte more would be part 10,001, so the parts grow instead
+        let size = PART_SIZE as u64 * MAX_PARTS as u64 + 1;
+        let part_size = upload_part_size(size).unwrap();
+        assert_eq!(part_size > PART_SIZE, true);
+        assert_eq!((size + part_size as u64 - 1) / part_size as u64 <= MAX_PARTS as u64, true);
+        assert_eq!(upload_part_size(MAX_OBJECT_SIZE).is_ok(), true);
+        assert_eq!(upload_part_size(MAX_OBJECT_SIZE + 1).is_err(), true);
+    }
+
+    #[test]
+    fn create_file_parts_missing_file_test() {
+        assert_eq!(create_file_parts("file_tests/does_not_exist").is_err(), true);
+    }
+
+    #[test]
+    fn copy_part_ranges_test() {
+        let ranges = copy_part_ranges(COPY_PART_SIZE * 2 + 10);
+        assert_eq!(
+            ranges,
+            vec![
+                (1, "bytes=0-536870911".to_owned()),
+                (2, "bytes=536870912-1073741823".to_owned()),
+                (3, "bytes=1073741824-1073741833".to_owned()),
+            ]
+        );
+        // 5 TiB, the largest S3 object, still fits in 10,000 parts
+        assert_eq!(copy_part_ranges(5497558138880).len() as i64 <= MAX_PARTS, true);
+        assert_eq!(copy_part_ranges(0).is_empty(), true);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::config::yaml_scalar;
+use std::fmt;
+use std::str::FromStr;
+use yaml_rust::Yaml;
+
+// AWS partitions are isolated from each other, credentials and server side
+// operations only work within one partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Partition {
+    Aws,
+    AwsUsGov,
+    AwsCn,
+}
+
+impl Partition {
+    pub fn from_region(region: &str) -> Partition {
+        if region.starts_with("us-gov-") {
+            Partition::AwsUsGov
+        } else if region.starts_with("cn-") {
+            Partition::AwsCn
+        } else {
+            Partition::Aws
+        }
+    }
+
+    // Region used to locate buckets when only the partition is known
+    pub fn default_region(self) -> &'static str {
+        match self {
+            Partition::Aws => "us-east-1",
+            Partition::AwsUsGov => "us-gov-west-1",
+            Partition::AwsCn => "cn-north-1",
+        }
+    }
+
+    // The partition name as it appears in ARNs
+    pub fn name(self) -> &'static str {
+        match self {
+            Partition::Aws => "aws",
+            Partition::AwsUsGov => "aws-us-gov",
+            Partition::AwsCn => "aws-cn",
+        }
+    }
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Partition {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Partition, String> {
+        match name.to_lowercase().as_str() {
+            "aws" | "commercial" => Ok(Partition::Aws),
+            "aws-us-gov" | "us-gov" | "govcloud" => Ok(Partition::AwsUsGov),
+            "aws-cn" | "cn" | "china" => Ok(Partition::AwsCn),
+            _ => Err(format!("unknown partition {:?}", name)),
+        }
+    }
+}
+
+// Partition of an ARN, like the caller identity returned by STS
+pub fn arn_partition(arn: &str) -> Result<Partition, String> {
+    let mut fields = arn.split(':');
+    match (fields.next(), fields.next()) {
+        (Some("arn"), Some(partition)) => partition.parse(),
+        _ => Err(format!("{:?} is not an ARN", arn)),
+    }
+}
+
+// Directions data is allowed to move between partitions
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionPolicy {
+    pub allowed: Vec<(Partition, Partition)>,
+}
+
+// Without a policy data can stay within a partition or leave the commercial
+// partition, but nothing leaves GovCloud or China
+impl Default for PartitionPolicy {
+    fn default() -> PartitionPolicy {
+        PartitionPolicy {
+            allowed: vec![
+                (Partition::Aws, Partition::Aws),
+                (Partition::AwsUsGov, Partition::AwsUsGov),
+                (Partition::AwsCn, Partition::AwsCn),
+                (Partition::Aws, Partition::AwsUsGov),
+                (Partition::Aws, Partition::AwsCn),
+            ],
+        }
+    }
+}
+
+impl PartitionPolicy {
+    // Reads the `partitions` section of the job config. The listed directions
+    // replace the defaults:
+    //
+    //   partitions:
+    //     allowed:
+    //       - from: aws
+    //         to: aws-us-gov
+    pub fn from_yaml(yaml: &Yaml) -> Result<PartitionPolicy, String> {
+        let entries = match &yaml["allowed"] {
+            Yaml::BadValue | Yaml::Null => return Ok(PartitionPolicy::default()),
+            Yaml::Array(entries) => entries,
+            _ => return Err("partitions.allowed must be a list".to_owned()),
+        };
+        let allowed = entries
+            .iter()
+            .map(|entry| match (yaml_scalar(&entry["from"]), yaml_scalar(&entry["to"])) {
+                (Some(from), Some(to)) => Ok((from.parse()?, to.parse()?)),
+                _ => Err("partitions.allowed entries need a from and a to".to_owned()),
+            })
+            .collect::<Result<Vec<(Partition, Partition)>, String>>()?;
+        Ok(PartitionPolicy { allowed })
+    }
+
+    pub fn allows(&self, source: Partition, destination: Partition) -> bool {
+        self.allowed.contains(&(source, destination))
+    }
+
+    // Checks the transfer against the policy using the partitions of the bucket
+    // regions, and makes sure the credentials used for each bucket belong to the
+    // same partition as the bucket. Sides that aren't AWS have no identity.
+    pub fn validate(
+        &self,
+        source_region: &str,
+        destination_region: &str,
+        source_identity: Option<&str>,
+        destination_identity: Option<&str>,
+    ) -> Result<(), String> {
+        let source = Partition::from_region(source_region);
+        let destination = Partition::from_region(destination_region);
+        for (side, partition, identity) in &[
+            ("source", source, source_identity),
+            ("destination", destination, destination_identity),
+        ] {
+            if let Some(arn) = identity {
+                let identity_partition = arn_partition(arn)?;
+                if identity_partition != *partition {
+                    return Err(format!(
+                        "The {} credentials {} are in {} but the {} bucket is in {}",
+                        side, arn, identity_partition, side, partition
+                    ));
+                }
+            }
+        }
+        if !self.allows(source, destination) {
+            return Err(format!(
+                "Transfers from {} to {} are not allowed by the job's partition policy",
+                source, destination
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn policy(content: &str) -> Result<PartitionPolicy, String> {
+        let doc = &YamlLoader::load_from_str(content).unwrap()[0];
+        PartitionPolicy::from_yaml(&doc["partitions"])
+    }
+
+    #[test]
+    fn from_region_test() {
+        assert_eq!(Partition::from_region("us-west-2"), Partition::Aws);
+        assert_eq!(Partition::from_region("us-gov-west-1"), Partition::AwsUsGov);
+        assert_eq!(Partition::from_region("cn-northwest-1"), Partition::AwsCn);
+        assert_eq!(Partition::from_region("us-gov-east-1").to_string(), "aws-us-gov");
+    }
+
+    #[test]
+    fn from_str_test() {
+        assert_eq!("commercial".parse(), Ok(Partition::Aws));
+        assert_eq!("us-gov".parse(), Ok(Partition::AwsUsGov));
+        assert_eq!("aws-cn".parse(), Ok(Partition::AwsCn));
+        assert_eq!("mars".parse::<Partition>().is_err(), true);
+        assert_eq!(Partition::from_region(Partition::AwsCn.default_region()), Partition::AwsCn);
+    }
+
+    #[test]
+    fn arn_partition_test() {
+        assert_eq!(
+            arn_partition("arn:aws-us-gov:sts::111122223333:assumed-role/bearcat/sync"),
+            Ok(Partition::AwsUsGov)
+        );
+        assert_eq!(arn_partition("arn:aws:iam::111122223333:user/bearcat"), Ok(Partition::Aws));
+        assert_eq!(arn_partition("bearcat").is_err(), true);
+    }
+
+    #[test]
+    fn default_policy_test() {
+        let policy = policy("other: value").unwrap();
+        assert_eq!(policy, PartitionPolicy::default());
+        assert_eq!(policy.validate("us-west-2", "us-gov-west-1", None, None), Ok(()));
+        assert_eq!(policy.validate("us-gov-west-1", "us-west-2", None, None).is_err(), true);
+        assert_eq!(policy.validate("cn-north-1", "us-gov-east-1", None, None).is_err(), true);
+    }
+
+    #[test]
+    fn configured_policy_test() {
+        let policy = policy("partitions:\n  allowed:\n    - from: aws\n      to: aws-us-gov\n").unwrap();
+        assert_eq!(policy.allowed, vec![(Partition::Aws, Partition::AwsUsGov)]);
+        assert_eq!(policy.validate("us-east-1", "us-west-2", None, None).is_err(), true);
+        assert_eq!(
+            policy.validate(
+                "us-east-1",
+                "us-gov-west-1",
+                Some("arn:aws:iam::111122223333:user/bearcat"),
+                Some("arn:aws-us-gov:iam::444455556666:user/bearcat")
+            ),
+            Ok(())
+        );
+        // Credentials from the wrong partition mean the region was misreported
+        assert_eq!(
+            policy
+                .validate(
+                    "us-east-1",
+                    "us-gov-west-1",
+                    None,
+                    Some("arn:aws:iam::444455556666:user/bearcat")
+                )
+                .is_err(),
+            true
+        );
+    }
+
+    #[test]
+    fn invalid_policy_test() {
+        assert_eq!(policy("partitions:\n  allowed: aws\n").is_err(), true);
+        assert_eq!(policy("partitions:\n  allowed:\n    - from: aws\n").is_err(), true);
+        assert_eq!(
+            policy("partitions:\n  allowed:\n    - from: aws\n      to: mars\n").is_err(),
+            true
+        );
+    }
+}
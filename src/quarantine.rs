@@ -0,0 +1,386 @@
+use crate::bucket::{
+    get_object_tags, head_bucket_object, promote_object, put_object_tags,
+};
+use crate::config::{yaml_scalar, JobConfig};
+use crate::encryption::ServerSideEncryption;
+use crate::envelope::WRAPPED_KEY_METADATA_KEY;
+use crate::objects::ObjectDetails;
+use crate::release::ReleasePolicy;
+use rusoto_s3::Tag;
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fs;
+use yaml_rust::Yaml;
+use log::{info, warn};
+
+// Tag set on objects that failed their checks and were held in quarantine
+pub const QUARANTINE_REASON_TAG: &str = "bearcat-quarantine-reason";
+
+// S3 allows at most 10 tags on an object and 256 characters in a tag value
+const MAX_TAGS: usize = 10;
+const MAX_TAG_VALUE_LENGTH: usize = 256;
+
+// Objects are written to a quarantine location first and only copied to
+// their final key once every configured check passes
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuarantineConfig {
+    pub enabled: bool,
+    // Quarantine bucket, the destination bucket when not set
+    pub bucket: Option<String>,
+    pub prefix: String,
+    // Only objects with one of these SHA-256 digests are promoted
+    pub sha256_allowlist: Option<BTreeSet<String>>,
+    // Check the release rules against the quarantined copy
+    pub check_release: bool,
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> QuarantineConfig {
+        QuarantineConfig {
+            enabled: false,
+            bucket: None,
+            prefix: "quarantine/".to_owned(),
+            sha256_allowlist: None,
+            check_release: false,
+        }
+    }
+}
+
+impl QuarantineConfig {
+    // Reads the `quarantine` section of the job config:
+    //
+    //   quarantine:
+    //     enabled: true
+    //     bucket: deploy-sync-quarantine
+    //     prefix: incoming/
+    //     sha256_allowlist: /etc/bearcat/allowlist.txt
+    //     check_release: true
+    pub fn from_yaml(yaml: &Yaml) -> Result<QuarantineConfig, String> {
+        let defaults = QuarantineConfig::default();
+        let flag = |field: &str, default: bool| match &yaml[field] {
+            Yaml::Boolean(b) => Ok(*b),
+            Yaml::BadValue => Ok(default),
+            _ => Err(format!("quarantine.{} must be true or false", field)),
+        };
+        let sha256_allowlist = match yaml_scalar(&yaml["sha256_allowlist"]) {
+            Some(path) => {
+                let content = fs::read_to_string(&path)
+                    .map_err(|e| format!("Could not read the SHA-256 allowlist {:?}: {}", path, e))?;
+                Some(parse_allowlist(&content)?)
+            }
+            None => None,
+        };
+        let config = QuarantineConfig {
+            enabled: flag("enabled", defaults.enabled)?,
+            bucket: yaml_scalar(&yaml["bucket"]),
+            prefix: yaml_scalar(&yaml["prefix"]).unwrap_or(defaults.prefix),
+            sha256_allowlist,
+            check_release: flag("check_release", defaults.check_release)?,
+        };
+        if config.enabled && config.bucket.is_none() && config.prefix.is_empty() {
+            return Err("quarantine.prefix can't be empty when quarantining in the destination bucket".to_owned());
+        }
+        Ok(config)
+    }
+
+    // Bucket and key an object is written to before it is promoted
+    pub fn location(&self, destination_bucket: &str, key: &str) -> (String, String) {
+        if !self.enabled {
+            return (destination_bucket.to_owned(), key.to_owned());
+        }
+        let bucket = self.bucket.clone().unwrap_or_else(|| destination_bucket.to_owned());
+        (bucket, format!("{}{}", self.prefix, key))
+    }
+
+    // Runs the checks against the quarantined copy, returning why it failed.
+    // `source` is the object bearcat staged, server side copies have none.
+    // The digests in the metadata came with the source object and could
+    // claim anything, so the allowlist only trusts what bearcat hashed.
+    pub fn check(
+        &self,
+        source: Option<&ObjectDetails>,
+        metadata: &HashMap<String, String>,
+        tags: &[Tag],
+        release: &ReleasePolicy,
+    ) -> Result<(), String> {
+        if let Some(reason) = source.and_then(|s| s.blocked.as_ref()) {
+            return Err(reason.clone());
+        }
+        if let Some(allowlist) = &self.sha256_allowlist {
+            match source.and_then(computed_sha256) {
+                None => return Err("the SHA-256 of the object wasn't computed by bearcat".to_owned()),
+                Some(sha256) if !allowlist.contains(&sha256.to_lowercase()) => {
+                    return Err(format!("SHA-256 {} is not on the allowlist", sha256))
+                }
+                Some(_) => {}
+            }
+        }
+        if self.check_release {
+            release.evaluate(metadata, tags)?;
+        }
+        Ok(())
+    }
+}
+
+// One hex encoded SHA-256 digest per line, blank lines and # comments are skipped
+pub fn parse_allowlist(content: &str) -> Result<BTreeSet<String>, String> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            // sha256sum output has the file name after the digest
+            let digest = line.split_whitespace().next().unwrap_or(line).to_lowercase();
+            if digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()) {
+                Ok(digest)
+            } else {
+                Err(format!("{:?} is not a SHA-256 digest", line))
+            }
+        })
+        .collect()
+}
+
+// Tag values only allow letters, numbers, spaces and + - = . _ : / @
+pub fn reason_tag_value(reason: &str) -> String {
+    reason
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || " +-=._:/@".contains(c) {
+                c
+            } else {
+                ' '
+            }
+        })
+        .take(MAX_TAG_VALUE_LENGTH)
+        .collect()
+}
+
+// Adds the reason tag, making room for it when the object is at the tag limit
+fn with_reason_tag(mut tags: Vec<Tag>, reason: &str) -> Vec<Tag> {
+    tags.retain(|t| t.key != QUARANTINE_REASON_TAG);
+    tags.truncate(MAX_TAGS - 1);
+    tags.push(Tag {
+        key: QUARANTINE_REASON_TAG.to_owned(),
+        value: reason_tag_value(reason),
+    });
+    tags
+}
+
+// The SHA-256 of the plaintext bearcat hashed while staging the object.
+// Sealed objects that are passed on without being opened only have the
+// digest their envelope claims.
+pub fn computed_sha256(details: &ObjectDetails) -> Option<&str> {
+    if details.headers.metadata.contains_key(WRAPPED_KEY_METADATA_KEY) {
+        None
+    } else {
+        Some(&details.sha256)
+    }
+}
+
+// Checks an object that was written to quarantine and promotes it to its
+// final key when the checks pass. Objects that fail stay in quarantine with
+// the reason in a tag. Returns whether the object was promoted.
+pub fn process_quarantined(
+    client: &rusoto_s3::S3Client,
+    destination_bucket: &str,
+    key: &str,
+    source: Option<&ObjectDetails>,
+    job: &JobConfig,
+) -> Result<bool, Box<dyn Error>> {
+    let config = &job.quarantine;
+    let (quarantine_bucket, quarantine_key) = config.location(destination_bucket, key);
+    let customer_key = match &job.encryption.destination {
+        ServerSideEncryption::Customer(customer_key) => Some(customer_key),
+        _ => None,
+    };
+    let head = head_bucket_object(client, &quarantine_bucket, &quarantine_key, customer_key)?;
+    let tags = get_object_tags(client, &quarantine_bucket, &quarantine_key, None)?;
+    match config.check(source, &head.metadata.unwrap_or_default(), &tags, &job.release) {
+        Ok(()) => {
+            promote_object(client, &quarantine_bucket, &quarantine_key, destination_bucket, key, job)?;
+            info!(target: "QUARANTINE", "{:?} passed its checks", key);
+            Ok(true)
+        }
+        Err(reason) => {
+            warn!(target: "QUARANTINE", "Holding {:?} in quarantine: {}", quarantine_key, reason);
+            put_object_tags(client, &quarantine_bucket, &quarantine_key, with_reason_tag(tags, &reason))?;
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::{record_sha256, sha256_hex, SHA256_METADATA_KEY};
+    use crate::envelope::PLAINTEXT_SHA256_METADATA_KEY;
+    use crate::metadata::ObjectHeaders;
+    use yaml_rust::YamlLoader;
+
+    const DIGEST: &str = "5d7e5b9d4e3a2c1b0f9e8d7c6b5a49382716f5e4d3c2b1a09f8e7d6c5b4a3928";
+
+    fn config(content: &str) -> Result<QuarantineConfig, String> {
+        let doc = &YamlLoader::load_from_str(content).unwrap()[0];
+        QuarantineConfig::from_yaml(&doc["quarantine"])
+    }
+
+    fn metadata(sha256: &str) -> HashMap<String, String> {
+        let mut metadata = HashMap::new();
+        metadata.insert(SHA256_METADATA_KEY.to_owned(), sha256.to_owned());
+        metadata
+    }
+
+    // An object bearcat staged, with the digest it hashed
+    fn details(sha256: &str, metadata: HashMap<String, String>) -> ObjectDetails {
+        ObjectDetails {
+            key: "go/go.tar.gz".to_owned(),
+            e_tag: None,
+            version_id: None,
+            sha256: sha256.to_owned(),
+            storage_class: None,
+            headers: ObjectHeaders {
+                metadata,
+                ..Default::default()
+            },
+            blocked: None,
+        }
+    }
+
+    #[test]
+    fn location_test() {
+        let disabled = QuarantineConfig::default();
+        assert_eq!(
+            disabled.location("deploy", "go/go.tar.gz"),
+            ("deploy".to_owned(), "go/go.tar.gz".to_owned())
+        );
+        let same_bucket = config("quarantine:\n  enabled: true\n").unwrap();
+        assert_eq!(
+            same_bucket.location("deploy", "go/go.tar.gz"),
+            ("deploy".to_owned(), "quarantine/go/go.tar.gz".to_owned())
+        );
+        let other_bucket =
+            config("quarantine:\n  enabled: true\n  bucket: held\n  prefix: ''\n").unwrap();
+        assert_eq!(
+            other_bucket.location("deploy", "go/go.tar.gz"),
+            ("held".to_owned(), "go/go.tar.gz".to_owned())
+        );
+    }
+
+    #[test]
+    fn parse_allowlist_test() {
+        let allowlist = parse_allowlist(&format!(
+            "# release 1.12.5\n{}  go1.12.5.linux-amd64.tar.gz\n\n",
+            DIGEST.to_uppercase()
+        ))
+        .unwrap();
+        assert_eq!(allowlist.contains(DIGEST), true);
+        assert_eq!(allowlist.len(), 1);
+        assert_eq!(parse_allowlist("not-a-digest").is_err(), true);
+    }
+
+    #[test]
+    fn allowlist_check_test() {
+        let config = QuarantineConfig {
+            enabled: true,
+            sha256_allowlist: Some(parse_allowlist(DIGEST).unwrap()),
+            ..Default::default()
+        };
+        let release = ReleasePolicy::default();
+        let approved = details(DIGEST, metadata(DIGEST));
+        assert_eq!(config.check(Some(&approved), &metadata(DIGEST), &[], &release), Ok(()));
+        let other = "0".repeat(64);
+        let unknown = details(&other, metadata(&other));
+        assert_eq!(config.check(Some(&unknown), &metadata(&other), &[], &release).is_err(), true);
+        assert_eq!(config.check(None, &HashMap::new(), &[], &release).is_err(), true);
+    }
+
+    #[test]
+    fn forged_digest_check_test() {
+        let config = QuarantineConfig {
+            enabled: true,
+            sha256_allowlist: Some(parse_allowlist(DIGEST).unwrap()),
+            ..Default::default()
+        };
+        let release = ReleasePolicy::default();
+        // A source object whose uploader put an allowlisted digest in its metadata
+        let content = b"not what the allowlist approved";
+        let mut forged = metadata(DIGEST);
+        forged.insert(PLAINTEXT_SHA256_METADATA_KEY.to_owned(), DIGEST.to_owned());
+        let details = details(&sha256_hex(content), forged.clone());
+        assert_eq!(computed_sha256(&details), Some(details.sha256.as_str()));
+        assert_eq!(config.check(Some(&details), &forged, &[], &release).is_err(), true);
+        // Server side copies never hash the content
+        assert_eq!(config.check(None, &forged, &[], &release).is_err(), true);
+
+        // Sealed objects passed on unopened only have the digest they claim
+        let mut sealed = details.clone();
+        sealed.headers.metadata.insert(WRAPPED_KEY_METADATA_KEY.to_owned(), "wrapped".to_owned());
+        record_sha256(&mut sealed.headers.metadata, &sealed.sha256);
+        assert_eq!(computed_sha256(&sealed), None);
+        assert_eq!(config.check(Some(&sealed), &forged, &[], &release).is_err(), true);
+    }
+
+    #[test]
+    fn blocked_check_test() {
+        let config = QuarantineConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        let release = ReleasePolicy::default();
+        let mut blocked = details(DIGEST, metadata(DIGEST));
+        assert_eq!(config.check(Some(&blocked), &metadata(DIGEST), &[], &release), Ok(()));
+        blocked.blocked = Some("infected with Eicar-Test-Signature".to_owned());
+        assert_eq!(
+            config.check(Some(&blocked), &metadata(DIGEST), &[], &release),
+            Err("infected with Eicar-Test-Signature".to_owned())
+        );
+    }
+
+    #[test]
+    fn release_check_test() {
+        let doc = &YamlLoader::load_from_str("rules:\n  - tags:\n      approved-by: '*'\n").unwrap()[0];
+        let release = ReleasePolicy::from_yaml(doc).unwrap();
+        let config = QuarantineConfig {
+            enabled: true,
+            check_release: true,
+            ..Default::default()
+        };
+        assert_eq!(config.check(None, &HashMap::new(), &[], &release).is_err(), true);
+        let approved = vec![Tag {
+            key: "approved-by".to_owned(),
+            value: "jdoe".to_owned(),
+        }];
+        assert_eq!(config.check(None, &HashMap::new(), &approved, &release), Ok(()));
+    }
+
+    #[test]
+    fn reason_tag_test() {
+        assert_eq!(
+            reason_tag_value("SHA-256 abc is not on the \"allowlist\"!"),
+            "SHA-256 abc is not on the  allowlist  "
+        );
+        assert_eq!(reason_tag_value(&"a".repeat(300)).len(), MAX_TAG_VALUE_LENGTH);
+        let tags: Vec<Tag> = (0..10)
+            .map(|i| Tag {
+                key: format!("tag-{}", i),
+                value: "value".to_owned(),
+            })
+            .collect();
+        let tagged = with_reason_tag(tags, "infected");
+        assert_eq!(tagged.len(), MAX_TAGS);
+        assert_eq!(tagged.last().map(|t| t.key.as_str()), Some(QUARANTINE_REASON_TAG));
+    }
+
+    #[test]
+    fn invalid_config_test() {
+        assert_eq!(
+            config("quarantine:\n  enabled: true\n  prefix: ''\n").is_err(),
+            true
+        );
+        assert_eq!(config("quarantine:\n  check_release: often\n").is_err(), true);
+        assert_eq!(
+            config("quarantine:\n  sha256_allowlist: file_tests/missing.txt\n").is_err(),
+            true
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use crate::bucket::{get_object_tags, head_bucket_object_version};
+use crate::config::{yaml_scalar, yaml_string_map};
+use crate::encryption::CustomerKey;
+use rusoto_s3::Tag;
+use std::collections::HashMap;
+use std::error::Error;
+use yaml_rust::Yaml;
+
+// Matches any value, the entry only has to be present
+const ANY_VALUE: &str = "*";
+
+// A set of tag and metadata values an object needs to be released
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReleaseRule {
+    pub name: String,
+    pub tags: Vec<(String, String)>,
+    pub metadata: Vec<(String, String)>,
+}
+
+impl ReleaseRule {
+    fn from_yaml(yaml: &Yaml, index: usize) -> Result<ReleaseRule, String> {
+        let name = yaml_scalar(&yaml["name"]).unwrap_or_else(|| format!("rule {}", index + 1));
+        let rule = ReleaseRule {
+            tags: yaml_string_map(&yaml["tags"], "release.rules.tags")?,
+            metadata: yaml_string_map(&yaml["metadata"], "release.rules.metadata")?
+                .into_iter()
+                .map(|(k, v)| (k.to_lowercase(), v))
+                .collect(),
+            name,
+        };
+        if rule.tags.is_empty() && rule.metadata.is_empty() {
+            return Err(format!("release rule {:?} has no conditions", rule.name));
+        }
+        Ok(rule)
+    }
+
+    // Returns why the object doesn't match, or None when it does
+    fn mismatch(&self, metadata: &HashMap<String, String>, tags: &[Tag]) -> Option<String> {
+        let tag_value = |key: &str| tags.iter().find(|t| t.key == key).map(|t| t.value.as_str());
+        let checks = self
+            .tags
+            .iter()
+            .map(|(key, expected)| ("tag", key, expected, tag_value(key)))
+            .chain(self.metadata.iter().map(|(key, expected)| {
+                ("metadata", key, expected, metadata.get(key).map(String::as_str))
+            }));
+        for (kind, key, expected, value) in checks {
+            match value {
+                None => return Some(format!("{} {:?} is missing", kind, key)),
+                Some(value) if expected != ANY_VALUE && value != expected => {
+                    return Some(format!(
+                        "{} {:?} is {:?}, expected {:?}",
+                        kind, key, value, expected
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        None
+    }
+}
+
+// Objects are only transferred when they match at least one rule. Without
+// rules every object is released.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReleasePolicy {
+    pub rules: Vec<ReleaseRule>,
+}
+
+impl ReleasePolicy {
+    // Reads the `release` section of the job config. A value of "*" only
+    // requires the tag or metadata key to be present:
+    //
+    //   release:
+    //     rules:
+    //       - name: public releases
+    //         tags:
+    //           classification: public-release
+    //           approved-by: "*"
+    //         metadata:
+    //           build-status: passed
+    pub fn from_yaml(yaml: &Yaml) -> Result<ReleasePolicy, String> {
+        let rules = match &yaml["rules"] {
+            Yaml::BadValue | Yaml::Null => Vec::new(),
+            Yaml::Array(rules) => rules
+                .iter()
+                .enumerate()
+                .map(|(index, rule)| ReleaseRule::from_yaml(rule, index))
+                .collect::<Result<Vec<ReleaseRule>, String>>()?,
+            _ => return Err("release.rules must be a list".to_owned()),
+        };
+        Ok(ReleasePolicy { rules })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    // Ok when a rule matches, otherwise the reason each rule rejected the object
+    pub fn evaluate(&self, metadata: &HashMap<String, String>, tags: &[Tag]) -> Result<(), String> {
+        let mut reasons = Vec::new();
+        for rule in &self.rules {
+            match rule.mismatch(metadata, tags) {
+                None => return Ok(()),
+                Some(reason) => reasons.push(format!("{}: {}", rule.name, reason)),
+            }
+        }
+        if reasons.is_empty() {
+            Ok(())
+        } else {
+            Err(reasons.join("; "))
+        }
+    }
+}
+
+// Reads the tags and metadata of a source object and checks them against the
+// policy. The outer error is for failed requests, the inner one is the
+// rejection reason.
+pub fn check_release(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
+    version_id: Option<&str>,
+    policy: &ReleasePolicy,
+    customer_key: Option<&CustomerKey>,
+) -> Result<Result<(), String>, Box<dyn Error>> {
+    if !policy.enabled() {
+        return Ok(Ok(()));
+    }
+    let head = head_bucket_object_version(client, bucket, key, version_id, customer_key)?;
+    let tags = get_object_tags(client, bucket, key, version_id)?;
+    Ok(policy.evaluate(&head.metadata.unwrap_or_default(), &tags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    const POLICY: &str = "
+release:
+  rules:
+    - name: public releases
+      tags:
+        classification: public-release
+        approved-by: '*'
+    - tags:
+        classification: internal
+      metadata:
+        Build-Status: passed
+";
+
+    fn policy(content: &str) -> Result<ReleasePolicy, String> {
+        let doc = &YamlLoader::load_from_str(content).unwrap()[0];
+        ReleasePolicy::from_yaml(&doc["release"])
+    }
+
+    fn tags(tags: &[(&str, &str)]) -> Vec<Tag> {
+        tags.iter()
+            .map(|(key, value)| Tag {
+                key: key.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matching_rule_test() {
+        let policy = policy(POLICY).unwrap();
+        let metadata = HashMap::new();
+        assert_eq!(
+            policy.evaluate(
+                &metadata,
+                &tags(&[("classification", "public-release"), ("approved-by", "jdoe")])
+            ),
+            Ok(())
+        );
+        let mut metadata = HashMap::new();
+        metadata.insert("build-status".to_owned(), "passed".to_owned());
+        assert_eq!(policy.evaluate(&metadata, &tags(&[("classification", "internal")])), Ok(()));
+    }
+
+    #[test]
+    fn rejection_reason_test() {
+        let policy = policy(POLICY).unwrap();
+        let reason = policy
+            .evaluate(&HashMap::new(), &tags(&[("classification", "public-release")]))
+            .unwrap_err();
+        assert_eq!(
+            reason,
+            "public releases: tag \"approved-by\" is missing; rule 2: tag \"classification\" is \"public-release\", expected \"internal\""
+        );
+    }
+
+    #[test]
+    fn no_rules_test() {
+        let policy = policy("other: value").unwrap();
+        assert_eq!(policy.enabled(), false);
+        assert_eq!(policy.evaluate(&HashMap::new(), &[]), Ok(()));
+    }
+
+    #[test]
+    fn invalid_policy_test() {
+        assert_eq!(policy("release:\n  rules: public\n").is_err(), true);
+        assert_eq!(policy("release:\n  rules:\n    - name: empty\n").is_err(), true);
+        assert_eq!(policy("release:\n  rules:\n    - tags: [approved-by]\n").is_err(), true);
+    }
+}
@@ -0,0 +1,363 @@
+use crate::config::{yaml_scalar, yaml_string_list};
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use yaml_rust::Yaml;
+
+// Size of the chunks files are streamed to clamd in
+const CLAMD_CHUNK_SIZE: usize = 65536;
+
+// Replaced with the staged file's path in scanner commands
+const PATH_PLACEHOLDER: &str = "{}";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClamdAddress {
+    Unix(String),
+    Tcp(String),
+}
+
+impl ClamdAddress {
+    // Accepts tcp://host:port, unix:///path or a plain socket path
+    pub fn parse(address: &str) -> Result<ClamdAddress, String> {
+        if address.starts_with("tcp://") {
+            Ok(ClamdAddress::Tcp(address["tcp://".len()..].to_owned()))
+        } else if address.starts_with("unix://") {
+            Ok(ClamdAddress::Unix(address["unix://".len()..].to_owned()))
+        } else if address.starts_with('/') {
+            Ok(ClamdAddress::Unix(address.to_owned()))
+        } else {
+            Err(format!("unknown clamd address {:?}", address))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scanner {
+    // ClamAV's daemon, sent the file with INSTREAM
+    Clamd(ClamdAddress),
+    // Exits with 0 for clean files and 1 for infected ones, anything else
+    // means the file couldn't be scanned
+    Command(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanVerdict {
+    Clean,
+    Infected(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScannerConfig {
+    pub scanner: Option<Scanner>,
+    pub timeout: Duration,
+}
+
+impl Default for ScannerConfig {
+    fn default() -> ScannerConfig {
+        ScannerConfig {
+            scanner: None,
+            timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+impl ScannerConfig {
+    // Reads the `scanner` section of the job config, with either a clamd
+    // socket or a command. The staged file's path replaces {} in the command,
+    // or is added to the end when there is no {}:
+    //
+    //   scanner:
+    //     clamd: tcp://127.0.0.1:3310    # or /var/run/clamav/clamd.ctl
+    //     command: [clamdscan, --no-summary]
+    //     timeout: 300
+    pub fn from_yaml(yaml: &Yaml) -> Result<ScannerConfig, String> {
+        let defaults = ScannerConfig::default();
+        let command = yaml_string_list(&yaml["command"], "scanner.command")?;
+        let scanner = match (yaml_scalar(&yaml["clamd"]), command.is_empty()) {
+            (Some(_), false) => {
+                return Err("scanner can use clamd or a command, not both".to_owned())
+            }
+            (Some(address), true) => Some(Scanner::Clamd(ClamdAddress::parse(&address)?)),
+            (None, false) => Some(Scanner::Command(command)),
+            (None, true) => None,
+        };
+        let timeout = match &yaml["timeout"] {
+            Yaml::Integer(seconds) if *seconds > 0 => Duration::from_secs(*seconds as u64),
+            Yaml::BadValue => defaults.timeout,
+            _ => return Err("scanner.timeout must be a positive number of seconds".to_owned()),
+        };
+        Ok(ScannerConfig { scanner, timeout })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.scanner.is_some()
+    }
+
+    // Scans the file, returning an error when it couldn't be scanned. clamd
+    // is streamed `content`, scanner commands are given the path.
+    pub fn scan_file<R: Read>(&self, path: &str, content: R) -> Result<ScanVerdict, String> {
+        match &self.scanner {
+            None => Ok(ScanVerdict::Clean),
+            Some(Scanner::Clamd(ClamdAddress::Tcp(address))) => {
+                let stream = TcpStream::connect(address)
+                    .map_err(|e| format!("Could not connect to clamd at {}: {}", address, e))?;
+                stream.set_read_timeout(Some(self.timeout)).map_err(|e| e.to_string())?;
+                stream.set_write_timeout(Some(self.timeout)).map_err(|e| e.to_string())?;
+                clamd_scan(stream, path, content)
+            }
+            Some(Scanner::Clamd(ClamdAddress::Unix(socket))) => {
+                let stream = UnixStream::connect(socket)
+                    .map_err(|e| format!("Could not connect to clamd at {}: {}", socket, e))?;
+                stream.set_read_timeout(Some(self.timeout)).map_err(|e| e.to_string())?;
+                stream.set_write_timeout(Some(self.timeout)).map_err(|e| e.to_string())?;
+                clamd_scan(stream, path, content)
+            }
+            Some(Scanner::Command(command)) => command_scan(command, path, self.timeout),
+        }
+    }
+}
+
+// Streams the file to clamd in length prefixed chunks, ending with an empty one
+fn clamd_scan<S: Read + Write, R: Read>(
+    mut stream: S,
+    path: &str,
+    mut file: R,
+) -> Result<ScanVerdict, String> {
+    let send = |stream: &mut S, bytes: &[u8]| {
+        stream
+            .write_all(bytes)
+            .map_err(|e| format!("Could not send {:?} to clamd: {}", path, e))
+    };
+    send(&mut stream, b"zINSTREAM\0")?;
+    let mut chunk = vec![0; CLAMD_CHUNK_SIZE];
+    loop {
+        let read = file
+            .read(&mut chunk)
+            .map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+        send(&mut stream, &(read as u32).to_be_bytes())?;
+        if read == 0 {
+            break;
+        }
+        send(&mut stream, &chunk[..read])?;
+    }
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .map_err(|e| format!("No response from clamd for {:?}: {}", path, e))?;
+    parse_clamd_response(&String::from_utf8_lossy(&response))
+}
+
+// clamd answers "stream: OK", "stream: <signature> FOUND" or "<message> ERROR"
+fn parse_clamd_response(response: &str) -> Result<ScanVerdict, String> {
+    let response = response.trim_end_matches(|c| c == '\0' || c == '\n');
+    let result = response.trim_start_matches("stream:").trim();
+    if result == "OK" {
+        Ok(ScanVerdict::Clean)
+    } else if result.ends_with(" FOUND") {
+        Ok(ScanVerdict::Infected(
+            result[..result.len() - " FOUND".len()].to_owned(),
+        ))
+    } else {
+        Err(format!("clamd could not scan the file: {}", response))
+    }
+}
+
+fn command_scan(command: &[String], path: &str, timeout: Duration) -> Result<ScanVerdict, String> {
+    let mut args: Vec<String> = command
+        .iter()
+        .map(|arg| arg.replace(PATH_PLACEHOLDER, path))
+        .collect();
+    if !command.iter().any(|arg| arg.contains(PATH_PLACEHOLDER)) {
+        args.push(path.to_owned());
+    }
+    let mut child = Command::new(&args[0])
+        .args(&args[1..])
+        .spawn()
+        .map_err(|e| format!("Could not run the scanner {:?}: {}", args[0], e))?;
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait().map_err(|e| e.to_string())? {
+            Some(status) => break status,
+            None if started.elapsed() > timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("The scanner timed out after {:?}", timeout));
+            }
+            None => thread::sleep(Duration::from_millis(100)),
+        }
+    };
+    match status.code() {
+        Some(0) => Ok(ScanVerdict::Clean),
+        Some(1) => Ok(ScanVerdict::Infected(format!("{} reported an infection", args[0]))),
+        Some(code) => Err(format!("The scanner failed with exit code {}", code)),
+        None => Err("The scanner was killed by a signal".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+
+    fn scan(scanner: &ScannerConfig, path: &str) -> Result<ScanVerdict, String> {
+        scanner.scan_file(path, File::open(path).unwrap())
+    }
+
+    const EICAR: &[u8] = b"X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+    fn write_test_file(name: &str, content: &[u8]) -> String {
+        fs::create_dir_all("file_tests").unwrap();
+        let path = format!("file_tests/{}", name);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    // Answers a single INSTREAM request the way clamd does
+    fn fake_clamd<S: Read + Write>(stream: S) {
+        let mut reader = BufReader::new(stream);
+        let mut command = [0; 10];
+        reader.read_exact(&mut command).unwrap();
+        assert_eq!(&command, b"zINSTREAM\0");
+        let mut content = Vec::new();
+        loop {
+            let mut length = [0; 4];
+            reader.read_exact(&mut length).unwrap();
+            let length = u32::from_be_bytes(length) as usize;
+            if length == 0 {
+                break;
+            }
+            let mut chunk = vec![0; length];
+            reader.read_exact(&mut chunk).unwrap();
+            content.extend(chunk);
+        }
+        let response: &[u8] = if content.windows(EICAR.len()).any(|w| w == EICAR) {
+            b"stream: Eicar-Test-Signature FOUND\0"
+        } else {
+            b"stream: OK\0"
+        };
+        reader.get_mut().write_all(response).unwrap();
+    }
+
+    fn tcp_scanner() -> ScannerConfig {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            fake_clamd(stream);
+        });
+        ScannerConfig {
+            scanner: Some(Scanner::Clamd(ClamdAddress::Tcp(address))),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn clamd_tcp_test() {
+        let clean = write_test_file("scan_clean.txt", b"bearcat");
+        assert_eq!(scan(&tcp_scanner(), &clean), Ok(ScanVerdict::Clean));
+        let infected = write_test_file("scan_eicar.txt", EICAR);
+        assert_eq!(
+            scan(&tcp_scanner(), &infected),
+            Ok(ScanVerdict::Infected("Eicar-Test-Signature".to_owned()))
+        );
+    }
+
+    #[test]
+    fn clamd_unix_test() {
+        fs::create_dir_all("file_tests").unwrap();
+        let socket = "file_tests/clamd.sock";
+        let _ = fs::remove_file(socket);
+        let listener = UnixListener::bind(socket).unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            fake_clamd(stream);
+        });
+        let scanner = ScannerConfig {
+            scanner: Some(Scanner::Clamd(ClamdAddress::Unix(socket.to_owned()))),
+            ..Default::default()
+        };
+        // Larger than one chunk
+        let infected = write_test_file("scan_eicar_large.bin", &[&[0; 70000][..], EICAR].concat());
+        assert_eq!(
+            scan(&scanner, &infected),
+            Ok(ScanVerdict::Infected("Eicar-Test-Signature".to_owned()))
+        );
+    }
+
+    #[test]
+    fn clamd_unreachable_test() {
+        let scanner = ScannerConfig {
+            scanner: Some(Scanner::Clamd(ClamdAddress::Unix("file_tests/missing.sock".to_owned()))),
+            ..Default::default()
+        };
+        let clean = write_test_file("scan_unreachable.txt", b"bearcat");
+        assert_eq!(scan(&scanner, &clean).is_err(), true);
+    }
+
+    #[test]
+    fn parse_clamd_response_test() {
+        assert_eq!(parse_clamd_response("stream: OK\0"), Ok(ScanVerdict::Clean));
+        assert_eq!(
+            parse_clamd_response("stream: Win.Test.EICAR_HDB-1 FOUND\0"),
+            Ok(ScanVerdict::Infected("Win.Test.EICAR_HDB-1".to_owned()))
+        );
+        assert_eq!(parse_clamd_response("INSTREAM size limit exceeded. ERROR\0").is_err(), true);
+    }
+
+    #[test]
+    fn command_scan_test() {
+        let scanner = |command: &[&str]| ScannerConfig {
+            scanner: Some(Scanner::Command(command.iter().map(|s| s.to_string()).collect())),
+            ..Default::default()
+        };
+        let grep = scanner(&["sh", "-c", "if grep -q EICAR \"$0\"; then exit 1; fi", "{}"]);
+        let clean = write_test_file("command_clean.txt", b"bearcat");
+        let infected = write_test_file("command_eicar.txt", EICAR);
+        assert_eq!(scan(&grep, &clean), Ok(ScanVerdict::Clean));
+        assert_eq!(
+            scan(&grep, &infected),
+            Ok(ScanVerdict::Infected("sh reported an infection".to_owned()))
+        );
+        assert_eq!(scan(&scanner(&["sh", "-c", "exit 2"]), &clean).is_err(), true);
+        assert_eq!(scan(&scanner(&["file_tests/no-such-scanner"]), &clean).is_err(), true);
+        let slow = ScannerConfig {
+            timeout: Duration::from_millis(200),
+            ..scanner(&["sh", "-c", "sleep 5", "{}"])
+        };
+        assert_eq!(scan(&slow, &clean).is_err(), true);
+    }
+
+    #[test]
+    fn scanner_config_test() {
+        let config = |content: &str| {
+            let doc = &yaml_rust::YamlLoader::load_from_str(content).unwrap()[0];
+            ScannerConfig::from_yaml(&doc["scanner"])
+        };
+        assert_eq!(config("other: value"), Ok(ScannerConfig::default()));
+        assert_eq!(
+            config("scanner:\n  clamd: /var/run/clamav/clamd.ctl\n  timeout: 60\n"),
+            Ok(ScannerConfig {
+                scanner: Some(Scanner::Clamd(ClamdAddress::Unix(
+                    "/var/run/clamav/clamd.ctl".to_owned()
+                ))),
+                timeout: Duration::from_secs(60),
+            })
+        );
+        assert_eq!(
+            config("scanner:\n  command: [clamdscan, --no-summary]\n").unwrap().scanner,
+            Some(Scanner::Command(vec!["clamdscan".to_owned(), "--no-summary".to_owned()]))
+        );
+        assert_eq!(config("scanner:\n  clamd: localhost:3310\n").is_err(), true);
+        assert_eq!(
+            config("scanner:\n  clamd: tcp://localhost:3310\n  command: [scan]\n").is_err(),
+            true
+        );
+    }
+}
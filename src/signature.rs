@@ -0,0 +1,257 @@
+use crate::bucket::{delete_bucket_object, get_bucket_object, put_bucket_object};
+use crate::config::{yaml_scalar, yaml_string_list, JobConfig};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use yaml_rust::Yaml;
+use log::info;
+
+// Numbers the temporary files OpenPGP signatures are written to for gpgv
+static SIGNATURE_FILES: AtomicUsize = AtomicUsize::new(0);
+
+// Objects are only synced when a companion signature made by a trusted key
+// exists next to them in the source bucket
+#[derive(Debug, Clone)]
+pub struct SignaturePolicy {
+    pub enabled: bool,
+    // Added to an object's key to find its signature
+    pub suffix: String,
+    pub minisign_keys: Vec<minisign_verify::PublicKey>,
+    // Keyring with the trusted OpenPGP keys, checked with gpgv
+    pub gpg_keyring: Option<String>,
+}
+
+impl Default for SignaturePolicy {
+    fn default() -> SignaturePolicy {
+        SignaturePolicy {
+            enabled: false,
+            suffix: ".sig".to_owned(),
+            minisign_keys: Vec::new(),
+            gpg_keyring: None,
+        }
+    }
+}
+
+impl SignaturePolicy {
+    // Reads the `signatures` section of the job config:
+    //
+    //   signatures:
+    //     enabled: true
+    //     suffix: .sig
+    //     minisign_keys: [RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3]
+    //     gpg_keyring: /etc/bearcat/trusted.gpg
+    pub fn from_yaml(yaml: &Yaml) -> Result<SignaturePolicy, String> {
+        let defaults = SignaturePolicy::default();
+        let enabled = match &yaml["enabled"] {
+            Yaml::Boolean(b) => *b,
+            Yaml::BadValue => defaults.enabled,
+            _ => return Err("signatures.enabled must be true or false".to_owned()),
+        };
+        let minisign_keys = yaml_string_list(&yaml["minisign_keys"], "signatures.minisign_keys")?
+            .iter()
+            .map(|key| {
+                minisign_verify::PublicKey::from_base64(key)
+                    .map_err(|e| format!("Invalid minisign key {:?}: {}", key, e))
+            })
+            .collect::<Result<Vec<minisign_verify::PublicKey>, String>>()?;
+        let policy = SignaturePolicy {
+            enabled,
+            suffix: yaml_scalar(&yaml["suffix"]).unwrap_or(defaults.suffix),
+            minisign_keys,
+            gpg_keyring: yaml_scalar(&yaml["gpg_keyring"]),
+        };
+        if policy.suffix.is_empty() {
+            return Err("signatures.suffix can't be empty".to_owned());
+        }
+        if policy.enabled && policy.minisign_keys.is_empty() && policy.gpg_keyring.is_none() {
+            return Err("signatures needs minisign_keys or a gpg_keyring to trust".to_owned());
+        }
+        Ok(policy)
+    }
+
+    pub fn signature_key(&self, key: &str) -> String {
+        format!("{}{}", key, self.suffix)
+    }
+
+    // Signatures are synced with the object they sign, not on their own
+    pub fn is_signature(&self, key: &str) -> bool {
+        self.enabled && key.ends_with(&self.suffix)
+    }
+
+    // Checks a detached minisign or OpenPGP signature over the content
+    pub fn verify<R: Read>(&self, content: R, signature: &[u8]) -> Result<(), String> {
+        let text = String::from_utf8_lossy(signature);
+        if text.starts_with("untrusted comment:") {
+            self.verify_minisign(content, &text)
+        } else {
+            self.verify_openpgp(content, signature)
+        }
+    }
+
+    fn verify_minisign<R: Read>(&self, mut content: R, text: &str) -> Result<(), String> {
+        let signature = minisign_verify::Signature::decode(text)
+            .map_err(|e| format!("Invalid minisign signature: {}", e))?;
+        let mut data = Vec::new();
+        content
+            .read_to_end(&mut data)
+            .map_err(|e| format!("Could not read the signed content: {}", e))?;
+        if self
+            .minisign_keys
+            .iter()
+            .any(|key| key.verify(&data, &signature, true).is_ok())
+        {
+            Ok(())
+        } else {
+            Err("the minisign signature isn't from a trusted key".to_owned())
+        }
+    }
+
+    // The signed content is piped to gpgv so it never has to be on disk in the clear
+    fn verify_openpgp<R: Read>(&self, mut content: R, signature: &[u8]) -> Result<(), String> {
+        let keyring = self
+            .gpg_keyring
+            .as_ref()
+            .ok_or_else(|| "OpenPGP signatures aren't trusted without a gpg_keyring".to_owned())?;
+        let signature_path = env::temp_dir().join(format!(
+            "bearcat-{}-{}.sig",
+            process::id(),
+            SIGNATURE_FILES.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::write(&signature_path, signature)
+            .map_err(|e| format!("Could not write the signature for gpgv: {}", e))?;
+        let child = Command::new("gpgv")
+            .arg("--keyring")
+            .arg(keyring)
+            .arg(&signature_path)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .spawn();
+        let status = child.and_then(|mut child| {
+            // gpgv stops reading early on a bad keyring, which is reported by its exit status
+            if let Some(mut stdin) = child.stdin.take() {
+                let _ = io::copy(&mut content, &mut stdin);
+            }
+            child.wait()
+        });
+        let _ = fs::remove_file(&signature_path);
+        match status {
+            Ok(status) if status.success() => Ok(()),
+            Ok(_) => Err("the OpenPGP signature isn't from a trusted key".to_owned()),
+            Err(e) => Err(format!("Could not run gpgv: {}", e)),
+        }
+    }
+}
+
+// Copies an object's signature to the destination once the object is at its
+// final key. The source copy is removed when `delete_source` is set, matching
+// the move of the object itself.
+pub fn sync_signature(
+    source_client: &rusoto_s3::S3Client,
+    source_bucket: &str,
+    destination_client: &rusoto_s3::S3Client,
+    destination_bucket: &str,
+    key: &str,
+    delete_source: bool,
+    job: &JobConfig,
+) -> Result<(), Box<dyn Error>> {
+    let signature_key = job.signatures.signature_key(key);
+    let customer_key = job.encryption.source_customer_key.as_ref();
+    let signature = get_bucket_object(source_client, source_bucket, &signature_key, customer_key)?;
+    put_bucket_object(
+        destination_client,
+        destination_bucket,
+        &signature_key,
+        signature,
+        "application/octet-stream",
+        job,
+    )?;
+    if delete_source {
+        delete_bucket_object(source_client, source_bucket, &signature_key)?;
+    }
+    info!(target: "SIGNATURE", "Synced the signature {:?}", signature_key);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use yaml_rust::YamlLoader;
+
+    const PUBLIC_KEY: &str = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3";
+    // Prehashed signature of "test" made with PUBLIC_KEY's secret key
+    const SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUQf6LRCGA9i559r3g7V1qNyJDApGip8MfqcadIgT9CuhV3EMhHoN1mGTkUidF/z7SrlQgXdy8ofjb7bNJJylDOocrCo8KLzZwo=
+trusted comment: timestamp:1556193335\tfile:test
+y/rUw2y8/hOUYjZU71eHp/Wo1KZ40fGy2VJEDl34XMJM+TX48Ss/17u3IvIfbVR1FkZZSNCisQbuQY+bHwhEBg==";
+
+    fn policy(content: &str) -> Result<SignaturePolicy, String> {
+        let doc = &YamlLoader::load_from_str(content).unwrap()[0];
+        SignaturePolicy::from_yaml(&doc["signatures"])
+    }
+
+    fn trusted() -> SignaturePolicy {
+        policy(&format!(
+            "signatures:\n  enabled: true\n  minisign_keys: [{}]\n",
+            PUBLIC_KEY
+        ))
+        .unwrap()
+    }
+
+    fn staged(name: &str, content: &[u8]) -> String {
+        fs::create_dir_all("file_tests").unwrap();
+        let path = format!("file_tests/{}", name);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn minisign_test() {
+        let policy = trusted();
+        let signed = staged("signed.txt", b"test");
+        assert_eq!(policy.verify(File::open(&signed).unwrap(), SIGNATURE.as_bytes()), Ok(()));
+        let tampered = staged("tampered.txt", b"Test");
+        assert_eq!(policy.verify(File::open(&tampered).unwrap(), SIGNATURE.as_bytes()).is_err(), true);
+        let untrusted = SignaturePolicy {
+            minisign_keys: vec![minisign_verify::PublicKey::from_base64(
+                "RWQBAgMEBQYHCAcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcH",
+            )
+            .unwrap()],
+            ..trusted()
+        };
+        assert_eq!(untrusted.verify(File::open(&signed).unwrap(), SIGNATURE.as_bytes()).is_err(), true);
+    }
+
+    #[test]
+    fn openpgp_without_keyring_test() {
+        let signed = staged("signed.bin", b"test");
+        let armored = b"-----BEGIN PGP SIGNATURE-----\n\n-----END PGP SIGNATURE-----\n";
+        assert_eq!(trusted().verify(File::open(&signed).unwrap(), armored).is_err(), true);
+        let keyring = SignaturePolicy {
+            gpg_keyring: Some("file_tests/missing.gpg".to_owned()),
+            ..trusted()
+        };
+        assert_eq!(keyring.verify(File::open(&signed).unwrap(), armored).is_err(), true);
+    }
+
+    #[test]
+    fn signature_key_test() {
+        let policy = trusted();
+        assert_eq!(policy.signature_key("go/go.tar.gz"), "go/go.tar.gz.sig");
+        assert_eq!(policy.is_signature("go/go.tar.gz.sig"), true);
+        assert_eq!(policy.is_signature("go/go.tar.gz"), false);
+        assert_eq!(SignaturePolicy::default().is_signature("go/go.tar.gz.sig"), false);
+    }
+
+    #[test]
+    fn invalid_policy_test() {
+        assert_eq!(policy("signatures:\n  enabled: true\n").is_err(), true);
+        assert_eq!(policy("signatures:\n  minisign_keys: [not-a-key]\n").is_err(), true);
+        assert_eq!(policy("signatures:\n  suffix: ''\n").is_err(), true);
+        let keyring = policy("signatures:\n  enabled: true\n  gpg_keyring: /etc/bearcat/trusted.gpg\n");
+        assert_eq!(keyring.unwrap().gpg_keyring, Some("/etc/bearcat/trusted.gpg".to_owned()));
+    }
+}
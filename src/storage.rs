@@ -0,0 +1,326 @@
+use crate::bucket::{head_bucket_object_version, restore_bucket_object};
+use crate::config::{yaml_scalar, yaml_string_map};
+use crate::encryption::CustomerKey;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use yaml_rust::Yaml;
+use log::{info, warn};
+
+const STORAGE_CLASSES: [&str; 8] = [
+    "STANDARD",
+    "REDUCED_REDUNDANCY",
+    "STANDARD_IA",
+    "ONEZONE_IA",
+    "INTELLIGENT_TIERING",
+    "GLACIER",
+    "DEEP_ARCHIVE",
+    "GLACIER_IR",
+];
+
+// Storage classes whose objects have to be restored before they can be read
+const ARCHIVE_STORAGE_CLASSES: [&str; 2] = ["GLACIER", "DEEP_ARCHIVE"];
+
+const RESTORE_TIERS: [&str; 3] = ["Standard", "Bulk", "Expedited"];
+
+pub fn is_archived(storage_class: Option<&str>) -> bool {
+    storage_class.map_or(false, |c| ARCHIVE_STORAGE_CLASSES.contains(&c))
+}
+
+fn validate_storage_class(class: &str) -> Result<String, String> {
+    let class = class.to_uppercase();
+    if STORAGE_CLASSES.contains(&class.as_str()) {
+        Ok(class)
+    } else {
+        Err(format!("unknown storage class {:?}", class))
+    }
+}
+
+// Chooses the storage class of destination objects from the source class.
+// Objects without a mapping use the default, or the bucket default when no
+// default is set.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StorageClassConfig {
+    pub default: Option<String>,
+    pub map: Vec<(String, String)>,
+}
+
+impl StorageClassConfig {
+    // Reads the `storage_class` section of the job config:
+    //
+    //   storage_class:
+    //     default: STANDARD_IA
+    //     map:
+    //       GLACIER: DEEP_ARCHIVE
+    //       STANDARD: INTELLIGENT_TIERING
+    pub fn from_yaml(yaml: &Yaml) -> Result<StorageClassConfig, String> {
+        let default = match yaml_scalar(&yaml["default"]) {
+            Some(class) => Some(validate_storage_class(&class)?),
+            None => None,
+        };
+        let map = yaml_string_map(&yaml["map"], "storage_class.map")?
+            .into_iter()
+            .map(|(from, to)| Ok((validate_storage_class(&from)?, validate_storage_class(&to)?)))
+            .collect::<Result<Vec<(String, String)>, String>>()?;
+        Ok(StorageClassConfig { default, map })
+    }
+
+    // S3 leaves out the storage class of STANDARD objects
+    pub fn destination_class(&self, source_class: Option<&str>) -> Option<String> {
+        let source_class = source_class.unwrap_or("STANDARD");
+        self.map
+            .iter()
+            .find(|(from, _)| from == source_class)
+            .map(|(_, to)| to.to_owned())
+            .or_else(|| self.default.clone())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestoreConfig {
+    // Issue restore requests for archived source objects
+    pub enabled: bool,
+    // Number of days the restored copy is kept
+    pub days: i64,
+    pub tier: String,
+    // Where requested restores are tracked between runs
+    pub state_file: String,
+}
+
+impl Default for RestoreConfig {
+    fn default() -> RestoreConfig {
+        RestoreConfig {
+            enabled: false,
+            days: 7,
+            tier: "Standard".to_owned(),
+            state_file: "bearcat-restores.json".to_owned(),
+        }
+    }
+}
+
+impl RestoreConfig {
+    // Reads the `restore` section of the job config:
+    //
+    //   restore:
+    //     enabled: true
+    //     days: 7
+    //     tier: Bulk
+    //     state_file: /var/lib/bearcat/restores.json
+    pub fn from_yaml(yaml: &Yaml) -> Result<RestoreConfig, String> {
+        let defaults = RestoreConfig::default();
+        let enabled = match &yaml["enabled"] {
+            Yaml::Boolean(b) => *b,
+            Yaml::BadValue => defaults.enabled,
+            _ => return Err("restore.enabled must be true or false".to_owned()),
+        };
+        let days = match &yaml["days"] {
+            Yaml::Integer(days) if *days > 0 => *days,
+            Yaml::BadValue => defaults.days,
+            _ => return Err("restore.days must be a positive number".to_owned()),
+        };
+        let tier = yaml_scalar(&yaml["tier"]).unwrap_or(defaults.tier);
+        if !RESTORE_TIERS.contains(&tier.as_str()) {
+            return Err(format!("unknown restore tier {:?}", tier));
+        }
+        Ok(RestoreConfig {
+            enabled,
+            days,
+            tier,
+            state_file: yaml_scalar(&yaml["state_file"]).unwrap_or(defaults.state_file),
+        })
+    }
+}
+
+// Parses the x-amz-restore header, returning whether the restore is still running
+pub fn restore_in_progress(restore: &str) -> bool {
+    restore.contains("ongoing-request=\"true\"")
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RestoreRecord {
+    // Seconds since the epoch when the restore was requested
+    pub requested_at: u64,
+    pub e_tag: Option<String>,
+}
+
+// Restores requested by earlier runs, persisted as JSON
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RestoreState {
+    pub requests: BTreeMap<String, RestoreRecord>,
+}
+
+impl RestoreState {
+    pub fn load(path: &str) -> Result<RestoreState, Box<dyn Error>> {
+        if !Path::new(path).exists() {
+            return Ok(RestoreState::default());
+        }
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)
+            .map_err(|e| format!("Could not parse the restore state {:?}: {}", path, e))?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn record(&mut self, key: &str, e_tag: Option<String>) {
+        let requested_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.requests.insert(
+            key.to_owned(),
+            RestoreRecord {
+                requested_at,
+                e_tag,
+            },
+        );
+    }
+
+    // Returns true when the key was being tracked
+    pub fn complete(&mut self, key: &str) -> bool {
+        self.requests.remove(key).is_some()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ArchiveStatus {
+    // Restored and readable
+    Available,
+    // A restore was requested and hasn't finished
+    Pending,
+    // Not restored and restores are disabled for the job
+    Archived,
+}
+
+// The restore state tracks historical versions apart from the latest object
+pub fn restore_key(key: &str, version_id: Option<&str>) -> String {
+    match version_id {
+        Some(version_id) => format!("{}?versionId={}", key, version_id),
+        None => key.to_owned(),
+    }
+}
+
+// Checks whether an archived object, or one of its versions, can be read,
+// requesting a restore when it can't and restores are enabled
+pub fn prepare_archived_object(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
+    version_id: Option<&str>,
+    config: &RestoreConfig,
+    customer_key: Option<&CustomerKey>,
+    state: &mut RestoreState,
+) -> Result<ArchiveStatus, Box<dyn Error>> {
+    let head = head_bucket_object_version(client, bucket, key, version_id, customer_key)?;
+    let tracked = restore_key(key, version_id);
+    match head.restore.as_ref().map(String::as_str) {
+        Some(restore) if restore_in_progress(restore) => {
+            if !state.requests.contains_key(&tracked) {
+                state.record(&tracked, head.e_tag.clone());
+            }
+            info!(target: "RESTORE", "Restore of {:?} is still in progress", tracked);
+            Ok(ArchiveStatus::Pending)
+        }
+        Some(_) => Ok(ArchiveStatus::Available),
+        None if config.enabled => {
+            restore_bucket_object(client, bucket, key, version_id, config.days, &config.tier)?;
+            state.record(&tracked, head.e_tag.clone());
+            info!(target: "RESTORE", "Requested a {} restore of {:?}", config.tier, tracked);
+            Ok(ArchiveStatus::Pending)
+        }
+        None => {
+            warn!(target: "RESTORE", "{:?} is archived and restores are disabled", tracked);
+            Ok(ArchiveStatus::Archived)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn doc(content: &str) -> Yaml {
+        YamlLoader::load_from_str(content).unwrap().remove(0)
+    }
+
+    #[test]
+    fn is_archived_test() {
+        assert_eq!(is_archived(Some("GLACIER")), true);
+        assert_eq!(is_archived(Some("DEEP_ARCHIVE")), true);
+        assert_eq!(is_archived(Some("STANDARD")), false);
+        assert_eq!(is_archived(None), false);
+    }
+
+    #[test]
+    fn destination_class_test() {
+        let config = StorageClassConfig::from_yaml(
+            &doc("storage_class:\n  default: standard_ia\n  map:\n    GLACIER: DEEP_ARCHIVE\n    STANDARD: INTELLIGENT_TIERING\n")
+                ["storage_class"],
+        )
+        .unwrap();
+        assert_eq!(config.destination_class(Some("GLACIER")), Some("DEEP_ARCHIVE".to_owned()));
+        assert_eq!(config.destination_class(None), Some("INTELLIGENT_TIERING".to_owned()));
+        assert_eq!(config.destination_class(Some("ONEZONE_IA")), Some("STANDARD_IA".to_owned()));
+        assert_eq!(StorageClassConfig::default().destination_class(Some("GLACIER")), None);
+    }
+
+    #[test]
+    fn invalid_storage_class_test() {
+        assert_eq!(StorageClassConfig::from_yaml(&doc("default: COLD")).is_err(), true);
+        assert_eq!(
+            StorageClassConfig::from_yaml(&doc("map:\n  STANDARD: FROZEN\n")).is_err(),
+            true
+        );
+    }
+
+    #[test]
+    fn restore_config_test() {
+        let config = RestoreConfig::from_yaml(&doc("enabled: true\ntier: Bulk\ndays: 2\n")).unwrap();
+        assert_eq!(config.enabled, true);
+        assert_eq!(config.tier, "Bulk");
+        assert_eq!(config.days, 2);
+        assert_eq!(config.state_file, RestoreConfig::default().state_file);
+        assert_eq!(RestoreConfig::from_yaml(&doc("tier: Slow")).is_err(), true);
+        assert_eq!(RestoreConfig::from_yaml(&doc("days: 0")).is_err(), true);
+        assert_eq!(RestoreConfig::from_yaml(&doc("enabled: yes please")).is_err(), true);
+    }
+
+    #[test]
+    fn restore_in_progress_test() {
+        assert_eq!(restore_in_progress("ongoing-request=\"true\""), true);
+        assert_eq!(
+            restore_in_progress(
+                "ongoing-request=\"false\", expiry-date=\"Fri, 21 Dec 2012 00:00:00 GMT\""
+            ),
+            false
+        );
+    }
+
+    #[test]
+    fn restore_state_test() {
+        fs::create_dir_all("file_tests").unwrap();
+        let path = "file_tests/restores.json";
+        let _ = fs::remove_file(path);
+        let mut state = RestoreState::load(path).unwrap();
+        assert_eq!(state, RestoreState::default());
+        state.record("archive/go1.12.5.linux-amd64.tar.gz", Some("\"e0b8\"".to_owned()));
+        state.save(path).unwrap();
+
+        let mut loaded = RestoreState::load(path).unwrap();
+        assert_eq!(loaded, state);
+        assert_eq!(loaded.complete("archive/go1.12.5.linux-amd64.tar.gz"), true);
+        assert_eq!(loaded.complete("archive/go1.12.5.linux-amd64.tar.gz"), false);
+    }
+
+    #[test]
+    fn restore_key_test() {
+        assert_eq!(restore_key("a.tar.gz", None), "a.tar.gz");
+        assert_eq!(restore_key("a.tar.gz", Some("v1")), "a.tar.gz?versionId=v1");
+    }
+}
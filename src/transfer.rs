@@ -0,0 +1,54 @@
+use yaml_rust::Yaml;
+
+// How objects are moved between the buckets
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferConfig {
+    // Copy within S3 when both buckets are in the same partition and the
+    // destination credentials can read the source bucket
+    pub server_side_copy: bool,
+}
+
+impl Default for TransferConfig {
+    fn default() -> TransferConfig {
+        TransferConfig {
+            server_side_copy: true,
+        }
+    }
+}
+
+impl TransferConfig {
+    // Reads the `transfer` section of the job config:
+    //
+    //   transfer:
+    //     server_side_copy: false
+    pub fn from_yaml(yaml: &Yaml) -> Result<TransferConfig, String> {
+        let defaults = TransferConfig::default();
+        let server_side_copy = match &yaml["server_side_copy"] {
+            Yaml::Boolean(b) => *b,
+            Yaml::BadValue => defaults.server_side_copy,
+            _ => return Err("transfer.server_side_copy must be true or false".to_owned()),
+        };
+        Ok(TransferConfig { server_side_copy })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn doc(content: &str) -> Yaml {
+        YamlLoader::load_from_str(content).unwrap().remove(0)
+    }
+
+    #[test]
+    fn transfer_config_test() {
+        assert_eq!(
+            TransferConfig::from_yaml(&doc("other: value")["transfer"]).unwrap(),
+            TransferConfig::default()
+        );
+        let config = TransferConfig::from_yaml(&doc("server_side_copy: false")).unwrap();
+        assert_eq!(config.server_side_copy, false);
+        assert_eq!(TransferConfig::from_yaml(&doc("server_side_copy: maybe")).is_err(), true);
+    }
+}
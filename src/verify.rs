@@ -0,0 +1,443 @@
+use crate::bucket::{hash_bucket_object, head_bucket_object, list_bucket_objects};
+use crate::checksum::{content_sha256, SOURCE_ETAG_METADATA_KEY};
+use crate::etag::normalize_etag;
+use rand::seq::SliceRandom;
+use regex::Regex;
+use rusoto_s3::Object;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::str::FromStr;
+use log::{info, warn};
+
+#[derive(Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!("unknown output format {:?}", s)),
+        }
+    }
+}
+
+// Limits the keys that are compared between the buckets
+#[derive(Debug, Default)]
+pub struct KeyFilter {
+    pub prefix: Option<String>,
+    pub include: Option<Regex>,
+    pub exclude: Option<Regex>,
+}
+
+impl KeyFilter {
+    pub fn matches(&self, key: &str) -> bool {
+        if let Some(prefix) = &self.prefix {
+            if !key.starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+        if let Some(include) = &self.include {
+            if !include.is_match(key) {
+                return false;
+            }
+        }
+        if let Some(exclude) = &self.exclude {
+            if exclude.is_match(key) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub struct VerifyOptions {
+    pub filter: KeyFilter,
+    // Number of randomly chosen keys to download from both buckets and hash
+    pub sample: usize,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SizeMismatch {
+    pub key: String,
+    pub source_size: i64,
+    pub destination_size: i64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    pub source_bucket: String,
+    pub destination_bucket: String,
+    pub source_objects: usize,
+    pub destination_objects: usize,
+    // In the source but not in the destination
+    pub missing: Vec<String>,
+    // In the destination but not in the source
+    pub extra: Vec<String>,
+    pub size_mismatched: Vec<SizeMismatch>,
+    pub checksum_mismatched: Vec<String>,
+    // Present in both with the same size, but without a digest that can be compared
+    pub unverified: Vec<String>,
+    // Keys whose content was hashed in both buckets
+    pub sampled: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChecksumComparison {
+    Match,
+    Mismatch,
+    Unverified,
+}
+
+impl VerifyReport {
+    pub fn has_drift(&self) -> bool {
+        !self.missing.is_empty()
+            || !self.extra.is_empty()
+            || !self.size_mismatched.is_empty()
+            || !self.checksum_mismatched.is_empty()
+    }
+
+    pub fn render(&self, format: &OutputFormat) -> Result<String, Box<dyn Error>> {
+        match format {
+            OutputFormat::Text => Ok(self.to_text()),
+            OutputFormat::Json => Ok(serde_json::to_string_pretty(self)?),
+            OutputFormat::Csv => self.to_csv(),
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!(
+            "Source: {} ({} objects)\nDestination: {} ({} objects)\n",
+            self.source_bucket, self.source_objects, self.destination_bucket, self.destination_objects
+        ));
+        let sections = [
+            ("Missing from destination", &self.missing),
+            ("Extra in destination", &self.extra),
+            ("Checksum mismatched", &self.checksum_mismatched),
+            ("Unverified", &self.unverified),
+            ("Sampled", &self.sampled),
+        ];
+        for (title, keys) in sections.iter() {
+            out.push_str(&format!("{}: {}\n", title, keys.len()));
+            for key in keys.iter() {
+                out.push_str(&format!("  {}\n", key));
+            }
+        }
+        out.push_str(&format!("Size mismatched: {}\n", self.size_mismatched.len()));
+        for mismatch in &self.size_mismatched {
+            out.push_str(&format!(
+                "  {} (source {} bytes, destination {} bytes)\n",
+                mismatch.key, mismatch.source_size, mismatch.destination_size
+            ));
+        }
+        out.push_str(if self.has_drift() {
+            "Result: DRIFT\n"
+        } else {
+            "Result: IN SYNC\n"
+        });
+        out
+    }
+
+    fn to_csv(&self) -> Result<String, Box<dyn Error>> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record(&["status", "key", "source_size", "destination_size"])?;
+        for key in &self.missing {
+            writer.write_record(&["missing", key, "", ""])?;
+        }
+        for key in &self.extra {
+            writer.write_record(&["extra", key, "", ""])?;
+        }
+        for mismatch in &self.size_mismatched {
+            writer.write_record(&[
+                "size_mismatch",
+                &mismatch.key,
+                &mismatch.source_size.to_string(),
+                &mismatch.destination_size.to_string(),
+            ])?;
+        }
+        for key in &self.checksum_mismatched {
+            writer.write_record(&["checksum_mismatch", key, "", ""])?;
+        }
+        for key in &self.unverified {
+            writer.write_record(&["unverified", key, "", ""])?;
+        }
+        Ok(String::from_utf8(writer.into_inner()?)?)
+    }
+}
+
+// Objects keyed by name, skipping "directory" placeholders and filtered keys
+fn index_objects(objects: Vec<Object>, filter: &KeyFilter) -> BTreeMap<String, Object> {
+    objects
+        .into_iter()
+        .filter_map(|o| o.key.clone().map(|k| (k, o)))
+        .filter(|(k, _)| !k.ends_with('/') && filter.matches(k))
+        .collect()
+}
+
+// Sorts the keys into missing, extra and size mismatched, returning the keys
+// present in both buckets with the same size for checksum comparison
+pub fn compare_listings(
+    report: &mut VerifyReport,
+    source: &BTreeMap<String, Object>,
+    destination: &BTreeMap<String, Object>,
+) -> Vec<String> {
+    let mut same_size = Vec::new();
+    for (key, object) in source {
+        match destination.get(key) {
+            None => report.missing.push(key.to_owned()),
+            Some(dest) => {
+                let source_size = object.size.unwrap_or(0);
+                let destination_size = dest.size.unwrap_or(0);
+                if source_size == destination_size {
+                    same_size.push(key.to_owned());
+                } else {
+                    report.size_mismatched.push(SizeMismatch {
+                        key: key.to_owned(),
+                        source_size,
+                        destination_size,
+                    });
+                }
+            }
+        }
+    }
+    report.extra = destination
+        .keys()
+        .filter(|k| !source.contains_key(*k))
+        .cloned()
+        .collect();
+    same_size
+}
+
+// Compares the digests bearcat records at the destination with the source.
+// Matching ETags are taken as proof of equal content; otherwise the SHA-256
+// or the source ETag stored in the destination metadata are used.
+pub fn compare_checksums(
+    source_e_tag: Option<&str>,
+    source_sha256: Option<&str>,
+    destination_e_tag: Option<&str>,
+    destination_metadata: Option<&HashMap<String, String>>,
+) -> ChecksumComparison {
+    let source_e_tag = source_e_tag.map(normalize_etag);
+    if source_e_tag.is_some() && source_e_tag == destination_e_tag.map(normalize_etag) {
+        return ChecksumComparison::Match;
+    }
+    let metadata = match destination_metadata {
+        Some(metadata) => metadata,
+        None => return ChecksumComparison::Unverified,
+    };
+    if let (Some(source), Some(destination)) = (source_sha256, content_sha256(metadata)) {
+        return if source == destination {
+            ChecksumComparison::Match
+        } else {
+            ChecksumComparison::Mismatch
+        };
+    }
+    if let (Some(source), Some(destination)) =
+        (source_e_tag, metadata.get(SOURCE_ETAG_METADATA_KEY))
+    {
+        return if source == normalize_etag(destination) {
+            ChecksumComparison::Match
+        } else {
+            ChecksumComparison::Mismatch
+        };
+    }
+    ChecksumComparison::Unverified
+}
+
+// Read only audit of the destination bucket against the source bucket
+pub fn verify_buckets(
+    source_client: &rusoto_s3::S3Client,
+    source_bucket: &str,
+    destination_client: &rusoto_s3::S3Client,
+    destination_bucket: &str,
+    options: &VerifyOptions,
+) -> Result<VerifyReport, Box<dyn Error>> {
+    let prefix = options.filter.prefix.as_ref().map(String::as_str);
+    info!(target: "VERIFY", "Listing source bucket {:?}", source_bucket);
+    let source = index_objects(
+        list_bucket_objects(source_client, source_bucket, prefix)?,
+        &options.filter,
+    );
+    info!(target: "VERIFY", "Listing destination bucket {:?}", destination_bucket);
+    let destination = index_objects(
+        list_bucket_objects(destination_client, destination_bucket, prefix)?,
+        &options.filter,
+    );
+
+    let mut report = VerifyReport {
+        source_bucket: source_bucket.to_owned(),
+        destination_bucket: destination_bucket.to_owned(),
+        source_objects: source.len(),
+        destination_objects: destination.len(),
+        ..Default::default()
+    };
+    let same_size = compare_listings(&mut report, &source, &destination);
+
+    for key in &same_size {
+        let source_e_tag = source[key].e_tag.as_ref().map(String::as_str);
+        let destination_e_tag = destination[key].e_tag.as_ref().map(String::as_str);
+        let mut comparison = compare_checksums(source_e_tag, None, destination_e_tag, None);
+        if comparison != ChecksumComparison::Match {
+            let dest_head = head_bucket_object(destination_client, destination_bucket, key, None)?;
+            let source_sha256 = match dest_head.metadata.as_ref() {
+                Some(m) if content_sha256(m).is_some() => {
+                    head_bucket_object(source_client, source_bucket, key, None)?
+                        .metadata
+                        .and_then(|m| content_sha256(&m).cloned())
+                }
+                _ => None,
+            };
+            comparison = compare_checksums(
+                source_e_tag,
+                source_sha256.as_ref().map(String::as_str),
+                destination_e_tag,
+                dest_head.metadata.as_ref(),
+            );
+        }
+        match comparison {
+            ChecksumComparison::Match => {}
+            ChecksumComparison::Mismatch => report.checksum_mismatched.push(key.to_owned()),
+            ChecksumComparison::Unverified => report.unverified.push(key.to_owned()),
+        }
+    }
+
+    // Deep comparison of the content of a random sample of keys
+    let mut rng = rand::thread_rng();
+    let mut sample: Vec<&String> = same_size.choose_multiple(&mut rng, options.sample).collect();
+    sample.sort();
+    for key in sample {
+        info!(target: "VERIFY", "Hashing {:?} in both buckets", key);
+        let source_hash = hash_bucket_object(source_client, source_bucket, key)?;
+        let destination_hash = hash_bucket_object(destination_client, destination_bucket, key)?;
+        report.unverified.retain(|k| k != key);
+        if source_hash != destination_hash {
+            warn!(target: "VERIFY", "Content of {:?} differs between the buckets", key);
+            if !report.checksum_mismatched.contains(key) {
+                report.checksum_mismatched.push(key.to_owned());
+                report.checksum_mismatched.sort();
+            }
+        }
+        report.sampled.push(key.to_owned());
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::SHA256_METADATA_KEY;
+
+    fn object(key: &str, size: i64) -> Object {
+        Object {
+            key: Some(key.to_owned()),
+            size: Some(size),
+            ..Default::default()
+        }
+    }
+
+    fn index(objects: Vec<Object>) -> BTreeMap<String, Object> {
+        index_objects(objects, &KeyFilter::default())
+    }
+
+    #[test]
+    fn key_filter_test() {
+        let filter = KeyFilter {
+            prefix: Some("releases/".to_owned()),
+            include: Some(Regex::new(r"\.tar\.gz$").unwrap()),
+            exclude: Some(Regex::new(r"rc").unwrap()),
+        };
+        assert_eq!(filter.matches("releases/go1.12.5.linux-amd64.tar.gz"), true);
+        assert_eq!(filter.matches("go1.12.5.linux-amd64.tar.gz"), false);
+        assert_eq!(filter.matches("releases/go1.12.5.zip"), false);
+        assert_eq!(filter.matches("releases/go1.13rc1.tar.gz"), false);
+        assert_eq!(KeyFilter::default().matches("anything"), true);
+    }
+
+    #[test]
+    fn output_format_test() {
+        assert_eq!("JSON".parse::<OutputFormat>(), Ok(OutputFormat::Json));
+        assert_eq!("csv".parse::<OutputFormat>(), Ok(OutputFormat::Csv));
+        assert_eq!("xml".parse::<OutputFormat>().is_err(), true);
+    }
+
+    #[test]
+    fn compare_listings_test() {
+        let source = index(vec![
+            object("same", 10),
+            object("missing", 5),
+            object("resized", 7),
+            object("folder/", 0),
+        ]);
+        let destination = index(vec![object("same", 10), object("resized", 8), object("extra", 1)]);
+        let mut report = VerifyReport::default();
+        let same_size = compare_listings(&mut report, &source, &destination);
+        assert_eq!(same_size, vec!["same".to_owned()]);
+        assert_eq!(report.missing, vec!["missing".to_owned()]);
+        assert_eq!(report.extra, vec!["extra".to_owned()]);
+        assert_eq!(
+            report.size_mismatched,
+            vec![SizeMismatch {
+                key: "resized".to_owned(),
+                source_size: 7,
+                destination_size: 8,
+            }]
+        );
+        assert_eq!(report.has_drift(), true);
+    }
+
+    #[test]
+    fn compare_checksums_test() {
+        let e_tag = "\"e0b805b5969df6c2c7dff6038d960d52-8\"";
+        assert_eq!(
+            compare_checksums(Some(e_tag), None, Some("e0b805b5969df6c2c7dff6038d960d52-8"), None),
+            ChecksumComparison::Match
+        );
+        assert_eq!(
+            compare_checksums(Some(e_tag), None, Some("other"), None),
+            ChecksumComparison::Unverified
+        );
+
+        let mut metadata = HashMap::new();
+        metadata.insert(SOURCE_ETAG_METADATA_KEY.to_owned(), normalize_etag(e_tag).to_owned());
+        assert_eq!(
+            compare_checksums(Some(e_tag), None, Some("other"), Some(&metadata)),
+            ChecksumComparison::Match
+        );
+        assert_eq!(
+            compare_checksums(Some("\"changed\""), None, Some("other"), Some(&metadata)),
+            ChecksumComparison::Mismatch
+        );
+
+        metadata.insert(SHA256_METADATA_KEY.to_owned(), "abc".to_owned());
+        assert_eq!(
+            compare_checksums(Some(e_tag), Some("def"), Some("other"), Some(&metadata)),
+            ChecksumComparison::Mismatch
+        );
+    }
+
+    #[test]
+    fn render_report_test() {
+        let report = VerifyReport {
+            source_bucket: "bearcat-commercial".to_owned(),
+            destination_bucket: "bearcat-govcloud".to_owned(),
+            missing: vec!["a,b".to_owned()],
+            ..Default::default()
+        };
+        let csv = report.render(&OutputFormat::Csv).unwrap();
+        assert_eq!(csv, "status,key,source_size,destination_size\nmissing,\"a,b\",,\n");
+        let json = report.render(&OutputFormat::Json).unwrap();
+        assert_eq!(json.contains("\"missing\": [\n    \"a,b\"\n  ]"), true);
+        let text = report.render(&OutputFormat::Text).unwrap();
+        assert_eq!(text.contains("Missing from destination: 1\n  a,b\n"), true);
+        assert_eq!(text.ends_with("Result: DRIFT\n"), true);
+    }
+}
@@ -0,0 +1,451 @@
+use crate::bucket::{
+    copy, delete_bucket_object, download_version, is_bucket_versioned,
+    list_bucket_object_versions, upload,
+};
+use crate::config::{yaml_scalar, JobConfig};
+use crate::error::{in_context, BearcatError, Classify, Outcome, RunSummary};
+use crate::inspect::BlockedFile;
+use crate::manifest::Manifest;
+use crate::quarantine::process_quarantined;
+use crate::release::check_release;
+use crate::signature::sync_signature;
+use crate::storage::{is_archived, prepare_archived_object, restore_key, ArchiveStatus, RestoreState};
+use rusoto_s3::{DeleteMarkerEntry, ObjectVersion};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use yaml_rust::Yaml;
+use log::{error, info, warn};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionConfig {
+    // Copy every version and delete marker instead of only the latest objects
+    pub enabled: bool,
+    // Where the copied versions are tracked between runs
+    pub state_file: String,
+}
+
+impl Default for VersionConfig {
+    fn default() -> VersionConfig {
+        VersionConfig {
+            enabled: false,
+            state_file: "bearcat-versions.json".to_owned(),
+        }
+    }
+}
+
+impl VersionConfig {
+    // Reads the `versions` section of the job config:
+    //
+    //   versions:
+    //     enabled: true
+    //     state_file: /var/lib/bearcat/versions.json
+    pub fn from_yaml(yaml: &Yaml) -> Result<VersionConfig, String> {
+        let defaults = VersionConfig::default();
+        let enabled = match &yaml["enabled"] {
+            Yaml::Boolean(b) => *b,
+            Yaml::BadValue => defaults.enabled,
+            _ => return Err("versions.enabled must be true or false".to_owned()),
+        };
+        Ok(VersionConfig {
+            enabled,
+            state_file: yaml_scalar(&yaml["state_file"]).unwrap_or(defaults.state_file),
+        })
+    }
+}
+
+// A version or delete marker of a source object
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionEntry {
+    pub key: String,
+    pub version_id: String,
+    pub last_modified: String,
+    pub delete_marker: bool,
+    pub storage_class: Option<String>,
+}
+
+impl VersionEntry {
+    fn from_version(version: ObjectVersion) -> Option<VersionEntry> {
+        Some(VersionEntry {
+            key: version.key?,
+            // Objects written before versioning was enabled have the "null" version
+            version_id: version.version_id.unwrap_or_else(|| "null".to_owned()),
+            last_modified: version.last_modified.unwrap_or_default(),
+            delete_marker: false,
+            storage_class: version.storage_class,
+        })
+    }
+
+    fn from_delete_marker(marker: DeleteMarkerEntry) -> Option<VersionEntry> {
+        Some(VersionEntry {
+            key: marker.key?,
+            version_id: marker.version_id.unwrap_or_else(|| "null".to_owned()),
+            last_modified: marker.last_modified.unwrap_or_default(),
+            delete_marker: true,
+            storage_class: None,
+        })
+    }
+}
+
+// Groups versions and delete markers by key, oldest first, so replaying them
+// leaves the same latest version at the destination. S3 lists the versions of
+// a key newest first, which breaks ties between equal timestamps.
+pub fn order_versions(
+    versions: Vec<ObjectVersion>,
+    delete_markers: Vec<DeleteMarkerEntry>,
+) -> Vec<VersionEntry> {
+    let mut entries: Vec<(usize, VersionEntry)> = versions
+        .into_iter()
+        .filter_map(VersionEntry::from_version)
+        .chain(delete_markers.into_iter().filter_map(VersionEntry::from_delete_marker))
+        .enumerate()
+        .collect();
+    entries.sort_by(|(a_index, a), (b_index, b)| {
+        a.key
+            .cmp(&b.key)
+            .then(a.last_modified.cmp(&b.last_modified))
+            .then(b_index.cmp(a_index))
+    });
+    entries.into_iter().map(|(_, entry)| entry).collect()
+}
+
+// Versions copied by earlier runs, persisted as JSON
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct VersionState {
+    pub copied: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl VersionState {
+    pub fn load(path: &str) -> Result<VersionState, Box<dyn Error>> {
+        if !Path::new(path).exists() {
+            return Ok(VersionState::default());
+        }
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)
+            .map_err(|e| format!("Could not parse the version state {:?}: {}", path, e))?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn contains(&self, key: &str, version_id: &str) -> bool {
+        self.copied
+            .get(key)
+            .map_or(false, |versions| versions.contains(version_id))
+    }
+
+    pub fn record(&mut self, key: &str, version_id: &str) {
+        self.copied
+            .entry(key.to_owned())
+            .or_insert_with(BTreeSet::new)
+            .insert(version_id.to_owned());
+    }
+}
+
+// Copies every version of every source object to the destination in the order
+// they were written, and places a delete marker wherever the source has one.
+// Versions are read in place, the source bucket isn't changed. With
+// `server_side` set the destination client copies the versions within S3.
+// A version that fails holds back the later versions of its key, and stops
+// the run unless the job continues on errors.
+#[allow(clippy::too_many_arguments)]
+pub fn replicate_versions(
+    source_client: &rusoto_s3::S3Client,
+    source_bucket: &str,
+    destination_client: &rusoto_s3::S3Client,
+    destination_bucket: &str,
+    server_side: bool,
+    job: &JobConfig,
+    manifest: &mut Manifest,
+    summary: &mut RunSummary,
+) -> Result<(), failure::Error> {
+    let versioned = is_bucket_versioned(destination_client, destination_bucket)
+        .classify(format!("Could not check the versioning of {:?}", destination_bucket))?;
+    if !versioned {
+        return Err(BearcatError::Config(format!(
+            "Versioning has to be enabled on the destination bucket {:?}",
+            destination_bucket
+        ))
+        .into());
+    }
+    let mut state = VersionState::load(&job.versions.state_file).classify("Could not load the version state")?;
+    let mut restores = RestoreState::load(&job.restore.state_file).classify("Could not load the restore state")?;
+    let (versions, delete_markers) = list_bucket_object_versions(source_client, source_bucket, None)
+        .classify(format!("Could not list the versions in {:?}", source_bucket))?;
+    // Later versions of a key are held back once one of its versions can't be copied
+    let mut blocked_key: Option<String> = None;
+    for entry in order_versions(versions, delete_markers) {
+        if entry.key.ends_with('/') || state.contains(&entry.key, &entry.version_id) {
+            continue;
+        }
+        // Signatures are copied with the versions they sign
+        if job.signatures.is_signature(&entry.key) {
+            continue;
+        }
+        if blocked_key.as_ref() == Some(&entry.key) {
+            continue;
+        }
+        let copied = copy_version(
+            source_client,
+            source_bucket,
+            destination_client,
+            destination_bucket,
+            server_side,
+            &entry,
+            job,
+            manifest,
+            &mut restores,
+        );
+        match copied {
+            Ok(Outcome::Synced) => summary.add(Outcome::Synced),
+            // Rejected versions are checked again on the next run, copying
+            // the later versions of their key would reorder its history
+            Ok(Outcome::Rejected) => {
+                summary.add(Outcome::Rejected);
+                blocked_key = Some(entry.key.clone());
+                continue;
+            }
+            Ok(Outcome::Skipped) => {
+                summary.add(Outcome::Skipped);
+                blocked_key = Some(entry.key.clone());
+                continue;
+            }
+            Err(e) if e.is::<BlockedFile>() => {
+                error!(target: "VERSIONS", "{}, its later versions are skipped", e);
+                summary.add(Outcome::Rejected);
+                blocked_key = Some(entry.key.clone());
+                continue;
+            }
+            Err(e) => {
+                let e = in_context(e, format!("Could not copy version {} of {:?}", entry.version_id, entry.key));
+                if !job.failures.continue_on_error {
+                    return Err(e);
+                }
+                summary.fail(Some(&entry.key), e);
+                blocked_key = Some(entry.key.clone());
+                continue;
+            }
+        }
+        state.record(&entry.key, &entry.version_id);
+        state.save(&job.versions.state_file).classify("Could not save the version state")?;
+    }
+    Ok(())
+}
+
+// Copies one version or delete marker to the destination
+#[allow(clippy::too_many_arguments)]
+fn copy_version(
+    source_client: &rusoto_s3::S3Client,
+    source_bucket: &str,
+    destination_client: &rusoto_s3::S3Client,
+    destination_bucket: &str,
+    server_side: bool,
+    entry: &VersionEntry,
+    job: &JobConfig,
+    manifest: &mut Manifest,
+    restores: &mut RestoreState,
+) -> Result<Outcome, Box<dyn Error>> {
+    if entry.delete_marker {
+        delete_bucket_object(destination_client, destination_bucket, &entry.key)?;
+        info!(target: "VERSIONS", "Placed a delete marker on {:?}", entry.key);
+        return Ok(Outcome::Synced);
+    }
+    let release = check_release(
+        source_client,
+        source_bucket,
+        &entry.key,
+        Some(&entry.version_id),
+        &job.release,
+        job.encryption.source_customer_key.as_ref(),
+    )?;
+    if let Err(reason) = release {
+        warn!(
+            target: "RELEASE",
+            "Rejected version {} of {:?}: {}", entry.version_id, entry.key, reason
+        );
+        return Ok(Outcome::Rejected);
+    }
+    // Archived versions can only be read once they have been restored
+    if is_archived(entry.storage_class.as_ref().map(String::as_str)) {
+        let status = prepare_archived_object(
+            source_client,
+            source_bucket,
+            &entry.key,
+            Some(&entry.version_id),
+            &job.restore,
+            job.encryption.source_customer_key.as_ref(),
+            restores,
+        )?;
+        if status != ArchiveStatus::Available {
+            if status == ArchiveStatus::Pending {
+                restores.save(&job.restore.state_file)?;
+            }
+            warn!(
+                target: "VERSIONS",
+                "Version {} of {:?} is archived ({:?}), its later versions are skipped for now",
+                entry.version_id, entry.key, status
+            );
+            return Ok(Outcome::Skipped);
+        }
+    }
+    let (upload_bucket, upload_key) = job.quarantine.location(destination_bucket, &entry.key);
+    // Server side copies don't stage the content
+    let source_object = if server_side {
+        copy(
+            destination_client,
+            source_client,
+            &entry.key,
+            source_bucket,
+            Some(&entry.version_id),
+            &upload_bucket,
+            &upload_key,
+            job,
+        )?;
+        None
+    } else {
+        let source_object = download_version(
+            source_client,
+            &entry.key,
+            source_bucket,
+            Some(&entry.version_id),
+            job,
+        )?;
+        upload(
+            destination_client,
+            &job.staging.path,
+            &entry.key,
+            &upload_bucket,
+            &upload_key,
+            &source_object,
+            job,
+        )?;
+        Some(source_object)
+    };
+    // Versions held in quarantine count as copied, they wait there for review
+    let promoted = !job.quarantine.enabled
+        || process_quarantined(
+            destination_client,
+            destination_bucket,
+            &entry.key,
+            source_object.as_ref(),
+            job,
+        )?;
+    if promoted && job.manifest.enabled {
+        manifest.record_transfer(destination_client, &entry.key, Some(&entry.version_id), job)?;
+    }
+    if promoted && job.signatures.enabled {
+        sync_signature(
+            source_client,
+            source_bucket,
+            destination_client,
+            destination_bucket,
+            &entry.key,
+            false,
+            job,
+        )?;
+    }
+    if restores.complete(&restore_key(&entry.key, Some(&entry.version_id))) {
+        restores.save(&job.restore.state_file)?;
+    }
+    info!(target: "VERSIONS", "Copied version {} of {:?}", entry.version_id, entry.key);
+    Ok(Outcome::Synced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn version(key: &str, version_id: &str, last_modified: &str) -> ObjectVersion {
+        ObjectVersion {
+            key: Some(key.to_owned()),
+            version_id: Some(version_id.to_owned()),
+            last_modified: Some(last_modified.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn delete_marker(key: &str, version_id: &str, last_modified: &str) -> DeleteMarkerEntry {
+        DeleteMarkerEntry {
+            key: Some(key.to_owned()),
+            version_id: Some(version_id.to_owned()),
+            last_modified: Some(last_modified.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn order_versions_test() {
+        let ordered = order_versions(
+            vec![
+                version("b.tar.gz", "b1", "2019-06-01T00:00:00.000Z"),
+                version("a.tar.gz", "a3", "2019-06-03T00:00:00.000Z"),
+                version("a.tar.gz", "a2", "2019-06-01T00:00:00.000Z"),
+                version("a.tar.gz", "a1", "2019-06-01T00:00:00.000Z"),
+            ],
+            vec![delete_marker("a.tar.gz", "d1", "2019-06-02T00:00:00.000Z")],
+        );
+        let ids: Vec<(&str, bool)> = ordered
+            .iter()
+            .map(|e| (e.version_id.as_str(), e.delete_marker))
+            .collect();
+        assert_eq!(
+            ids,
+            vec![
+                ("a1", false),
+                ("a2", false),
+                ("d1", true),
+                ("a3", false),
+                ("b1", false)
+            ]
+        );
+    }
+
+    #[test]
+    fn null_version_test() {
+        let ordered = order_versions(
+            vec![ObjectVersion {
+                key: Some("a.tar.gz".to_owned()),
+                ..Default::default()
+            }],
+            Vec::new(),
+        );
+        assert_eq!(ordered[0].version_id, "null");
+    }
+
+    #[test]
+    fn version_config_test() {
+        let doc = &YamlLoader::load_from_str("versions:\n  enabled: true\n").unwrap()[0];
+        let config = VersionConfig::from_yaml(&doc["versions"]).unwrap();
+        assert_eq!(config.enabled, true);
+        assert_eq!(config.state_file, VersionConfig::default().state_file);
+        assert_eq!(
+            VersionConfig::from_yaml(&doc["missing"]).unwrap(),
+            VersionConfig::default()
+        );
+        let doc = &YamlLoader::load_from_str("enabled: always").unwrap()[0];
+        assert_eq!(VersionConfig::from_yaml(doc).is_err(), true);
+    }
+
+    #[test]
+    fn version_state_test() {
+        fs::create_dir_all("file_tests").unwrap();
+        let path = "file_tests/versions.json";
+        let _ = fs::remove_file(path);
+        let mut state = VersionState::load(path).unwrap();
+        assert_eq!(state.contains("a.tar.gz", "a1"), false);
+        state.record("a.tar.gz", "a1");
+        state.record("a.tar.gz", "d1");
+        state.save(path).unwrap();
+
+        let loaded = VersionState::load(path).unwrap();
+        assert_eq!(loaded, state);
+        assert_eq!(loaded.contains("a.tar.gz", "a1"), true);
+        assert_eq!(loaded.contains("a.tar.gz", "a2"), false);
+        assert_eq!(loaded.contains("b.tar.gz", "a1"), false);
+    }
+}